@@ -0,0 +1,360 @@
+// TODO uncompressed ANP3 packages have not been seen in the wild, check if they're read properly
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ascii::AsciiExt;
+use byteorder::{self, ReadBytesExt, LittleEndian};
+use rw;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidPackage,
+    IoError(byteorder::Error),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl From<byteorder::Error> for Error {
+    fn from(e: byteorder::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::from(byteorder::Error::from(e))
+    }
+}
+
+/// Package of animations (IFP file).
+///
+/// Grand Theft Auto III and Vice City use the ANPK format, while San Andreas uses the ANP3 format.
+#[derive(Debug)]
+pub struct AnimPackage {
+    pub name: String,
+    pub animations: Vec<Animation>,
+}
+
+/// Animation of a set of bones.
+#[derive(Debug)]
+pub struct Animation {
+    pub name: String,
+    pub objects: Vec<AnimObject>,
+}
+
+/// Keyframes of a single bone in an `Animation`.
+#[derive(Debug)]
+pub struct AnimObject {
+    /// Name of the animated bone (frame name).
+    pub name: String,
+    /// Node id of the animated bone, not available on Grand Theft Auto III packages.
+    pub bone_id: Option<i32>,
+    pub keyframes: Vec<KeyFrame>,
+}
+
+/// Keyframe of an `AnimObject`.
+#[derive(Debug, Copy, Clone)]
+pub struct KeyFrame {
+    /// Time of the keyframe in seconds.
+    pub time: f32,
+    /// Rotation quaternion as `(x, y, z, w)`.
+    pub rot: [f32; 4],
+    /// Translation, if the keyframe has any.
+    pub trans: Option<rw::Vec3>,
+    /// Scale, if the keyframe has any.
+    pub scale: Option<rw::Vec3>,
+}
+
+impl AnimPackage {
+    /// Reads an animation package in either the ANPK or ANP3 format.
+    pub fn read<R: Read + Seek>(f: &mut R) -> Result<AnimPackage> {
+        match &try!(read_fourcc(f)) {
+            b"ANPK" => AnimPackage::read_anpk(f),
+            b"ANP3" => AnimPackage::read_anp3(f),
+            _ => Err(Error::InvalidPackage),
+        }
+    }
+
+    /// Finds the animation with the specified name, ignoring case.
+    pub fn find(&self, name: &str) -> Option<&Animation> {
+        self.animations.iter().find(|anim| anim.name.eq_ignore_ascii_case(name))
+    }
+
+    fn read_anpk<R: Read + Seek>(f: &mut R) -> Result<AnimPackage> {
+        let _size = try!(f.read_u32::<LittleEndian>());
+
+        let info_size = try!(expect_chunk(f, b"INFO"));
+        let num_anims = try!(f.read_u32::<LittleEndian>());
+        let name_size = try!(padded(info_size).checked_sub(4).ok_or(Error::InvalidPackage));
+        let name = try!(read_name(f, name_size));
+
+        // The counts aren't trusted to reserve memory, the vectors grow as the items are read.
+        let mut animations = Vec::new();
+        for _ in (0..num_anims) {
+            let name_size = try!(expect_chunk(f, b"NAME"));
+            let name = try!(read_name(f, padded(name_size)));
+
+            let _dgan_size = try!(expect_chunk(f, b"DGAN"));
+            let info_size = try!(expect_chunk(f, b"INFO"));
+            let num_objects = try!(f.read_u32::<LittleEndian>());
+            try!(f.seek(SeekFrom::Current(padded(info_size) as i64 - 4)));
+
+            let mut objects = Vec::new();
+            for _ in (0..num_objects) {
+                objects.push(try!(AnimObject::read_anpk(f)));
+            }
+
+            animations.push(Animation {
+                name: name,
+                objects: objects,
+            });
+        }
+
+        Ok(AnimPackage {
+            name: name,
+            animations: animations,
+        })
+    }
+
+    fn read_anp3<R: Read + Seek>(f: &mut R) -> Result<AnimPackage> {
+        let _size = try!(f.read_u32::<LittleEndian>());
+        let name = try!(read_name(f, 24));
+        let num_anims = try!(f.read_u32::<LittleEndian>());
+
+        let mut animations = Vec::new();
+        for _ in (0..num_anims) {
+            let name = try!(read_name(f, 24));
+            let num_objects = try!(f.read_u32::<LittleEndian>());
+            let _frame_data_size = try!(f.read_u32::<LittleEndian>());
+            let is_compressed = try!(f.read_u32::<LittleEndian>()) != 0;
+
+            let mut objects = Vec::new();
+            for _ in (0..num_objects) {
+                objects.push(try!(AnimObject::read_anp3(f, is_compressed)));
+            }
+
+            animations.push(Animation {
+                name: name,
+                objects: objects,
+            });
+        }
+
+        Ok(AnimPackage {
+            name: name,
+            animations: animations,
+        })
+    }
+}
+
+impl Animation {
+    /// Gets the duration of the animation in seconds.
+    pub fn duration(&self) -> f32 {
+        self.objects.iter()
+                    .filter_map(|obj| obj.keyframes.last())
+                    .map(|kf| kf.time)
+                    .fold(0.0, |a: f32, b| a.max(b))
+    }
+
+    /// Converts this animation into a RenderWare hierarchical animation.
+    ///
+    /// Each object becomes a node of the animation, in the same order. Objects without a
+    /// translation get a zero translation, the caller should take care of those.
+    pub fn to_rw(&self) -> rw::Animation {
+        fn to_rw_keyframe(kf: &KeyFrame, prev: Option<usize>) -> rw::HAnimKeyFrame {
+            rw::HAnimKeyFrame {
+                time: kf.time,
+                // IFP rotations are stored conjugated compared to RenderWare ones.
+                rot: [-kf.rot[0], -kf.rot[1], -kf.rot[2], kf.rot[3]],
+                trans: kf.trans.unwrap_or(rw::Vec3(0.0, 0.0, 0.0)),
+                prev: prev,
+            }
+        }
+
+        let identity = KeyFrame {
+            time: 0.0,
+            rot: [0.0, 0.0, 0.0, 1.0],
+            trans: None,
+            scale: None,
+        };
+
+        // The starting keyframe of each node comes first, then the remaining ones.
+        let mut keyframes: Vec<_> = self.objects.iter().map(|obj| {
+            to_rw_keyframe(obj.keyframes.first().unwrap_or(&identity), None)
+        }).collect();
+
+        for (node, obj) in self.objects.iter().enumerate() {
+            let mut prev = node;
+            for kf in obj.keyframes.iter().skip(1) {
+                keyframes.push(to_rw_keyframe(kf, Some(prev)));
+                prev = keyframes.len() - 1;
+            }
+        }
+
+        rw::Animation {
+            duration: self.duration(),
+            flags: 0,
+            data: rw::AnimData::HAnim(rw::HAnimAnimation::new(self.objects.len(), keyframes)),
+        }
+    }
+}
+
+impl AnimObject {
+    /// Checks whether the keyframes of this object have translations.
+    pub fn has_translation(&self) -> bool {
+        self.keyframes.iter().any(|kf| kf.trans.is_some())
+    }
+
+    fn read_anpk<R: Read + Seek>(f: &mut R) -> Result<AnimObject> {
+        let _cpan_size = try!(expect_chunk(f, b"CPAN"));
+        let anim_size = try!(expect_chunk(f, b"ANIM"));
+        let name = try!(read_name(f, 28));
+        let num_frames = try!(f.read_u32::<LittleEndian>());
+        let _unknown = try!(f.read_u32::<LittleEndian>());
+        let _next = try!(f.read_i32::<LittleEndian>());
+        let _prev = try!(f.read_i32::<LittleEndian>());
+
+        // Only Vice City packages have the bone id.
+        let bone_id = if anim_size >= 48 {
+            let bone_id = try!(f.read_i32::<LittleEndian>());
+            try!(f.seek(SeekFrom::Current(padded(anim_size) as i64 - 48)));
+            Some(bone_id)
+        } else {
+            try!(f.seek(SeekFrom::Current(padded(anim_size) as i64 - 44)));
+            None
+        };
+
+        let mut keyframes = Vec::new();
+        if num_frames > 0 {
+            let kind = try!(read_fourcc(f));
+            let _size = try!(f.read_u32::<LittleEndian>());
+            let (has_trans, has_scale) = match &kind {
+                b"KR00" => (false, false),
+                b"KRT0" => (true, false),
+                b"KRTS" => (true, true),
+                _ => return Err(Error::InvalidPackage),
+            };
+
+            for _ in (0..num_frames) {
+                let rot = try!(read_quat(f));
+                let trans = if has_trans { Some(try!(read_vec3(f))) } else { None };
+                let scale = if has_scale { Some(try!(read_vec3(f))) } else { None };
+                keyframes.push(KeyFrame {
+                    time: try!(f.read_f32::<LittleEndian>()),
+                    rot: rot,
+                    trans: trans,
+                    scale: scale,
+                });
+            }
+        }
+
+        Ok(AnimObject {
+            name: name,
+            bone_id: bone_id,
+            keyframes: keyframes,
+        })
+    }
+
+    fn read_anp3<R: Read + Seek>(f: &mut R, is_compressed: bool) -> Result<AnimObject> {
+        let name = try!(read_name(f, 24));
+        let frame_type = try!(f.read_u32::<LittleEndian>());
+        let num_frames = try!(f.read_u32::<LittleEndian>());
+        let bone_id = try!(f.read_i32::<LittleEndian>());
+
+        let has_trans = match (frame_type, is_compressed) {
+            (3, true) | (1, false) => false,
+            (4, true) | (2, false) => true,
+            _ => return Err(Error::InvalidPackage),
+        };
+
+        let mut keyframes = Vec::new();
+        for _ in (0..num_frames) {
+            let kf = if is_compressed {
+                let mut rot = [0.0f32; 4];
+                for x in rot.iter_mut() {
+                    *x = try!(f.read_i16::<LittleEndian>()) as f32 / 4096.0;
+                }
+                let time = try!(f.read_i16::<LittleEndian>()) as f32 / 60.0;
+                let trans = if has_trans {
+                    Some(rw::Vec3(try!(f.read_i16::<LittleEndian>()) as f32 / 1024.0,
+                                  try!(f.read_i16::<LittleEndian>()) as f32 / 1024.0,
+                                  try!(f.read_i16::<LittleEndian>()) as f32 / 1024.0))
+                } else {
+                    None
+                };
+                KeyFrame { time: time, rot: rot, trans: trans, scale: None }
+            } else {
+                let rot = try!(read_quat(f));
+                let trans = if has_trans { Some(try!(read_vec3(f))) } else { None };
+                let time = try!(f.read_f32::<LittleEndian>());
+                KeyFrame { time: time, rot: rot, trans: trans, scale: None }
+            };
+            keyframes.push(kf);
+        }
+
+        Ok(AnimObject {
+            name: name,
+            bone_id: Some(bone_id),
+            keyframes: keyframes,
+        })
+    }
+}
+
+/// Rounds a chunk size up to the 4 bytes alignment used by ANPK packages.
+fn padded(size: u32) -> u64 {
+    (size as u64 + 3) & !3
+}
+
+fn read_fourcc<R: Read>(f: &mut R) -> Result<[u8; 4]> {
+    let mut fourcc = [0u8; 4];
+    try!(read_full(f, &mut fourcc));
+    Ok(fourcc)
+}
+
+/// Reads a chunk header expecting the specified fourcc, returns the chunk size.
+fn expect_chunk<R: Read>(f: &mut R, fourcc: &[u8; 4]) -> Result<u32> {
+    if &try!(read_fourcc(f)) != fourcc {
+        return Err(Error::InvalidPackage);
+    }
+    Ok(try!(f.read_u32::<LittleEndian>()))
+}
+
+/// Reads a null terminated (or padded) name of `size` bytes.
+///
+/// The name buffer grows as it's read, so a bogus size fails at the end of the file.
+fn read_name<R: Read>(f: &mut R, size: u64) -> Result<String> {
+    let mut buf = Vec::new();
+    try!(f.by_ref().take(size).read_to_end(&mut buf));
+    if (buf.len() as u64) < size {
+        return Err(Error::IoError(byteorder::Error::UnexpectedEOF));
+    }
+    if let Some(endpos) = buf.iter().position(|&c| c == 0) {
+        buf.truncate(endpos);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn read_quat<R: Read>(f: &mut R) -> Result<[f32; 4]> {
+    Ok([try!(f.read_f32::<LittleEndian>()),
+        try!(f.read_f32::<LittleEndian>()),
+        try!(f.read_f32::<LittleEndian>()),
+        try!(f.read_f32::<LittleEndian>())])
+}
+
+fn read_vec3<R: Read>(f: &mut R) -> Result<rw::Vec3> {
+    Ok(rw::Vec3(try!(f.read_f32::<LittleEndian>()),
+                try!(f.read_f32::<LittleEndian>()),
+                try!(f.read_f32::<LittleEndian>())))
+}
+
+// TODO common function read_full
+fn read_full<R: Read>(f: &mut R, buf: &mut [u8]) -> byteorder::Result<()> {
+    let mut nread = 0usize;
+    while nread < buf.len() {
+        match f.read(&mut buf[nread..]) {
+            Ok(0) => return Err(byteorder::Error::UnexpectedEOF),
+            Ok(n) => nread += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(From::from(e))
+        }
+    }
+    Ok(())
+}
@@ -6,7 +6,9 @@ use std::io::{self, Seek, SeekFrom, Read, BufReader};
 use std::collections::HashMap;
 use byteorder::{self, ReadBytesExt, LittleEndian};
 use std::iter;
+use std::{error, fmt};
 
+#[derive(Debug)]
 pub enum Error {
     InvalidImage,
     NonExistingEntry,
@@ -27,6 +29,32 @@ impl From<io::Error> for Error {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IoError(ref err) => write!(f, "{}", err),
+            _ => write!(f, "{}", error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::InvalidImage => "invalid img archive",
+            Error::NonExistingEntry => "no such entry in the img archive",
+            Error::IoError(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CdEntry {
     offset: u32,         // in sectors
@@ -1,5 +1,6 @@
 #![feature(alloc)]
 #![feature(rc_weak)]
+#![feature(arc_weak)]
 // TODO there are lots of unwraps because of testing, get rid of those and add proper error
 // handling
 
@@ -20,7 +21,7 @@ mod rw;
 mod native;
 
 use native::{NativeGeometry, NativeDictionaryList, NativeTexture};
-use native::Renderer;
+use native::{Renderer, AlphaMode};
 
 mod user;
 use user::{UserCamera, UserControl};
@@ -28,6 +29,8 @@ use user::{UserCamera, UserControl};
 mod img;
 use img::{CdImage, CdEntry};
 
+mod ifp;
+
 use docopt::Docopt;
 
 use std::io::{Seek, Read};
@@ -36,6 +39,7 @@ use std::path::{PathBuf, Path};
 use std::io::BufReader;
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use glium::{Surface, DisplayBuild};
 use glium::backend::Facade;
@@ -102,22 +106,90 @@ pub fn try_load_clump<F, P1, P2>(facade: &F, dff: Option<P1>, txd: Option<P2>) -
 pub fn load_dictionary<F, R>(facade: &F,
                              rw: &mut rw::Instance,
                              f: R, txdname: &str)
-                            -> rw::Result<Rc<rw::TexDictionary>>
+                            -> rw::Result<Arc<rw::TexDictionary>>
                             where F: Facade, R: Read + Seek {
     rw::TexDictionary::read(&mut rw::Stream::new(f, rw), txdname)
 }
 
-pub fn load_clump<F, R>(facade: &F, 
-                        rw: &mut rw::Instance,
-                        rwdict: &Rc<rw::TexDictionary>,
-                        dicts: &NativeDictionaryList,
-                        f: R) -> Option<native::Clump>
-                        where F: Facade, R: Read + Seek {
+pub fn load_objects<R>(rw: &mut rw::Instance,
+                       rwdict: &Arc<rw::TexDictionary>,
+                       f: R) -> rw::Result<Vec<rw::Object>>
+                       where R: Read + Seek {
     rw.bind_dictionary(rwdict);
-    let rwclump = rw::Clump::read(&mut rw::Stream::new(f, rw)).unwrap();
-    native::Clump::from_rw(facade, &rwclump, &dicts)
+    rw.unbind_uv_dictionary();
+
+    // Clump streams may start with the UV animation dictionary used by its materials, which
+    // gets bound while reading the stream.
+    rw::read_stream(&mut rw::Stream::new(f, rw), true)
+}
+
+pub fn load_clump<F>(facade: &F, objects: &[rw::Object], dicts: &NativeDictionaryList) -> Option<native::Clump>
+                     where F: Facade {
+    // The first clump in the stream is displayed.
+    objects.iter().filter_map(|obj| match *obj {
+        rw::Object::Clump(ref rwclump) => Some(rwclump),
+        _ => None,
+    }).next().and_then(|rwclump| native::Clump::from_rw(facade, rwclump, dicts)).map(|clump| {
+        for diagnostic in clump.diagnostics() {
+            println!("Warning: {}", diagnostic);
+        }
+        clump
+    })
+}
+
+pub fn load_world<F>(facade: &F, objects: &[rw::Object], dicts: &NativeDictionaryList) -> Option<native::World>
+                     where F: Facade {
+    objects.iter().filter_map(|obj| match *obj {
+        rw::Object::World(ref rwworld) => Some(rwworld),
+        _ => None,
+    }).next().and_then(|rwworld| native::World::from_rw(facade, rwworld, dicts))
 }
 
+pub fn load_animation<R>(rw: &mut rw::Instance, f: R) -> rw::Result<Arc<rw::Animation>>
+                         where R: Read + Seek {
+    rw::Animation::read(&mut rw::Stream::new(f, rw)).map(Arc::new)
+}
+
+pub fn load_anim_package<R>(mut f: R) -> ifp::Result<ifp::AnimPackage>
+                            where R: Read + Seek {
+    ifp::AnimPackage::read(&mut f)
+}
+
+/// Builds a program from the shader sources at the specified paths.
+fn load_program<F: Facade>(facade: &F, vs_path: &str, fs_path: &str) -> glium::Program {
+    // TODO remove unwraps
+    let mut vertex_shader_src = String::with_capacity(512);
+    BufReader::new(
+        File::open(vs_path).unwrap()
+    ).read_to_string(&mut vertex_shader_src).unwrap();
+
+    let mut fragment_shader_src = String::with_capacity(512);
+    BufReader::new(
+        File::open(fs_path).unwrap()
+    ).read_to_string(&mut fragment_shader_src).unwrap();
+
+    glium::Program::from_source(facade,
+                                &vertex_shader_src,
+                                &fragment_shader_src,
+                                None).unwrap()
+}
+
+/// Reads a file from the filesystem, or if it isn't there, from the cd image.
+fn read_file(cd: &mut Option<CdImage>, path: &Path) -> Option<Vec<u8>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).ok().map(|_| data)
+        },
+        Err(_) => {
+            let fname = match path.file_name() {
+                Some(fname) => fname.to_string_lossy().into_owned(),
+                None => return None,
+            };
+            cd.as_mut().and_then(|cd| cd.read(&fname).ok())
+        },
+    }
+}
 
 
 static USAGE: &'static str = "
@@ -125,21 +197,32 @@ static USAGE: &'static str = "
 
     Usage:
       rwdraw [options] <dffname> <txdname>...
+      rwdraw [--img=<path>] --list-anims <ifpname>
       rwdraw (-h | --help)
       rwdraw --version
 
     Options:
-      -h --help     Show this screen.
-      --version     Show version.
-      --img=<path>  Reads the <dffname> and <txdname> from the specified img file.
-                    Filesystem paths are still accepted on <dffname> and <txdname>.
+      -h --help       Show this screen.
+      --version       Show version.
+      --img=<path>    Reads the <dffname> and <txdname> from the specified img file.
+                      Filesystem paths are still accepted on <dffname> and <txdname>.
+      --anm=<path>    Plays the specified RenderWare animation on the model.
+                      Takes precedence over --ifp and --anim, as does dropping an anm file.
+      --ifp=<path>    Animation package to play the --anim animation from.
+      --anim=<name>   Plays the specified animation from the --ifp package on the model.
+      --list-anims    Lists the animations in the <ifpname> animation package.
 ";
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     flag_img: Option<String>,
+    flag_anm: Option<String>,
+    flag_ifp: Option<String>,
+    flag_anim: Option<String>,
+    flag_list_anims: bool,
     arg_dffname: String,
     arg_txdname: Vec<String>,
+    arg_ifpname: String,
 }
 
 fn main() {
@@ -148,7 +231,36 @@ fn main() {
                             .map(|d| d.version(Some("rwdraw 0.1.0".to_owned())))
                             .and_then(|d| d.decode())
                             .unwrap_or_else(|e| e.exit());
-    display_main(args)
+    if args.flag_list_anims {
+        list_anims_main(args)
+    } else {
+        display_main(args)
+    }
+}
+
+fn list_anims_main(args: Args) {
+    let mut cd = args.flag_img.and_then(|flag_img| {
+        CdImage::open(PathBuf::from(flag_img)).ok()
+    });
+
+    let package = match read_file(&mut cd, Path::new(&args.arg_ifpname)) {
+        Some(data) => load_anim_package(Cursor::new(data)),
+        None => {
+            println!("Could not find {}", args.arg_ifpname);
+            return;
+        },
+    };
+
+    match package {
+        Ok(package) => {
+            println!("{} ({} animations)", package.name, package.animations.len());
+            for anim in package.animations.iter() {
+                println!("  {:<24} {:>3} objects {:>7.3}s",
+                         anim.name, anim.objects.len(), anim.duration());
+            }
+        },
+        Err(err) => println!("Could not read {}: {:?}", args.arg_ifpname, err),
+    }
 }
 
 
@@ -173,35 +285,29 @@ fn display_main(args: Args) {
     // cargo run -- "target/containercrane_04.dff" "target/cranes_dyn2_cj.txd"
     // Program data
     let mut should_reload_model = true;
+    let mut should_reload_anim = true;
     let mut clump = None;
+    let mut world = None;
     let mut cd = args.flag_img.and_then(|flag_img| {
         CdImage::open(PathBuf::from(flag_img)).ok()
     });
     let mut dffname = Some(PathBuf::from(args.arg_dffname));
     let mut txdname = Some(PathBuf::from(args.arg_txdname[0].clone()));
+    let mut anmname = args.flag_anm.map(PathBuf::from);
+    let ifpname = args.flag_ifp.map(PathBuf::from);
+    let ifp_anim = args.flag_anim;
     
     // Frame timing for delta time
     let mut curr_frame_time: f64 = clock_ticks::precise_time_s();
     let mut last_frame_time: f64;
 
     // Program object / shaders for rendering.
-    let program = {
-        // TODO remove unwraps
-        let mut vertex_shader_src = String::with_capacity(512);
-        BufReader::new(
-            File::open(r"src/shader/gta3_prelit_tex1.vs.glsl").unwrap()
-        ).read_to_string(&mut vertex_shader_src).unwrap();
-
-        let mut fragment_shader_src = String::with_capacity(512);
-        BufReader::new(
-            File::open(r"src/shader/gta3_prelit_tex1.fs.glsl").unwrap()
-        ).read_to_string(&mut fragment_shader_src).unwrap();
-
-        glium::Program::from_source(&display, 
-                                    &vertex_shader_src,
-                                    &fragment_shader_src,
-                                    None).unwrap()
-    };
+    let program = load_program(&display, r"src/shader/gta3_prelit_tex1.vs.glsl",
+                                         r"src/shader/gta3_prelit_tex1.fs.glsl");
+    let skin_program = Rc::new(load_program(&display, r"src/shader/gta3_skin_tex1.vs.glsl",
+                                                      r"src/shader/gta3_prelit_tex1.fs.glsl"));
+    let lit_program = Rc::new(load_program(&display, r"src/shader/gta3_lit_tex1.vs.glsl",
+                                                     r"src/shader/gta3_prelit_tex1.fs.glsl"));
 
     // Blank texture
     let tex_blank = Rc::new(NativeTexture::new_blank_texture(&display));
@@ -238,10 +344,14 @@ fn display_main(args: Args) {
                             txdname = Some(path);
                             should_reload_model = true;
                         },
-                        Some(ref ext) if ext == "dff" => {
+                        Some(ref ext) if ext == "dff" || ext == "bsp" => {
                             dffname = Some(path);
                             should_reload_model = true;
                         },
+                        Some(ref ext) if ext == "anm" => {
+                            anmname = Some(path);
+                            should_reload_anim = true;
+                        },
                         _ => (),
                     }
                 },
@@ -253,7 +363,7 @@ fn display_main(args: Args) {
             should_reload_model = false;
 
             // TODO remove unwraps
-            clump = match cd {
+            let (new_clump, new_world) = match cd {
                 Some(ref mut cd) => {
                     let mut rw = rw::Instance::new();
 
@@ -274,23 +384,86 @@ fn display_main(args: Args) {
 
                     dicts.add_rwdict(&display, &rwdict);
 
-                    cd.read(&dff_fname).ok().and_then(|data| {
-                        load_clump(&display, &mut rw, &rwdict, &dicts, Cursor::new(data))
-                    })
+                    let objects = match cd.read(&dff_fname) {
+                        Ok(data) => match load_objects(&mut rw, &rwdict, Cursor::new(data)) {
+                            Ok(objects) => objects,
+                            Err(err) => {
+                                println!("Could not read {}: {}", dff_fname, err);
+                                Vec::new()
+                            },
+                        },
+                        Err(_) => {
+                            println!("Could not find {}", dff_fname);
+                            Vec::new()
+                        },
+                    };
+
+                    (load_clump(&display, &objects, &dicts), load_world(&display, &objects, &dicts))
                 },
-                None => None,
+                None => (None, None),
             };
+            clump = new_clump;
+            world = new_world;
+
+            should_reload_anim = true;
         }
 
-        let mut renderer = Renderer::new(display.draw(), tex_blank.clone());
+        if should_reload_anim {
+            should_reload_anim = false;
+
+            let anim = anmname.as_ref().and_then(|path| read_file(&mut cd, path)).and_then(|data| {
+                let mut rw = rw::Instance::new();
+                load_animation(&mut rw, Cursor::new(data)).ok()
+            });
+
+            let package = match (anim.as_ref(), ifpname.as_ref()) {
+                (None, Some(path)) => read_file(&mut cd, path).and_then(|data| {
+                    load_anim_package(Cursor::new(data)).ok()
+                }),
+                _ => None,
+            };
+
+            if let Some(ref mut clump) = clump {
+                match package {
+                    Some(ref package) => {
+                        let ifp_anim = ifp_anim.as_ref().and_then(|name| package.find(name));
+                        clump.set_ifp_animation(ifp_anim)
+                    },
+                    None => clump.set_animation(anim),
+                }
+            }
+        }
+
+        let mut renderer = Renderer::new(display.draw(), tex_blank.clone(),
+                                         skin_program.clone(), lit_program.clone());
         renderer.target.clear_color_and_depth((0.0, 0.0, 1.0, 1.0), 1.0);
 
         let view = camera.process_view_matrix(&user, delta_time);
 
-        if let Some(ref clump) = clump {
-            let model = Matrix4::<f32>::identity();
-            clump.render(&mut renderer, &program, &proj, &(view * xzy_to_xyz * model));
+        if let Some(ref world) = world {
+            world.update(delta_time);
+        }
+
+        if let Some(ref mut clump) = clump {
+            clump.update(delta_time);
+        }
+
+        // Opaque meshes first, then the alpha tested ones, and finally the translucent ones,
+        // which are only queued by the passes and then sorted all together.
+        for &pass in AlphaMode::passes().iter() {
+            renderer.pass = pass;
+
+            if let Some(ref world) = world {
+                let model = Matrix4::<f32>::identity();
+                world.render(&mut renderer, &program, &proj, &(view * xzy_to_xyz * model));
+            }
+
+            if let Some(ref clump) = clump {
+                let model = Matrix4::<f32>::identity();
+                clump.render(&mut renderer, &program, &proj, &(view * xzy_to_xyz * model));
+            }
         }
+        renderer.render_translucent(&program, &proj);
 
         renderer.into_surface().finish().unwrap();
     } 
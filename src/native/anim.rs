@@ -0,0 +1,138 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::cell::{Cell, RefCell};
+use cgmath::Matrix4;
+use rw;
+use super::model::Node;
+
+/// Node animated by an animation node.
+#[derive(Debug)]
+pub struct AnimTarget {
+    pub node: Rc<RefCell<Node>>,
+    /// Whether only the rotation is animated, keeping the translation of the node.
+    pub rotation_only: bool,
+}
+
+/// Plays a hierarchical animation.
+#[derive(Debug)]
+pub struct AnimPlayer {
+    anim: Arc<rw::Animation>,
+    targets: Vec<Option<AnimTarget>>,
+    time: f32,
+}
+
+impl AnimPlayer {
+    /// Constructs a player starting at the beggining of `anim`.
+    ///
+    /// Each animation node `i` animates `targets[i]`, if any.
+    pub fn new(anim: Arc<rw::Animation>, targets: Vec<Option<AnimTarget>>) -> AnimPlayer {
+        AnimPlayer {
+            anim: anim,
+            targets: targets,
+            time: 0.0,
+        }
+    }
+
+    /// Gets the animation being played.
+    pub fn animation(&self) -> &Arc<rw::Animation> {
+        &self.anim
+    }
+
+    /// Gets the nodes animated by this player.
+    pub fn targets(&self) -> &[Option<AnimTarget>] {
+        &self.targets
+    }
+
+    /// Advances the animation time, looping at the end of the animation.
+    pub fn update(&mut self, delta_time: f32) {
+        let duration = self.anim.duration;
+        let time = self.time + delta_time;
+        self.time = if duration > 0.0 { time % duration } else { 0.0 };
+    }
+
+    /// Updates the local transformation of the animated nodes to the current time.
+    ///
+    /// The world transformations must be recomputed afterwards.
+    pub fn apply(&self) {
+        for (i, target) in self.targets.iter().enumerate() {
+            let target = match *target {
+                Some(ref target) => target,
+                None => continue,
+            };
+            if let Some(rwmatrix) = self.anim.node_matrix(i, self.time) {
+                let mut node = target.node.borrow_mut();
+                let mut matrix = Matrix4::from(rwmatrix);
+                if target.rotation_only {
+                    matrix.w = node.base_matrix.w;
+                }
+                node.matrix = matrix;
+            }
+        }
+    }
+}
+
+/// Plays the UV animation of a material.
+#[derive(Debug)]
+pub struct UvAnimPlayer {
+    anim: Arc<rw::Animation>,
+    node: usize,
+    time: Cell<f32>,
+}
+
+impl UvAnimPlayer {
+    /// Constructs a player for the node of `anim` animating the specified UV channel.
+    ///
+    /// Returns `None` if no node animates such channel.
+    pub fn new(anim: Arc<rw::Animation>, channel: u32) -> Option<UvAnimPlayer> {
+        let node = match anim.data {
+            rw::AnimData::UvLinear(ref uvanim) | rw::AnimData::UvParam(ref uvanim) => {
+                (0..anim.num_nodes()).find(|&node| {
+                    uvanim.node_to_channel.get(node).map(|&c| c == channel).unwrap_or(false)
+                })
+            },
+            _ => None,
+        };
+
+        node.map(|node| UvAnimPlayer {
+            anim: anim.clone(),
+            node: node,
+            time: Cell::new(0.0),
+        })
+    }
+
+    /// Advances the animation time, looping at the end of the animation.
+    pub fn update(&self, delta_time: f32) {
+        let duration = self.anim.duration;
+        let time = self.time.get() + delta_time;
+        self.time.set(if duration > 0.0 { time % duration } else { 0.0 });
+    }
+
+    /// Computes the UV matrix at the current animation time.
+    pub fn uv_matrix(&self) -> rw::UvMatrix {
+        self.anim.uv_matrix(self.node, self.time.get()).unwrap_or(UV_IDENTITY)
+    }
+}
+
+/// The identity UV matrix.
+pub const UV_IDENTITY: rw::UvMatrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// Composes two UV matrices, `a` applied after `b`.
+pub fn uv_multiply(a: &rw::UvMatrix, b: &rw::UvMatrix) -> rw::UvMatrix {
+    [
+        a[0] * b[0] + a[2] * b[1],
+        a[1] * b[0] + a[3] * b[1],
+        a[0] * b[2] + a[2] * b[3],
+        a[1] * b[2] + a[3] * b[3],
+        a[0] * b[4] + a[2] * b[5] + a[4],
+        a[1] * b[4] + a[3] * b[5] + a[5],
+    ]
+}
+
+/// Converts an UV matrix into a column-major 3x3 matrix for use in shaders.
+pub fn uv_to_mat3(m: &rw::UvMatrix) -> [[f32; 3]; 3] {
+    [
+        [m[0], m[1], 0.0],
+        [m[2], m[3], 0.0],
+        [m[4], m[5], 1.0],
+    ]
+}
@@ -0,0 +1,161 @@
+//! Decoding of S3TC (DXT) compressed images into RGBA 8 bits each.
+//!
+//! Used when the driver is unable to sample compressed textures by itself. Images are made of
+//! blocks of 4x4 pixels, ordered from left to right and top to bottom.
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DxtFormat {
+    Dxt1,
+    Dxt3,
+    Dxt5,
+}
+
+impl DxtFormat {
+    /// Gets the size in bytes of a block of 4x4 pixels.
+    fn block_size(&self) -> usize {
+        match *self {
+            DxtFormat::Dxt1 => 8,
+            DxtFormat::Dxt3 | DxtFormat::Dxt5 => 16,
+        }
+    }
+}
+
+/// Decodes an image of the specified format and dimensions into RGBA 8 bits each.
+///
+/// Missing blocks, if `data` is too short, are decoded as transparent black.
+pub fn decode(format: DxtFormat, width: usize, height: usize, data: &[u8]) -> Vec<(u8, u8, u8, u8)> {
+    let bsize = format.block_size();
+    let blocks_x = (width + 3) / 4;
+    let blocks_y = (height + 3) / 4;
+    let mut rgba = vec![(0u8, 0u8, 0u8, 0u8); width * height];
+
+    for by in (0..blocks_y) {
+        for bx in (0..blocks_x) {
+            let offset = (by * blocks_x + bx) * bsize;
+            if offset + bsize > data.len() {
+                continue;
+            }
+            let block = &data[offset..offset + bsize];
+            for py in (0..4) {
+                for px in (0..4) {
+                    let (x, y) = (bx * 4 + px, by * 4 + py);
+                    if x < width && y < height {
+                        rgba[y * width + x] = decode_pixel(format, block, px, py);
+                    }
+                }
+            }
+        }
+    }
+
+    rgba
+}
+
+/// Decodes the pixel at the specified position in a block.
+fn decode_pixel(format: DxtFormat, block: &[u8], px: usize, py: usize) -> (u8, u8, u8, u8) {
+    let i = py * 4 + px;
+    match format {
+        DxtFormat::Dxt1 => color(block, px, py, true),
+        DxtFormat::Dxt3 => {
+            let nibble = (le16(block, py * 2) >> (px * 4)) & 0xF;
+            let (r, g, b, _) = color(&block[8..], px, py, false);
+            (r, g, b, (nibble as u8) * 17)
+        },
+        DxtFormat::Dxt5 => {
+            let (a0, a1) = (block[0] as u32, block[1] as u32);
+            let bits = (0..6).fold(0u64, |bits, k| bits | ((block[2 + k] as u64) << (8 * k)));
+            let index = ((bits >> (3 * i)) & 0x7) as u32;
+            let alpha = match index {
+                0 => a0,
+                1 => a1,
+                index if a0 > a1 => ((8 - index) * a0 + (index - 1) * a1) / 7,
+                6 => 0,
+                7 => 255,
+                index => ((6 - index) * a0 + (index - 1) * a1) / 5,
+            };
+            let (r, g, b, _) = color(&block[8..], px, py, false);
+            (r, g, b, alpha as u8)
+        },
+    }
+}
+
+/// Decodes a pixel of a color block, which may use the three colors mode only if `dxt1`.
+fn color(block: &[u8], px: usize, py: usize, dxt1: bool) -> (u8, u8, u8, u8) {
+    let (c0, c1) = (le16(block, 0), le16(block, 2));
+    let index = (block[4 + py] >> (px * 2)) & 0x3;
+    let (a, b) = (rgb565(c0), rgb565(c1));
+    let mix = |wa: u32, wb: u32| {
+        let channel = |i: usize| ((a[i] as u32 * wa + b[i] as u32 * wb) / (wa + wb)) as u8;
+        (channel(0), channel(1), channel(2), 255)
+    };
+    match index {
+        0 => (a[0], a[1], a[2], 255),
+        1 => (b[0], b[1], b[2], 255),
+        2 if c0 > c1 || !dxt1 => mix(2, 1),
+        3 if c0 > c1 || !dxt1 => mix(1, 2),
+        2 => mix(1, 1),
+        _ => (0, 0, 0, 0),
+    }
+}
+
+fn le16(block: &[u8], offset: usize) -> u16 {
+    (block[offset] as u16) | ((block[offset + 1] as u16) << 8)
+}
+
+fn rgb565(c: u16) -> [u8; 3] {
+    let (r, g, b) = ((c >> 11) as u32, ((c >> 5) & 0x3F) as u32, (c & 0x1F) as u32);
+    [((r << 3) | (r >> 2)) as u8, ((g << 2) | (g >> 4)) as u8, ((b << 3) | (b >> 2)) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, DxtFormat};
+
+    #[test]
+    fn dxt1_four_colors() {
+        // Red and blue endpoints, indices 0, 1, 2 and 3 on the first row.
+        let block = [0x00, 0xF8, 0x1F, 0x00, 0xE4, 0, 0, 0];
+        let rgba = decode(DxtFormat::Dxt1, 4, 1, &block);
+        assert_eq!(rgba, vec![(255, 0, 0, 255), (0, 0, 255, 255),
+                              (170, 0, 85, 255), (85, 0, 170, 255)]);
+    }
+
+    #[test]
+    fn dxt1_three_colors() {
+        // Black and white endpoints in this order give the transparent mode.
+        let block = [0x00, 0x00, 0xFF, 0xFF, 0xE4, 0, 0, 0];
+        let rgba = decode(DxtFormat::Dxt1, 4, 1, &block);
+        assert_eq!(rgba, vec![(0, 0, 0, 255), (255, 255, 255, 255),
+                              (127, 127, 127, 255), (0, 0, 0, 0)]);
+    }
+
+    #[test]
+    fn dxt3_explicit_alpha() {
+        let block = [0xF0, 0x08, 0, 0, 0, 0, 0, 0,
+                     0x00, 0x00, 0xFF, 0xFF, 0xE4, 0, 0, 0];
+        let rgba = decode(DxtFormat::Dxt3, 4, 1, &block);
+        // Never in the transparent mode, unlike DXT1.
+        assert_eq!(rgba, vec![(0, 0, 0, 0), (255, 255, 255, 255),
+                              (85, 85, 85, 136), (170, 170, 170, 0)]);
+    }
+
+    #[test]
+    fn dxt5_interpolated_alpha() {
+        // Eight alphas mode, indices 0, 1, 2 and 7.
+        let block = [255, 0, 0x88, 0x0E, 0, 0, 0, 0,
+                     0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0];
+        let alphas: Vec<_> = decode(DxtFormat::Dxt5, 4, 1, &block).iter().map(|p| p.3).collect();
+        assert_eq!(alphas, vec![255, 0, 218, 36]);
+
+        // Six alphas mode, indices 6, 7, 2 and 0.
+        let block = [0, 255, 0xBE, 0, 0, 0, 0, 0,
+                     0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0];
+        let alphas: Vec<_> = decode(DxtFormat::Dxt5, 4, 1, &block).iter().map(|p| p.3).collect();
+        assert_eq!(alphas, vec![0, 255, 51, 0]);
+    }
+
+    #[test]
+    fn missing_blocks() {
+        let rgba = decode(DxtFormat::Dxt5, 8, 4, &[0u8; 16]);
+        assert_eq!(rgba[4], (0, 0, 0, 0));
+    }
+}
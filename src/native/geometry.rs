@@ -1,18 +1,20 @@
-// TODO NativeMaterial instead of pushing in stuff to the NativeMesh
-
 use std::rc::Rc;
 use std::collections::HashMap;
-use super::{NativeDictionary, NativeDictionaryList, NativeTexture};
+use super::{NativeDictionary, NativeDictionaryList, NativeTexture, UvAnimPlayer, AlphaMode};
+use super::texture;
+use super::anim;
 
 use rw;
 use std::ops::Range;
+use std::cmp;
 use glium::{self, Surface};
 use glium::vertex::{VertexBuffer};
 use glium::index::{IndexBuffer, PrimitiveType};
+use glium::uniforms::{UniformBuffer, SamplerBehavior};
 use glium::backend::Facade;
-use super::Renderer;
+use super::{Renderer, TranslucentDraw};
 
-use cgmath::{Point3, Vector2, Vector4, Matrix4};
+use cgmath::{Point3, Vector2, Vector3, Vector4, Matrix4};
 
 #[derive(Debug, Copy, Clone)]
 pub struct VertexPrelit {
@@ -24,16 +26,62 @@ pub struct VertexPrelit {
 #[derive(Debug, Copy, Clone)]
 pub struct VertexLit {
     pos: Point3<f32>,
+    normal: Vector3<f32>,
+    /// Pre-light color, added to the light from the scene.
+    color: Vector4<f32>,
+    uv0: Vector2<f32>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct VertexSkinned {
+    pos: Point3<f32>,
+    normal: Vector3<f32>,
+    color: Vector4<f32>,
     uv0: Vector2<f32>,
+    /// Indices of the bones influencing the vertex, as floats for the sake of older GLSL.
+    bone_indices: Vector4<f32>,
+    bone_weights: Vector4<f32>,
 }
 
 implement_vertex!(VertexPrelit, pos, color, uv0);
-implement_vertex!(VertexLit, pos, uv0);
+implement_vertex!(VertexLit, pos, normal, color, uv0);
+implement_vertex!(VertexSkinned, pos, normal, color, uv0, bone_indices, bone_weights);
+
+/// Maximum number of bones in a skinned geometry, must match the skinning shader.
+pub const MAX_BONES: usize = 64;
+
+/// Bone matrices uploaded to the skinning shader.
+///
+/// Each matrix transforms vertices from the bind pose into the current pose of its bone.
+#[derive(Copy)]
+pub struct BonePalette {
+    pub matrices: [[[f32; 4]; 4]; MAX_BONES],
+}
+
+impl Clone for BonePalette {
+    fn clone(&self) -> BonePalette {
+        *self
+    }
+}
+
+impl ::std::fmt::Debug for BonePalette {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "BonePalette")
+    }
+}
+
+/// Skinning information of a geometry.
+#[derive(Debug)]
+pub struct NativeSkin {
+    /// Transforms vertices into the local space of each bone on the bind pose.
+    pub inverse_matrices: Vec<Matrix4<f32>>,
+}
 
 #[derive(Debug)]
 pub enum NativeVertexBuffer {
     Prelit(VertexBuffer<VertexPrelit>),
     Lit(VertexBuffer<VertexLit>),
+    Skinned(VertexBuffer<VertexSkinned>),
 }
 
 impl<'a> glium::vertex::IntoVerticesSource<'a> for &'a NativeVertexBuffer {
@@ -42,14 +90,36 @@ impl<'a> glium::vertex::IntoVerticesSource<'a> for &'a NativeVertexBuffer {
         match *self {
             Lit(ref vbo) => vbo.into_vertices_source(),
             Prelit(ref vbo) => vbo.into_vertices_source(),
+            Skinned(ref vbo) => vbo.into_vertices_source(),
         }
     }
 }
 
+/// Appearance of the meshes of a geometry, built out of a `rw::Material`.
+#[derive(Debug)]
+pub struct NativeMaterial {
+    pub texture: Option<Rc<NativeTexture>>,
+    /// How the texture is filtered and addressed.
+    pub sampler: SamplerBehavior,
+    pub uv_anims: Vec<UvAnimPlayer>,
+    /// Color the mesh is modulated by, in the range 0.0 to 1.0.
+    ///
+    /// White unless the geometry modulates by the material color.
+    pub color: [f32; 4],
+    /// Ambient reflection coefficient.
+    pub ambient: f32,
+    /// Diffuse reflection coefficient.
+    pub diffuse: f32,
+    /// How the alpha of the material color and texture affects rendering.
+    pub alpha: AlphaMode,
+}
+
 #[derive(Debug)]
 pub struct NativeMesh {
     pub range: Range<usize>,
-    pub texture: Option<Rc<NativeTexture>>,
+    pub material: NativeMaterial,
+    /// Center of the vertices of the mesh, used to sort translucent meshes.
+    pub center: [f32; 3],
 }
 
 #[derive(Debug)]
@@ -57,117 +127,238 @@ pub struct NativeGeometry {
     pub vbo: NativeVertexBuffer,
     pub ibo: IndexBuffer<u16>,
     pub meshes: Vec<NativeMesh>,
+    pub skin: Option<NativeSkin>,
+    /// Whether the geometry is shaded by the lights of the renderer, being flagged as lit and
+    /// having normals. Pre-light colors, if any, are added to the light from the scene.
+    pub is_lit: bool,
+    /// Center of the vertices of the geometry, used to sort translucent geometries.
+    pub center: [f32; 3],
 }
 
-impl NativeGeometry {
-
-    pub fn render<S: Surface>(&self, renderer: &mut Renderer<S>, program: &glium::Program,
-                              proj: &Matrix4<f32>, model_view: &Matrix4<f32>) 
-    {
-        use glium::draw_parameters::{DepthTest, BlendingFunction};
-        use glium::draw_parameters::LinearBlendingFactor::*;
+impl NativeMaterial {
+    /// Builds the appearance of a mesh out of `material`, finding its texture in `dicts`.
+    ///
+    /// The material color is only applied when `modulate` is set, as told by the geometry flags.
+    pub fn from_rw(material: &rw::Material, modulate: bool, dicts: &NativeDictionaryList) -> NativeMaterial {
+        let texture = match material.texture {
+            Some(ref tex) => dicts.find(&tex.dict, &tex.name),
+            None => None,
+        };
 
-        for mesh in self.meshes.iter() {
-            let uniforms = uniform! {
-                model_view_proj: (*proj) * (*model_view),
-                tex: &mesh.texture.as_ref().unwrap_or(&renderer.tex_blank).tex,
-            };
+        let sampler = match material.texture {
+            Some(ref tex) => texture::sampler_behavior(tex.filter, tex.wrap_x, tex.wrap_y),
+            None => SamplerBehavior::default(),
+        };
 
-            // TODO blending only when necessary
-            let params = glium::DrawParameters {
-                depth_test: DepthTest::IfLess,
-                depth_write: true,
-                blending_function: Some(BlendingFunction::Addition { source: SourceAlpha, destination: OneMinusSourceAlpha }),
-                .. Default::default()
-            };
+        // Only the first UV channel is used by our shaders.
+        let uv_anims = match material.plugin::<rw::MaterialUvAnimPlg>() {
+            Some(uvanim) => uvanim.animations.iter().filter_map(|anim| {
+                UvAnimPlayer::new(anim.clone(), 0)
+            }).collect(),
+            None => Vec::new(),
+        };
 
-            renderer.target.draw(&self.vbo, self.ibo.slice(mesh.range.clone()).unwrap(),
-                                 &program, &uniforms, &params).unwrap();
+        let color = if modulate { Vector4::from(material.color) } else { Vector4::new(1.0f32, 1.0, 1.0, 1.0) };
+        let color_alpha = if color.w < 1.0 { AlphaMode::Blended } else { AlphaMode::Opaque };
+        let alpha = cmp::max(color_alpha, texture.as_ref().map(|tex| tex.alpha).unwrap_or(AlphaMode::Opaque));
+
+        NativeMaterial {
+            texture: texture,
+            sampler: sampler,
+            uv_anims: uv_anims,
+            color: [color.x, color.y, color.z, color.w],
+            ambient: material.surf.ambient,
+            diffuse: material.surf.diffuse,
+            alpha: alpha,
         }
     }
 
-    pub fn from_rw<F: Facade>(facade: &F, rwgeo: &rw::Geometry,
-                              dicts: &NativeDictionaryList) -> Option<NativeGeometry> {
+    /// Computes the texture coordinates transformation from the UV animations of this material.
+    pub fn uv_matrix(&self) -> rw::UvMatrix {
+        self.uv_anims.iter().fold(anim::UV_IDENTITY, |m, player| {
+            anim::uv_multiply(&player.uv_matrix(), &m)
+        })
+    }
+}
 
-        use self::NativeVertexBuffer::*;
-        
-        struct RwData<'a> {
-            verts: Option<&'a Vec<rw::Vec3>>,
-            normals: Option<&'a Vec<rw::Vec3>>,
-            colors: Option<&'a Vec<rw::Rgba>>,
-            uv0: Option<&'a Vec<rw::Uv>>,
-        }
+impl NativeGeometry {
 
-        // Gather all the information we need to pattern match this RwGeometry and build the
-        // correct Vertex Buffer Object.
-        let rwdata = {
-            RwData {
-                // ignore any morph target that is not the first one because gta uses only that.
-                verts: rwgeo.targets.get(0).and_then(|target| target.verts.as_ref()),
-                normals: rwgeo.targets.get(0).and_then(|target| target.normals.as_ref()),
-                colors: rwgeo.colors.as_ref(),
-                uv0: rwgeo.uv_sets.get(0),
+    /// Advances the animations of this geometry.
+    pub fn update(&self, delta_time: f32) {
+        for mesh in self.meshes.iter() {
+            for player in mesh.material.uv_anims.iter() {
+                player.update(delta_time);
             }
-        };
+        }
+    }
 
-        // Build the vertex buffer specific for this type of model, we gonna do this by pattern
-        // matching the data we previosly built. 
-        let vertex_buffer = match rwdata {
-            // In case it's a prelit geometry...
-            RwData { verts: Some(verts), normals: _, colors: Some(colors), uv0: Some(uv0) } => {
+    /// Checks whether this geometry must be rendered with a bone palette.
+    pub fn is_skinned(&self) -> bool {
+        match self.vbo {
+            NativeVertexBuffer::Skinned(_) => true,
+            _ => false,
+        }
+    }
 
-                // Maybe make this a pattern guard?
-                if verts.len() != uv0.len() || uv0.len() != colors.len() {
-                    return None;
-                }
+    /// Checks whether this geometry has meshes of the specified alpha mode.
+    pub fn has_pass(&self, pass: AlphaMode) -> bool {
+        self.meshes.iter().any(|mesh| mesh.material.alpha == pass)
+    }
 
-                let maybe_buffer = VertexBuffer::new(facade,
-                    izip!(verts.iter(), colors.iter(), uv0.iter()).map(|(vert, rgba, uv0)| {
-                        VertexPrelit {
-                            pos: (*vert).into(),
-                            color: (*rgba).into(), // auto converts between 0-255 to 0-1 range
-                            uv0: (*uv0).into(),
-                        }
-                    }).collect::<Vec<_>>().as_ref()
-                );
+    /// Renders the meshes of the geometry belonging to the current pass of the `renderer`.
+    ///
+    /// Translucent meshes are only queued into the `renderer`, to be sorted along with the
+    /// ones of every other geometry, see `Renderer::render_translucent`.
+    pub fn render<S: Surface>(myself: &Rc<NativeGeometry>, renderer: &mut Renderer<S>,
+                              program: &glium::Program, proj: &Matrix4<f32>, model_view: &Matrix4<f32>,
+                              bones: Option<&Rc<UniformBuffer<BonePalette>>>)
+    {
+        let pass = renderer.pass;
+        let lights = renderer.lights;
+        for (i, mesh) in myself.meshes.iter().enumerate().filter(|&(_, mesh)| mesh.material.alpha == pass) {
+            if pass == AlphaMode::Blended {
+                renderer.queue_translucent(TranslucentDraw {
+                    geometry: myself.clone(),
+                    mesh: i,
+                    model_view: *model_view,
+                    bones: bones.map(|bones| bones.clone()),
+                    lights: lights,
+                    depth: view_depth(model_view, mesh.center),
+                });
+            } else {
+                myself.render_mesh(renderer, program, proj, model_view, bones.map(|bones| &**bones), mesh);
+            }
+        }
+    }
 
-                match maybe_buffer {
-                    Ok(vbo) => NativeVertexBuffer::Prelit(vbo),
-                    Err(_) => return None,
-                }
+    /// Renders a mesh of this geometry right away, as told by its alpha mode.
+    ///
+    /// Translucent meshes are rendered without writing depth. Skinned geometries are rendered
+    /// with the skinning program of the `renderer` and require the `bones` palette, they're not
+    /// rendered at all otherwise. Lit geometries are rendered with the lit program of the
+    /// `renderer`, shaded by its lights, and `program` is used for the remaining ones.
+    pub fn render_mesh<S: Surface>(&self, renderer: &mut Renderer<S>, program: &glium::Program,
+                                   proj: &Matrix4<f32>, model_view: &Matrix4<f32>,
+                                   bones: Option<&UniformBuffer<BonePalette>>, mesh: &NativeMesh)
+    {
+        use glium::draw_parameters::{DepthTest, BlendingFunction};
+        use glium::draw_parameters::LinearBlendingFactor::*;
+
+        let skin_program = renderer.skin_program.clone();
+        let lit_program = renderer.lit_program.clone();
+        let lights = renderer.lights;
+
+        // Fully transparent fragments are discarded even when blending, so they don't hide
+        // what's behind in case depth is written.
+        let (alpha_ref, blending_function, depth_write) = match mesh.material.alpha {
+            AlphaMode::Opaque => (0.0f32, None, true),
+            AlphaMode::Tested => (0.5, None, true),
+            AlphaMode::Blended => {
+                (1.0 / 255.0, Some(BlendingFunction::Addition { source: SourceAlpha, destination: OneMinusSourceAlpha }), false)
             },
+        };
+        let params = glium::DrawParameters {
+            depth_test: DepthTest::IfLess,
+            depth_write: depth_write,
+            blending_function: blending_function,
+            .. Default::default()
+        };
 
-            // In case it's not prelit...
-            // TODO what to actually do about this (NativeVertexBuffer::Prelit etc)
-            RwData { verts: Some(verts), normals: _, colors: None, uv0: Some(uv0) } => {
+        let model_view_proj = (*proj) * (*model_view);
+        let material = &mesh.material;
+        let uv_matrix = anim::uv_to_mat3(&material.uv_matrix());
+        let tex = material.texture.as_ref().unwrap_or(&renderer.tex_blank).sampled(material.sampler);
+
+        let indices = self.ibo.slice(mesh.range.clone()).unwrap();
+
+        match (self.is_skinned(), bones) {
+            (true, Some(bones)) => {
+                let uniforms = uniform! {
+                    model_view_proj: model_view_proj,
+                    model_view: *model_view,
+                    uv_matrix: uv_matrix,
+                    tex: tex,
+                    material_color: material.color,
+                    alpha_ref: alpha_ref,
+                    ambient: material.ambient,
+                    diffuse: material.diffuse,
+                    Bones: bones,
+                    lit: self.is_lit,
+                    light_direction: lights.direction,
+                    light_color: lights.color,
+                    ambient_color: lights.ambient,
+                };
+                renderer.target.draw(&self.vbo, indices, &skin_program,
+                                     &uniforms, &params).unwrap();
+            },
+            (true, None) => {},
+            (false, _) if self.is_lit => {
+                let uniforms = uniform! {
+                    model_view_proj: model_view_proj,
+                    model_view: *model_view,
+                    uv_matrix: uv_matrix,
+                    tex: tex,
+                    material_color: material.color,
+                    alpha_ref: alpha_ref,
+                    ambient: material.ambient,
+                    diffuse: material.diffuse,
+                    light_direction: lights.direction,
+                    light_color: lights.color,
+                    ambient_color: lights.ambient,
+                };
+                renderer.target.draw(&self.vbo, indices, &lit_program,
+                                     &uniforms, &params).unwrap();
+            },
+            (false, _) => {
+                let uniforms = uniform! {
+                    model_view_proj: model_view_proj,
+                    uv_matrix: uv_matrix,
+                    tex: tex,
+                    material_color: material.color,
+                    alpha_ref: alpha_ref,
+                    ambient: material.ambient,
+                    diffuse: material.diffuse,
+                };
+                renderer.target.draw(&self.vbo, indices, &program,
+                                     &uniforms, &params).unwrap();
+            },
+        }
+    }
 
-                // Maybe make this a pattern guard?
-                if verts.len() != uv0.len() {
-                    return None;
-                }
+    pub fn from_rw<F: Facade>(facade: &F, rwgeo: &rw::Geometry,
+                              dicts: &NativeDictionaryList) -> Option<NativeGeometry> {
 
-                let maybe_buffer = VertexBuffer::new(facade,
-                    izip!(verts.iter(), uv0.iter()).map(|(vert, uv0)| {
-                        VertexPrelit {
-                            pos: (*vert).into(),
-                            color: Vector4::new(1.0f32, 1.0, 1.0, 1.0),
-                            uv0: (*uv0).into(),
-                        }
-                    }).collect::<Vec<_>>().as_ref()
-                );
+        // Gather all the information we need to pattern match this RwGeometry and build the
+        // correct Vertex Buffer Object.
+        let rwdata = RwData {
+            // ignore any morph target that is not the first one because gta uses only that.
+            verts: rwgeo.targets.get(0).and_then(|target| target.verts.as_ref()),
+            normals: rwgeo.targets.get(0).and_then(|target| target.normals.as_ref()),
+            colors: rwgeo.colors.as_ref(),
+            uv0: rwgeo.uv_sets.get(0),
+            skin: rwgeo.plugin::<rw::SkinPlg>(),
+        };
 
-                match maybe_buffer {
-                    Ok(vbo) => NativeVertexBuffer::Prelit(vbo),
-                    Err(_) => return None,
-                }
-            },
+        let is_lit = rwgeo.flags.contains(rw::LIGHT) && rwdata.normals.is_some();
 
-            // Not sure what we're dealing with:
-            _ => return None,
+        let vertex_buffer = {
+            use self::NativeVertexBuffer::*;
+            let maybe_buffer = match rwdata.vertices(is_lit) {
+                Some(Vertices::Prelit(verts)) => VertexBuffer::new(facade, &verts).map(Prelit),
+                Some(Vertices::Lit(verts)) => VertexBuffer::new(facade, &verts).map(Lit),
+                Some(Vertices::Skinned(verts)) => VertexBuffer::new(facade, &verts).map(Skinned),
+                None => return None,
+            };
+            match maybe_buffer {
+                Ok(vbo) => vbo,
+                Err(_) => return None,
+            }
         };
 
         // Builds the index buffer and meshes, a mesh basically consists of a range of indices in
         // the index buffer to be used to render a slice of the geometry.
+        let modulate = rwgeo.flags.contains(rw::MODULATE_MATERIAL_COLOR);
+        let verts = rwgeo.targets.get(0).and_then(|target| target.verts.as_ref());
         let (indices, meshes) = {
             let mut current_index = 0;
             let mut indices = Vec::with_capacity(rwgeo.meshlist.total_indices as usize);
@@ -178,33 +369,313 @@ impl NativeGeometry {
                 current_index += rwmesh.indices.len();
                 indices.extend(rwmesh.indices.iter().cloned());
 
-                let texture = match rwmesh.material.texture {
-                    Some(ref tex) => dicts.find(&tex.dict, &tex.name),
-                    None => None,
-                };
+                let center = verts.map(|verts| {
+                    center_of(rwmesh.indices.iter().filter_map(|&i| verts.get(i as usize)))
+                }).unwrap_or([0.0, 0.0, 0.0]);
 
                 meshes.push(NativeMesh {
                     range: Range { start: start, end: current_index },
-                    texture: texture,
+                    material: NativeMaterial::from_rw(&rwmesh.material, modulate, dicts),
+                    center: center,
                 });
             }
 
             (indices, meshes)
         };
 
-        // TODO other formats other than TriStrip, check RwGeometry flags
+        let primitive = if rwgeo.meshlist.is_tri_strip {
+            PrimitiveType::TriangleStrip
+        } else {
+            PrimitiveType::TrianglesList
+        };
+
         let index_buffer = {
-            let maybe = IndexBuffer::new(facade, PrimitiveType::TriangleStrip, &indices);
+            let maybe = IndexBuffer::new(facade, primitive, &indices);
             match maybe {
                 Ok(ibo) => ibo,
                 Err(_) => return None,
             }
         };
 
+        // Skins with more bones than the shader has room for are rendered in the bind pose.
+        let skin = match vertex_buffer {
+            NativeVertexBuffer::Skinned(_) => rwgeo.plugin::<rw::SkinPlg>().map(|skin| NativeSkin {
+                inverse_matrices: skin.inverse_matrices.iter().map(|&m| Matrix4::from(m)).collect(),
+            }),
+            _ => None,
+        };
+
         Some(NativeGeometry {
             vbo: vertex_buffer,
             ibo: index_buffer,
             meshes: meshes,
+            skin: skin,
+            is_lit: is_lit,
+            center: verts.map(|verts| center_of(verts.iter())).unwrap_or([0.0, 0.0, 0.0]),
         })
     }
 }
+
+/// Vertex attributes of a `rw::Geometry` the vertex buffer is built out of.
+struct RwData<'a> {
+    verts: Option<&'a Vec<rw::Vec3>>,
+    normals: Option<&'a Vec<rw::Vec3>>,
+    colors: Option<&'a Vec<rw::Rgba>>,
+    uv0: Option<&'a Vec<rw::Uv>>,
+    skin: Option<&'a rw::SkinPlg>,
+}
+
+/// Vertices ready to be uploaded into a `NativeVertexBuffer`.
+#[derive(Debug)]
+enum Vertices {
+    Prelit(Vec<VertexPrelit>),
+    Lit(Vec<VertexLit>),
+    Skinned(Vec<VertexSkinned>),
+}
+
+/// Gets whether `data`, if present, has one element for each of the `len` vertices.
+fn fits<T>(data: Option<&Vec<T>>, len: usize) -> bool {
+    data.map(|data| data.len() == len).unwrap_or(true)
+}
+
+/// Gets the texture coordinate of the vertex `i`, untextured geometries get a zero one.
+fn uv_at(uv0: Option<&Vec<rw::Uv>>, i: usize) -> Vector2<f32> {
+    uv0.map(|uv0| uv0[i].into()).unwrap_or(Vector2::new(0.0f32, 0.0))
+}
+
+impl<'a> RwData<'a> {
+    /// Builds the vertices specific for this type of model by pattern matching its attributes.
+    fn vertices(&self, is_lit: bool) -> Option<Vertices> {
+
+        // Lit vertices add their pre-light color to the scene light, unlit ones are modulated by it.
+        let default_color = if is_lit {
+            Vector4::new(0.0f32, 0.0, 0.0, 1.0)
+        } else {
+            Vector4::new(1.0f32, 1.0, 1.0, 1.0)
+        };
+
+        match *self {
+            // In case it's a skinned geometry the shader has room for the bones of...
+            RwData { verts: Some(verts), normals, colors, uv0, skin: Some(skin) }
+            if skin.num_bones as usize <= MAX_BONES => {
+
+                if verts.len() != skin.bone_indices.len() || !fits(uv0, verts.len())
+                || !fits(colors, verts.len()) || !fits(normals, verts.len()) {
+                    return None;
+                }
+
+                Some(Vertices::Skinned(verts.iter().enumerate().map(|(i, vert)| {
+                    let mut indices = skin.bone_indices[i];
+                    let mut weights = skin.bone_weights[i];
+                    // Bones out of the skin would index past the palette, so they're ignored.
+                    for k in (0..4) {
+                        if indices[k] >= skin.num_bones {
+                            indices[k] = 0;
+                            weights[k] = 0.0;
+                        }
+                    }
+                    VertexSkinned {
+                        pos: (*vert).into(),
+                        normal: normals.map(|normals| normals[i].into())
+                                       .unwrap_or(Vector3::new(0.0f32, 0.0, 1.0)),
+                        color: colors.map(|colors| colors[i].into()).unwrap_or(default_color),
+                        uv0: uv_at(uv0, i),
+                        bone_indices: Vector4::new(indices[0] as f32, indices[1] as f32,
+                                                   indices[2] as f32, indices[3] as f32),
+                        bone_weights: Vector4::new(weights[0], weights[1],
+                                                   weights[2], weights[3]),
+                    }
+                }).collect()))
+            },
+
+            // In case it's lit by the scene lights...
+            RwData { verts: Some(verts), normals: Some(normals), colors, uv0, skin: _ } if is_lit => {
+
+                if verts.len() != normals.len() || !fits(uv0, verts.len())
+                || !fits(colors, verts.len()) {
+                    return None;
+                }
+
+                let pairs = izip!(verts.iter(), normals.iter()).enumerate();
+                Some(Vertices::Lit(pairs.map(|(i, (vert, normal))| {
+                    VertexLit {
+                        pos: (*vert).into(),
+                        normal: (*normal).into(),
+                        color: colors.map(|colors| colors[i].into()).unwrap_or(default_color),
+                        uv0: uv_at(uv0, i),
+                    }
+                }).collect()))
+            },
+
+            // In case it's a prelit geometry...
+            RwData { verts: Some(verts), normals: _, colors: Some(colors), uv0, skin: _ } => {
+
+                if verts.len() != colors.len() || !fits(uv0, verts.len()) {
+                    return None;
+                }
+
+                let pairs = izip!(verts.iter(), colors.iter()).enumerate();
+                Some(Vertices::Prelit(pairs.map(|(i, (vert, rgba))| {
+                    VertexPrelit {
+                        pos: (*vert).into(),
+                        color: (*rgba).into(), // auto converts between 0-255 to 0-1 range
+                        uv0: uv_at(uv0, i),
+                    }
+                }).collect()))
+            },
+
+            // In case it's neither prelit nor lit, render it in full bright.
+            RwData { verts: Some(verts), normals: _, colors: None, uv0, skin: _ } => {
+
+                if !fits(uv0, verts.len()) {
+                    return None;
+                }
+
+                Some(Vertices::Prelit(verts.iter().enumerate().map(|(i, vert)| {
+                    VertexPrelit {
+                        pos: (*vert).into(),
+                        color: Vector4::new(1.0f32, 1.0, 1.0, 1.0),
+                        uv0: uv_at(uv0, i),
+                    }
+                }).collect()))
+            },
+
+            // Not sure what we're dealing with:
+            _ => None,
+        }
+    }
+}
+
+/// Computes the average of the specified points.
+fn center_of<'a, I: Iterator<Item=&'a rw::Vec3>>(points: I) -> [f32; 3] {
+    let (mut sum, mut count) = ([0.0f32; 3], 0);
+    for p in points {
+        sum[0] += p.0;
+        sum[1] += p.1;
+        sum[2] += p.2;
+        count += 1;
+    }
+    if count > 0 {
+        [sum[0] / count as f32, sum[1] / count as f32, sum[2] / count as f32]
+    } else {
+        sum
+    }
+}
+
+/// Gets the depth of a point in view space, where farther points have lower values.
+pub fn view_depth(model_view: &Matrix4<f32>, p: [f32; 3]) -> f32 {
+    let m = model_view;
+    m.x.z * p[0] + m.y.z * p[1] + m.z.z * p[2] + m.w.z
+}
+
+/// Sorts `items` from back to front, given the view depth of each item.
+pub fn sort_back_to_front<T, F: Fn(&T) -> f32>(items: &mut Vec<T>, depth: F) {
+    items.sort_by(|a, b| depth(a).partial_cmp(&depth(b)).unwrap_or(cmp::Ordering::Equal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RwData, Vertices, MAX_BONES};
+    use rw::{Vec3, Rgba, SkinPlg};
+    use cgmath::{Vector2, Vector4};
+
+    fn triangle() -> Vec<Vec3> {
+        vec![Vec3(0.0, 0.0, 0.0), Vec3(1.0, 0.0, 0.0), Vec3(0.0, 1.0, 0.0)]
+    }
+
+    #[test]
+    fn untextured_fullbright() {
+        let verts = triangle();
+        let data = RwData {
+            verts: Some(&verts), normals: None, colors: None, uv0: None, skin: None,
+        };
+        match data.vertices(false) {
+            Some(Vertices::Prelit(vertices)) => {
+                assert_eq!(vertices.len(), 3);
+                assert!(vertices.iter().all(|v| v.uv0 == Vector2::new(0.0, 0.0)));
+                assert!(vertices.iter().all(|v| v.color == Vector4::new(1.0, 1.0, 1.0, 1.0)));
+            },
+            other => panic!("unexpected vertices {:?}", other),
+        }
+    }
+
+    #[test]
+    fn untextured_prelit() {
+        let verts = triangle();
+        let colors = vec![Rgba(255, 0, 0, 255); 3];
+        let data = RwData {
+            verts: Some(&verts), normals: None, colors: Some(&colors), uv0: None, skin: None,
+        };
+        match data.vertices(false) {
+            Some(Vertices::Prelit(vertices)) => {
+                assert!(vertices.iter().all(|v| v.uv0 == Vector2::new(0.0, 0.0)));
+                assert!(vertices.iter().all(|v| v.color == Vector4::new(1.0, 0.0, 0.0, 1.0)));
+            },
+            other => panic!("unexpected vertices {:?}", other),
+        }
+    }
+
+    #[test]
+    fn untextured_lit() {
+        let verts = triangle();
+        let normals = vec![Vec3(0.0, 0.0, 1.0); 3];
+        let data = RwData {
+            verts: Some(&verts), normals: Some(&normals), colors: None, uv0: None, skin: None,
+        };
+        match data.vertices(true) {
+            Some(Vertices::Lit(vertices)) => {
+                assert!(vertices.iter().all(|v| v.uv0 == Vector2::new(0.0, 0.0)));
+                assert!(vertices.iter().all(|v| v.color == Vector4::new(0.0, 0.0, 0.0, 1.0)));
+            },
+            other => panic!("unexpected vertices {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatched_colors() {
+        let verts = triangle();
+        let colors = vec![Rgba(255, 255, 255, 255); 2];
+        let data = RwData {
+            verts: Some(&verts), normals: None, colors: Some(&colors), uv0: None, skin: None,
+        };
+        assert!(data.vertices(false).is_none());
+    }
+
+    fn skin(num_bones: u8) -> SkinPlg {
+        SkinPlg {
+            num_bones: num_bones,
+            max_weights: 1,
+            used_bones: Vec::new(),
+            bone_indices: vec![[0, 0, 0, 0]; 3],
+            bone_weights: vec![[1.0, 0.0, 0.0, 0.0]; 3],
+            inverse_matrices: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn skinned() {
+        let verts = triangle();
+        let skin = skin(MAX_BONES as u8);
+        let data = RwData {
+            verts: Some(&verts), normals: None, colors: None, uv0: None, skin: Some(&skin),
+        };
+        match data.vertices(false) {
+            Some(Vertices::Skinned(vertices)) => assert_eq!(vertices.len(), 3),
+            other => panic!("unexpected vertices {:?}", other),
+        }
+    }
+
+    #[test]
+    fn too_many_bones() {
+        // The shader has no room for the bones, so the geometry is drawn unskinned.
+        let verts = triangle();
+        let skin = skin(MAX_BONES as u8 + 1);
+        let data = RwData {
+            verts: Some(&verts), normals: None, colors: None, uv0: None, skin: Some(&skin),
+        };
+        match data.vertices(false) {
+            Some(Vertices::Prelit(vertices)) => assert_eq!(vertices.len(), 3),
+            other => panic!("unexpected vertices {:?}", other),
+        }
+    }
+}
@@ -2,8 +2,13 @@ pub mod renderer;
 pub mod geometry;
 pub mod texture;
 pub mod model;
+pub mod anim;
+pub mod world;
+mod dxt;
 
-pub use self::renderer::Renderer;
-pub use self::geometry::NativeGeometry;
-pub use self::texture::{NativeDictionary, NativeDictionaryList, NativeTexture};
-pub use self::model::{Clump};
+pub use self::renderer::{Renderer, LightRig, TranslucentDraw};
+pub use self::geometry::{NativeGeometry, NativeSkin, BonePalette};
+pub use self::texture::{NativeDictionary, NativeDictionaryList, NativeTexture, TextureUniform, AlphaMode};
+pub use self::model::{Clump, ClumpLight};
+pub use self::anim::{UvAnimPlayer, AnimPlayer, AnimTarget};
+pub use self::world::World;
@@ -1,12 +1,17 @@
-use super::{NativeGeometry, NativeDictionaryList};
-use cgmath::Matrix4;
+use super::{NativeGeometry, NativeDictionaryList, AnimPlayer, AnimTarget, BonePalette};
+use super::geometry::MAX_BONES;
+use cgmath::{Matrix, Matrix4};
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
 use std::cell::RefCell;
+use std::ascii::AsciiExt;
 use rw;
+use ifp;
 use glium::backend::Facade;
+use glium::uniforms::UniformBuffer;
 use glium;
 use glium::Surface;
-use super::Renderer;
+use super::{Renderer, LightRig};
 
 // TODO make checks to confirm that our hierarchy is getting the same as the one in the rw::Clump
 
@@ -22,7 +27,12 @@ pub struct Node {
     pub childs: Vec<NodeObject>,
 
     pub name: String,
+    /// Hierarchical animation node id of this node.
+    pub bone_id: Option<i32>,
+    /// Local transformation, as stored in the frame or updated by animations.
     pub matrix: Matrix4<f32>,
+    /// Local transformation as stored in the frame.
+    pub base_matrix: Matrix4<f32>,
     pub world_matrix: Matrix4<f32>,
 }
 
@@ -30,28 +40,250 @@ pub struct Node {
 pub struct Atomic {
     pub parent: Option<Weak<RefCell<Node>>>,
     pub geometry: Rc<NativeGeometry>,
+    /// Bones of the skinned geometry, if any.
+    pub skin: Option<AtomicSkin>,
     // TODO atomic flags
     // TODO program?
 }
 
+/// Bones deforming the skinned geometry of an atomic.
+#[derive(Debug)]
+pub struct AtomicSkin {
+    /// Node of each bone of the skin, if found in the hierarchy.
+    pub bones: Vec<Option<Weak<RefCell<Node>>>>,
+    palette: Rc<UniformBuffer<BonePalette>>,
+}
+
 #[derive(Debug)]
 pub struct Clump {
     root: Rc<RefCell<Node>>,
+    /// Node ids of the animation hierarchy, if any.
+    hierarchy: Option<Vec<i32>>,
+    player: Option<AnimPlayer>,
+    /// Lights of the clump, if none the lights of the renderer are used.
+    lights: Vec<ClumpLight>,
+    /// Problems found while building the clump, such as atomics left out of it.
+    diagnostics: Vec<String>,
+}
+
+/// Light of a clump, with the direction it travels to in the space of the clump.
+#[derive(Debug, Copy, Clone)]
+pub struct ClumpLight {
+    pub kind: rw::LightKind,
+    pub color: [f32; 3],
+    pub direction: [f32; 3],
 }
 
 impl Clump {
     pub fn from_rw<F: Facade>(facade: &F, clump: &rw::Clump, dicts: &NativeDictionaryList) -> Option<Clump> {
         let root = clump.atomic_root().unwrap(); // TODO
-        match Node::from_rw(facade, &root, None, dicts) {
-            Some(node) => Some(Clump { root: node }),
-            None => None,
+        let mut diagnostics = Vec::new();
+        let node = match Node::from_rw(facade, clump, root, None, dicts, &mut diagnostics) {
+            Some(node) => node,
+            None => return None,
+        };
+
+        // Only the atomics attached to the hierarchy under the root can be placed in the scene.
+        for (i, atomic) in clump.atomics().iter().enumerate() {
+            match atomic.frame() {
+                None => diagnostics.push(format!("atomic {} has no frame and was left out", i)),
+                Some(id) if id != root && !clump.frames().ancestors(id).any(|ancestor| ancestor == root) => {
+                    diagnostics.push(format!("atomic {} is not under the root frame and was left out", i))
+                },
+                Some(_) => {},
+            }
+        }
+
+        // Directional lights travel towards the at vector of their frame.
+        let lights = clump.lights().iter().map(|light| {
+            let at = light.frame().map(|id| clump.frames().world_matrix(id).at)
+                                  .unwrap_or(rw::Vec3(0.0, 0.0, 1.0));
+            ClumpLight {
+                kind: light.kind,
+                color: light.color,
+                direction: [at.0, at.1, at.2],
+            }
+        }).collect();
+
+        let clump = Clump {
+            root: node,
+            hierarchy: Clump::find_hierarchy(clump.frames(), root),
+            player: None,
+            lights: lights,
+            diagnostics: diagnostics,
+        };
+        clump.bind_skins();
+        Some(clump)
+    }
+
+    /// Binds the bones of the skinned atomics to the nodes of the animation hierarchy.
+    ///
+    /// Bones refer to the nodes in the order of the animation hierarchy, if any, otherwise in
+    /// the order of the frames.
+    fn bind_skins(&self) {
+        let nodes = self.nodes();
+        let bone_nodes = match self.hierarchy {
+            Some(ref ids) => ids.iter().map(|&id| {
+                nodes.iter().find(|rcnode| rcnode.borrow().bone_id == Some(id)).map(|rc| rc.downgrade())
+            }).collect::<Vec<_>>(),
+            None => nodes.iter().map(|rc| Some(rc.downgrade())).collect(),
+        };
+
+        for rcnode in nodes.iter() {
+            for child in rcnode.borrow().childs.iter() {
+                if let NodeObject::Atomic(ref rcatomic) = *child {
+                    if let Some(ref mut skin) = rcatomic.borrow_mut().skin {
+                        let num_bones = skin.bones.len();
+                        skin.bones = (0..num_bones).map(|i| {
+                            bone_nodes.get(i).and_then(|node| node.clone())
+                        }).collect();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the node ids of the animation hierarchy in the frames under `id`.
+    fn find_hierarchy(frames: &rw::FrameList, id: rw::FrameId) -> Option<Vec<i32>> {
+        match frames[id].plugin::<rw::HAnimPlg>().and_then(|hanim| hanim.hierarchy.as_ref()) {
+            Some(hierarchy) => return Some(hierarchy.nodes.iter().map(|node| node.id).collect()),
+            None => {},
+        }
+        frames.childs(id).filter_map(|child| Clump::find_hierarchy(frames, child)).next()
+    }
+
+    /// Gets all the nodes in this clump in depth-first order.
+    pub fn nodes(&self) -> Vec<Rc<RefCell<Node>>> {
+        let mut nodes = Vec::new();
+        Node::collect(&self.root, &mut nodes);
+        nodes
+    }
+
+    /// Sets the hierarchical animation to be played on this clump.
+    ///
+    /// Animation nodes refer to the nodes in the order of the animation hierarchy, if any,
+    /// otherwise in the order of the frames. When `None`, the nodes get back to the
+    /// transformations stored in their frames.
+    pub fn set_animation(&mut self, anim: Option<Arc<rw::Animation>>) {
+        let nodes = self.nodes();
+        let targets = match self.hierarchy {
+            Some(ref ids) => ids.iter().map(|&id| {
+                nodes.iter().find(|rcnode| rcnode.borrow().bone_id == Some(id))
+            }).collect::<Vec<_>>(),
+            None => nodes.iter().map(Some).collect(),
+        };
+
+        let targets = targets.into_iter().map(|node| node.map(|rcnode| AnimTarget {
+            node: rcnode.clone(),
+            rotation_only: false,
+        })).collect();
+
+        self.play(anim.map(|anim| AnimPlayer::new(anim, targets)));
+    }
+
+    /// Sets the IFP animation to be played on this clump.
+    ///
+    /// Animation objects refer to the nodes by bone id, if any, otherwise by name.
+    pub fn set_ifp_animation(&mut self, anim: Option<&ifp::Animation>) {
+        let anim = match anim {
+            Some(anim) => anim,
+            None => return self.play(None),
+        };
+
+        let nodes = self.nodes();
+        let targets = anim.objects.iter().map(|obj| {
+            nodes.iter().find(|rcnode| {
+                let node = rcnode.borrow();
+                match (obj.bone_id, node.bone_id) {
+                    (Some(id), Some(node_id)) => id == node_id,
+                    _ => obj.name.eq_ignore_ascii_case(&node.name),
+                }
+            }).map(|rcnode| AnimTarget {
+                node: rcnode.clone(),
+                rotation_only: !obj.has_translation(),
+            })
+        }).collect();
+
+        self.play(Some(AnimPlayer::new(Arc::new(anim.to_rw()), targets)));
+    }
+
+    fn play(&mut self, player: Option<AnimPlayer>) {
+        for rcnode in self.nodes().iter() {
+            let mut node = rcnode.borrow_mut();
+            node.matrix = node.base_matrix;
+        }
+        self.player = player;
+        if let Some(ref player) = self.player {
+            player.apply();
+        }
+        Node::update_world(&self.root, None);
+    }
+
+    /// Advances the animations of this clump, uploading the new pose of its skins.
+    ///
+    /// Called once a frame, before rendering any pass.
+    pub fn update(&mut self, delta_time: f32) {
+        if let Some(ref mut player) = self.player {
+            player.update(delta_time);
+            player.apply();
+            Node::update_world(&self.root, None);
+        }
+
+        self.root.borrow().update(delta_time, &mut Vec::new())
+    }
+
+    /// Gets the lights of this clump.
+    pub fn lights(&self) -> &[ClumpLight] {
+        &self.lights
+    }
+
+    /// Gets the problems found while building this clump out of a `rw::Clump`.
+    pub fn diagnostics(&self) -> &[String] {
+        &self.diagnostics
+    }
+
+    /// Builds the light rig made of the lights of this clump, in the view space of `model_view`.
+    ///
+    /// Only the ambient lights and the first directional light are taken into account. Returns
+    /// `None` if the clump has no lights.
+    pub fn light_rig(&self, model_view: &Matrix4<f32>) -> Option<LightRig> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let mut rig = LightRig::dark();
+        let mut has_directional = false;
+        for light in self.lights.iter() {
+            match light.kind {
+                rw::LightKind::Ambient => {
+                    for c in (0..3) {
+                        rig.ambient[c] += light.color[c];
+                    }
+                },
+                rw::LightKind::Directional if !has_directional => {
+                    rig.direction = transform_direction(model_view, light.direction);
+                    rig.color = light.color;
+                    has_directional = true;
+                },
+                _ => {},
+            }
         }
+        Some(rig)
     }
 
+    /// Renders this clump in the current pass of the `renderer`, shaded by its own lights if it
+    /// has any.
     pub fn render<S: Surface>(&self, renderer: &mut Renderer<S>, program: &glium::Program,
                                   proj: &Matrix4<f32>, model_view: &Matrix4<f32>) {
 
-        self.root.borrow().render(renderer, program, proj, model_view)
+        let previous = renderer.lights;
+        if let Some(rig) = self.light_rig(model_view) {
+            renderer.lights = rig;
+        }
+
+        self.root.borrow().render(renderer, program, proj, model_view);
+
+        renderer.lights = previous;
     }
 
 }
@@ -72,32 +304,102 @@ impl Node {
         }
     }
 
+    /// Advances the UV animations of the geometries under this node and uploads their skins.
+    ///
+    /// Geometries shared by several atomics are advanced only once, being tracked in `updated`.
+    pub fn update(&self, delta_time: f32, updated: &mut Vec<*const NativeGeometry>) {
+        for child in self.childs.iter() {
+            match *child {
+                NodeObject::Atomic(ref rcatomic) => {
+                    let atomic = rcatomic.borrow();
+                    let geometry = &*atomic.geometry as *const NativeGeometry;
+                    if !updated.contains(&geometry) {
+                        updated.push(geometry);
+                        atomic.geometry.update(delta_time);
+                    }
+                    atomic.update_skin();
+                },
+                NodeObject::Node(ref rcnode) => rcnode.borrow().update(delta_time, updated),
+            }
+        }
+    }
+
     pub fn matrix(myself: &Rc<RefCell<Node>>) -> Matrix4<f32> {
         myself.borrow().matrix
     }
 
-    pub fn from_rw<F: Facade>(facade: &F, frame: &rw::Frame,
-                   parent: Option<Rc<RefCell<Node>>>, dicts: &NativeDictionaryList) -> Option<Rc<RefCell<Node>>> {
+    pub fn world_matrix(myself: &Rc<RefCell<Node>>) -> Matrix4<f32> {
+        myself.borrow().world_matrix
+    }
+
+    /// Recomputes the world matrices of this node and its descendants.
+    pub fn update_world(myself: &Rc<RefCell<Node>>, parent_world: Option<Matrix4<f32>>) {
+        let world = {
+            let mut node = myself.borrow_mut();
+            let matrix = node.matrix;
+            node.world_matrix = match parent_world {
+                Some(parent_world) => parent_world * matrix,
+                None => matrix,
+            };
+            node.world_matrix
+        };
+
+        for child in myself.borrow().childs.iter() {
+            if let NodeObject::Node(ref rcnode) = *child {
+                Node::update_world(rcnode, Some(world));
+            }
+        }
+    }
+
+    /// Collects this node and its descendants in depth-first order.
+    pub fn collect(myself: &Rc<RefCell<Node>>, nodes: &mut Vec<Rc<RefCell<Node>>>) {
+        nodes.push(myself.clone());
+        for child in myself.borrow().childs.iter() {
+            if let NodeObject::Node(ref rcnode) = *child {
+                Node::collect(rcnode, nodes);
+            }
+        }
+    }
+
+    /// Builds the node of the frame `id` and its descendants, along with their atomics.
+    ///
+    /// Atomics that can't be built are left out and reported in `diagnostics`.
+    pub fn from_rw<F: Facade>(facade: &F, clump: &rw::Clump, id: rw::FrameId,
+                   parent: Option<Rc<RefCell<Node>>>, dicts: &NativeDictionaryList,
+                   diagnostics: &mut Vec<String>) -> Option<Rc<RefCell<Node>>> {
 
+        let frame = &clump.frames()[id];
         let matrix = Matrix4::<f32>::from(frame.matrix());
 
-        let mut node = Rc::new(RefCell::new(Node {
-            name: frame.name(),
+        let node = Rc::new(RefCell::new(Node {
+            name: frame.name().to_owned(),
+            bone_id: frame.plugin::<rw::HAnimPlg>().map(|hanim| hanim.id),
             matrix: matrix,
-            world_matrix: parent.clone().map(|rc| Node::matrix(&rc) * matrix).unwrap_or(matrix),
+            base_matrix: matrix,
+            world_matrix: parent.clone().map(|rc| Node::world_matrix(&rc) * matrix).unwrap_or(matrix),
 
             parent: parent.clone().map(|rc| rc.downgrade()),
-            childs: Vec::with_capacity(frame.num_childs()),
+            childs: Vec::new(),
         }));
 
+        for child in clump.frames().childs(id) {
+            match Node::from_rw(facade, clump, child, Some(node.clone()), dicts, diagnostics) {
+                Some(rcnode) => node.borrow_mut().childs.push(NodeObject::Node(rcnode)),
+                None => return None,
+            }
+        }
 
-        {
-            let childs = frame.childs();
-            for frameobj in childs.iter() {
-                match NodeObject::from_rw(facade, frameobj, Some(node.clone()), dicts) {
-                    Some(nodeobj) => node.borrow_mut().childs.push(nodeobj),
-                    None => return None,
-                }
+        let atomics = clump.atomics().iter().enumerate();
+        for (i, rcatomic) in atomics.filter(|&(_, atomic)| atomic.frame() == Some(id)) {
+            match Atomic::from_rw(facade, &*rcatomic, Some(node.clone()), dicts) {
+                Some(atomic) => {
+                    if atomic.skin.is_none() && rcatomic.geometry.plugin::<rw::SkinPlg>().is_some() {
+                        diagnostics.push(format!("skin of atomic {} has more than {} bones, \
+                                                  drawn unskinned", i, MAX_BONES));
+                    }
+                    node.borrow_mut().childs.push(NodeObject::Atomic(Rc::new(RefCell::new(atomic))))
+                },
+                None => diagnostics.push(format!("atomic {} could not be built and was left out", i)),
             }
         }
 
@@ -105,27 +407,6 @@ impl Node {
     }
 }
 
-impl NodeObject {
-    pub fn from_rw<F: Facade>(facade: &F, frameobj: &rw::FrameObjectValue,
-                   parent: Option<Rc<RefCell<Node>>>, dicts: &NativeDictionaryList) -> Option<NodeObject> {
-        match *frameobj {
-            rw::FrameObjectValue::Frame(ref rcframe) => {
-                Node::from_rw(facade, &*rcframe, parent, dicts).map(|rcnode| {
-                    NodeObject::Node(rcnode)
-                })
-            },
-            rw::FrameObjectValue::Atomic(ref rcatomic) => {
-                Atomic::from_rw(facade, &*rcatomic, parent, dicts).map(|atomic| {
-                    NodeObject::Atomic( Rc::new(RefCell::new(atomic)) )
-                })
-            },
-            rw::FrameObjectValue::Clump(_) => {
-                unreachable!()
-            },
-        }
-    }
-}
-
 impl Atomic {
     pub fn render<S: Surface>(&self, renderer: &mut Renderer<S>, program: &glium::Program,
                                   proj: &Matrix4<f32>, model_view: &Matrix4<f32>) {
@@ -142,21 +423,94 @@ impl Atomic {
             None => (),
         }
 
-        let model_view2 = self.parent.clone()
-                                     .and_then(|weak| weak.upgrade())
-                                     .map(|parent| *model_view * parent.borrow().world_matrix)
-                                     .unwrap_or(*model_view);
-        self.geometry.render(renderer, program, proj, &model_view2)
+        let model_view2 = self.model_view(model_view);
+        let palette = self.skin.as_ref().map(|skin| &skin.palette);
+        NativeGeometry::render(&self.geometry, renderer, program, proj, &model_view2, palette)
+    }
+
+    /// Uploads the current pose of the bones of the skinned geometry, if any.
+    pub fn update_skin(&self) {
+        if let Some(ref skin) = self.skin {
+            skin.update_palette(&self.geometry, self.world_matrix().unwrap_or(Matrix4::identity()));
+        }
+    }
+
+    /// Gets the world matrix of the node this atomic is attached to, if any.
+    pub fn world_matrix(&self) -> Option<Matrix4<f32>> {
+        self.parent.clone()
+                   .and_then(|weak| weak.upgrade())
+                   .map(|parent| parent.borrow().world_matrix)
+    }
+
+    /// Gets the model view matrix of this atomic, given the one of its clump.
+    pub fn model_view(&self, model_view: &Matrix4<f32>) -> Matrix4<f32> {
+        self.world_matrix().map(|world| *model_view * world).unwrap_or(*model_view)
     }
 
     pub fn from_rw<F: Facade>(facade: &F, atomic: &rw::Atomic,
                    parent: Option<Rc<RefCell<Node>>>, dicts: &NativeDictionaryList) -> Option<Atomic> {
+        let geometry = match NativeGeometry::from_rw(facade, &atomic.geometry, dicts) {
+            Some(geometry) => Rc::new(geometry),
+            None => return None,
+        };
+
+        // The bones are bound by the clump once the whole hierarchy is built.
+        let skin = match geometry.skin {
+            Some(ref skin) => {
+                let palette = BonePalette {
+                    matrices: [Matrix4::<f32>::identity().into(); MAX_BONES],
+                };
+                match UniformBuffer::new(facade, palette) {
+                    Ok(palette) => Some(AtomicSkin {
+                        bones: skin.inverse_matrices.iter().map(|_| None).collect(),
+                        palette: Rc::new(palette),
+                    }),
+                    Err(_) => return None,
+                }
+            },
+            None => None,
+        };
+
         Some(Atomic {
             parent: parent.map(|rc| rc.downgrade()),
-            geometry: match NativeGeometry::from_rw(facade, &atomic.geometry, dicts) {
-                Some(geometry) => Rc::new(geometry),
-                None => return None,
-            },
+            geometry: geometry,
+            skin: skin,
         })
     }
-}
\ No newline at end of file
+}
+
+impl AtomicSkin {
+    /// Uploads the current pose of the bones to the palette of the skinning shader.
+    ///
+    /// Bones not found in the hierarchy keep the bind pose.
+    fn update_palette(&self, geometry: &NativeGeometry, atomic_world: Matrix4<f32>) {
+        let inverse_matrices = match geometry.skin {
+            Some(ref skin) => &skin.inverse_matrices,
+            None => return,
+        };
+
+        let inverse_world = atomic_world.invert().unwrap_or(Matrix4::identity());
+        let mut palette = BonePalette {
+            matrices: [Matrix4::<f32>::identity().into(); MAX_BONES],
+        };
+
+        for (i, (bone, inverse_matrix)) in self.bones.iter().zip(inverse_matrices.iter())
+                                                             .take(MAX_BONES).enumerate() {
+            if let Some(node) = bone.as_ref().and_then(|weak| weak.upgrade()) {
+                let matrix = inverse_world * node.borrow().world_matrix * (*inverse_matrix);
+                palette.matrices[i] = matrix.into();
+            }
+        }
+
+        self.palette.write(&palette);
+    }
+}
+
+/// Transforms the direction `d` by the rotation of `m`, normalizing the result.
+fn transform_direction(m: &Matrix4<f32>, d: [f32; 3]) -> [f32; 3] {
+    let v = [m.x.x * d[0] + m.y.x * d[1] + m.z.x * d[2],
+             m.x.y * d[0] + m.y.y * d[1] + m.z.y * d[2],
+             m.x.z * d[0] + m.y.z * d[1] + m.z.z * d[2]];
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if len > 0.0 { [v[0] / len, v[1] / len, v[2] / len] } else { v }
+}
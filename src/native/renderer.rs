@@ -1,18 +1,106 @@
-use glium::Surface;
+use glium::{self, Surface};
+use glium::uniforms::UniformBuffer;
 use std::rc::Rc;
-use super::{NativeTexture};
+use std::mem;
+use cgmath::Matrix4;
+use super::{NativeTexture, NativeGeometry, BonePalette, AlphaMode};
+use super::geometry;
 
 pub struct Renderer<T> where T: Surface {
     pub target: T,
     pub tex_blank: Rc<NativeTexture>,
+    /// Program used to render skinned geometries.
+    pub skin_program: Rc<glium::Program>,
+    /// Program used to render lit geometries, see `NativeGeometry::is_lit`.
+    pub lit_program: Rc<glium::Program>,
+    /// Lights shading the lit geometries.
+    pub lights: LightRig,
+    /// Current pass, only meshes of this alpha mode are rendered.
+    pub pass: AlphaMode,
+    /// Translucent meshes queued on the translucent pass, see `render_translucent`.
+    translucent: Vec<TranslucentDraw>,
+}
+
+/// Translucent mesh queued to be rendered after every other mesh, see `Renderer::render_translucent`.
+pub struct TranslucentDraw {
+    pub geometry: Rc<NativeGeometry>,
+    /// Index of the mesh in the geometry.
+    pub mesh: usize,
+    pub model_view: Matrix4<f32>,
+    pub bones: Option<Rc<UniformBuffer<BonePalette>>>,
+    /// Lights shading the mesh, as they were when it got queued.
+    pub lights: LightRig,
+    /// View depth of the center of the mesh.
+    pub depth: f32,
+}
+
+/// Lights used to shade the geometries which are not prelit.
+///
+/// A single directional light plus an ambient light, the direction being in view space and
+/// pointing where the light travels to.
+#[derive(Debug, Copy, Clone)]
+pub struct LightRig {
+    pub ambient: [f32; 3],
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+}
+
+impl LightRig {
+    /// Constructs a rig with no light at all.
+    pub fn dark() -> LightRig {
+        LightRig {
+            ambient: [0.0, 0.0, 0.0],
+            direction: [0.0, 0.0, -1.0],
+            color: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+impl Default for LightRig {
+    /// A white light coming from above the viewer plus a dim ambient light.
+    fn default() -> LightRig {
+        LightRig {
+            ambient: [0.4, 0.4, 0.4],
+            direction: [0.0, -0.6, -0.8],
+            color: [0.8, 0.8, 0.8],
+        }
+    }
 }
 
 impl<T> Renderer<T> where T: Surface {
-    pub fn new(target: T, tex_blank: Rc<NativeTexture>) -> Renderer<T> {
+    pub fn new(target: T, tex_blank: Rc<NativeTexture>,
+               skin_program: Rc<glium::Program>, lit_program: Rc<glium::Program>) -> Renderer<T> {
         Renderer {
             target: target,
             tex_blank: tex_blank,
+            skin_program: skin_program,
+            lit_program: lit_program,
+            lights: LightRig::default(),
+            pass: AlphaMode::Opaque,
+            translucent: Vec::new(),
+        }
+    }
+
+    /// Queues a translucent mesh, to be rendered by `render_translucent`.
+    pub fn queue_translucent(&mut self, draw: TranslucentDraw) {
+        self.translucent.push(draw);
+    }
+
+    /// Renders the queued translucent meshes of all the scene at once, from back to front.
+    ///
+    /// Meshes which aren't lit nor skinned are rendered with `program`.
+    pub fn render_translucent(&mut self, program: &glium::Program, proj: &Matrix4<f32>) {
+        let mut draws = mem::replace(&mut self.translucent, Vec::new());
+        geometry::sort_back_to_front(&mut draws, |draw| draw.depth);
+
+        let previous = self.lights;
+        for draw in draws.iter() {
+            self.lights = draw.lights;
+            let mesh = &draw.geometry.meshes[draw.mesh];
+            draw.geometry.render_mesh(self, program, proj, &draw.model_view,
+                                      draw.bones.as_ref().map(|bones| &**bones), mesh);
         }
+        self.lights = previous;
     }
 
     pub fn into_surface(self) -> T {
@@ -2,8 +2,13 @@ use std::rc::Rc;
 use std::collections::HashMap;
 
 use rw;
-use glium::texture::{ClientFormat, RawImage2d, CompressedSrgbTexture2d};
+use glium::texture::{ClientFormat, RawImage2d, CompressedSrgbTexture2d, SrgbTexture2d};
+use glium::texture::{CompressedSrgbFormat, CompressedMipmapsOption, SrgbFormat, MipmapsOption};
+use glium::uniforms::{AsUniformValue, UniformValue, SamplerBehavior, SamplerWrapFunction};
+use glium::uniforms::{MinifySamplerFilter, MagnifySamplerFilter};
 use glium::backend::Facade;
+use glium::Rect;
+use super::dxt::{self, DxtFormat};
 
 
 pub struct NativeDictionaryList {
@@ -17,12 +22,50 @@ pub struct NativeDictionary {
 
 #[derive(Debug)]
 pub struct NativeTexture {
-    pub tex: CompressedSrgbTexture2d,
+    pub tex: NativeTextureBuffer,
+    /// How the alpha channel of the texture affects rendering.
+    pub alpha: AlphaMode,
+}
+
+/// How the alpha of a texture or material affects rendering.
+///
+/// Scenes are rendered in a pass for each mode, in the order they're declared.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum AlphaMode {
+    /// Fully opaque.
+    Opaque,
+    /// Either fully opaque or fully transparent, rendered with alpha testing.
+    Tested,
+    /// Partially transparent, rendered with alpha blending.
+    Blended,
+}
+
+impl AlphaMode {
+    /// Gets the passes a scene is rendered in, in order.
+    pub fn passes() -> [AlphaMode; 3] {
+        [AlphaMode::Opaque, AlphaMode::Tested, AlphaMode::Blended]
+    }
+
+    /// Classifies a sequence of alpha values.
+    pub fn classify<I: Iterator<Item=u8>>(alphas: I) -> AlphaMode {
+        alphas.fold(AlphaMode::Opaque, |mode, alpha| match alpha {
+            255 => mode,
+            0 => ::std::cmp::max(mode, AlphaMode::Tested),
+            _ => AlphaMode::Blended,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub enum NativeTextureBuffer {
     Compressed2d(CompressedSrgbTexture2d),
+    Uncompressed2d(SrgbTexture2d),
+}
+
+/// Texture to be passed into the uniforms of a program.
+pub struct TextureUniform<'a> {
+    tex: &'a NativeTextureBuffer,
+    behavior: Option<SamplerBehavior>,
 }
 
 impl NativeDictionaryList {
@@ -57,25 +100,50 @@ impl NativeDictionary {
 }
 
 impl NativeTexture {
+    /// Uploads the texture, in the best format the driver supports.
+    ///
+    /// S3TC compressed textures are uploaded as such, or decompressed when the driver lacks
+    /// support for them. Other textures are uploaded as RGBA 8 bits each.
     pub fn from_rw<F: Facade>(facade: &F, rwtex: &rw::Texture) -> NativeTexture {
-        use rw::{TexLevel, TextureData};
-        use glium::texture::{CompressedSrgbTexture2d, CompressedSrgbFormat, CompressedMipmapsOption};
-        use glium::Rect;
-
-        let format = match rwtex.raster.base() {
-            &TexLevel { data: TextureData::Dxt1c(_), .. } => CompressedSrgbFormat::S3tcDxt1NoAlpha,
-            &TexLevel { data: TextureData::Dxt1a(_), .. } => CompressedSrgbFormat::S3tcDxt1Alpha,
-            &TexLevel { data: TextureData::Dxt3(_), .. } => CompressedSrgbFormat::S3tcDxt3Alpha,
-            _ => unimplemented!(),
+        use rw::TextureData;
+
+        let format = match rwtex.raster.base().data {
+            TextureData::Dxt1c(_) => Some(CompressedSrgbFormat::S3tcDxt1NoAlpha),
+            TextureData::Dxt1a(_) => Some(CompressedSrgbFormat::S3tcDxt1Alpha),
+            TextureData::Dxt3(_) => Some(CompressedSrgbFormat::S3tcDxt3Alpha),
+            TextureData::Dxt5(_) => Some(CompressedSrgbFormat::S3tcDxt5Alpha),
+            _ => None,
         };
+        let alpha = alpha_mode(rwtex.raster.base());
 
-        let mips_gen = CompressedMipmapsOption::EmptyMipmapsMax(rwtex.raster.num_mipmaps());
+        let tex = match format.and_then(|format| NativeTexture::upload_compressed(facade, rwtex, format)) {
+            Some(tex) => NativeTextureBuffer::Compressed2d(tex),
+            None => match NativeTexture::upload_rgba(facade, rwtex) {
+                Some(tex) => NativeTextureBuffer::Uncompressed2d(tex),
+                // Textures the driver can't take at all (e.g. too big) are left blank.
+                None => return NativeTexture::new_blank_texture(facade),
+            },
+        };
+
+        NativeTexture {
+            tex: tex,
+            alpha: alpha,
+        }
+    }
 
+    /// Uploads the S3TC compressed levels of the texture, fails if the driver lacks support.
+    fn upload_compressed<F: Facade>(facade: &F, rwtex: &rw::Texture,
+                                    format: CompressedSrgbFormat) -> Option<CompressedSrgbTexture2d> {
+        use rw::{TexLevel, TextureData};
+
+        let mips_gen = CompressedMipmapsOption::EmptyMipmapsMax(rwtex.raster.num_mipmaps());
 
         let tex = {
             let (width, height) = (rwtex.raster.width() as u32, rwtex.raster.height() as u32);
-            CompressedSrgbTexture2d::empty_with_format(facade, format,
-                                                       mips_gen, width, height).unwrap()//<<<<<<<<<
+            match CompressedSrgbTexture2d::empty_with_format(facade, format, mips_gen, width, height) {
+                Ok(tex) => tex,
+                Err(_) => return None,
+            }
         };
 
         for (level, rwmip) in rwtex.raster.mips.iter().enumerate() {
@@ -83,17 +151,68 @@ impl NativeTexture {
             match *rwmip {
                 TexLevel { data: TextureData::Dxt1c(ref data), width, height } |
                 TexLevel { data: TextureData::Dxt1a(ref data), width, height } |
-                TexLevel { data: TextureData::Dxt3(ref data), width, height } => {
-                    //println!("{} = {} {}", data.len(), width, height);
+                TexLevel { data: TextureData::Dxt3(ref data), width, height } |
+                TexLevel { data: TextureData::Dxt5(ref data), width, height } => {
                     let rect = Rect { left: 0, bottom: 0, width: width as u32, height: height as u32 };
-                    tex.mipmap(level).unwrap().write_compressed_data(rect, data, width as u32, height as u32, format);
+                    let mipmap = match tex.mipmap(level) {
+                        Some(mipmap) => mipmap,
+                        None => return None,
+                    };
+                    mipmap.write_compressed_data(rect, data, width as u32, height as u32, format);
                 },
-                _ => unimplemented!(),
+                // Levels are all in the format of the base level.
+                _ => return None,
             }
         }
 
-        NativeTexture {
-            tex: tex,
+        Some(tex)
+    }
+
+    /// Uploads the levels of the texture as RGBA 8 bits each, decoding them on the CPU.
+    ///
+    /// Fails if the driver can't create the texture, or it has more levels than the driver made.
+    fn upload_rgba<F: Facade>(facade: &F, rwtex: &rw::Texture) -> Option<SrgbTexture2d> {
+        let mips_gen = MipmapsOption::EmptyMipmapsMax(rwtex.raster.num_mipmaps());
+
+        let tex = {
+            let (width, height) = (rwtex.raster.width() as u32, rwtex.raster.height() as u32);
+            match SrgbTexture2d::empty_with_format(facade, SrgbFormat::U8U8U8U8, mips_gen, width, height) {
+                Ok(tex) => tex,
+                Err(_) => return None,
+            }
+        };
+
+        for (level, rwmip) in rwtex.raster.mips.iter().enumerate() {
+            let (width, height) = (rwmip.width as u32, rwmip.height as u32);
+            let rect = Rect { left: 0, bottom: 0, width: width, height: height };
+            let mipmap = match tex.mipmap(level as u32) {
+                Some(mipmap) => mipmap,
+                None => return None,
+            };
+            mipmap.write(rect, RawImage2d {
+                width: width,
+                height: height,
+                format: ClientFormat::U8U8U8U8,
+                data: decode_rgba(rwmip).into_iter().collect(),
+            });
+        }
+
+        Some(tex)
+    }
+
+    /// Gets this texture to be passed into the uniforms of a program.
+    pub fn as_uniform(&self) -> TextureUniform {
+        TextureUniform {
+            tex: &self.tex,
+            behavior: None,
+        }
+    }
+
+    /// Gets this texture to be passed into the uniforms of a program, sampled with `behavior`.
+    pub fn sampled(&self, behavior: SamplerBehavior) -> TextureUniform {
+        TextureUniform {
+            tex: &self.tex,
+            behavior: Some(behavior),
         }
     }
 
@@ -109,7 +228,129 @@ impl NativeTexture {
         }).unwrap(); // MUST succeed
 
         NativeTexture {
-            tex: texture,
+            tex: NativeTextureBuffer::Compressed2d(texture),
+            alpha: AlphaMode::Opaque,
         }
     }
 }
+
+impl<'a> AsUniformValue for TextureUniform<'a> {
+    fn as_uniform_value(&self) -> UniformValue {
+        match *self.tex {
+            NativeTextureBuffer::Compressed2d(ref tex) => UniformValue::CompressedSrgbTexture2d(tex, self.behavior),
+            NativeTextureBuffer::Uncompressed2d(ref tex) => UniformValue::SrgbTexture2d(tex, self.behavior),
+        }
+    }
+}
+
+/// Builds the sampler behavior matching the filtering and addressing modes of a texture.
+pub fn sampler_behavior(filter: rw::FilterMode, wrap_x: rw::WrapMode, wrap_y: rw::WrapMode) -> SamplerBehavior {
+    use rw::FilterMode;
+
+    let (minify, magnify) = match filter {
+        FilterMode::None => return SamplerBehavior {
+            wrap_function: (wrap_function(wrap_x), wrap_function(wrap_y), SamplerWrapFunction::Repeat),
+            .. Default::default()
+        },
+        FilterMode::Nearest => (MinifySamplerFilter::Nearest, MagnifySamplerFilter::Nearest),
+        FilterMode::Linear => (MinifySamplerFilter::Linear, MagnifySamplerFilter::Linear),
+        FilterMode::MipNearest => (MinifySamplerFilter::NearestMipmapNearest, MagnifySamplerFilter::Nearest),
+        FilterMode::MipLinear => (MinifySamplerFilter::NearestMipmapLinear, MagnifySamplerFilter::Nearest),
+        FilterMode::LinearMipNearest => (MinifySamplerFilter::LinearMipmapNearest, MagnifySamplerFilter::Linear),
+        FilterMode::LinearMipLinear => (MinifySamplerFilter::LinearMipmapLinear, MagnifySamplerFilter::Linear),
+    };
+
+    SamplerBehavior {
+        wrap_function: (wrap_function(wrap_x), wrap_function(wrap_y), SamplerWrapFunction::Repeat),
+        minify_filter: minify,
+        magnify_filter: magnify,
+        .. Default::default()
+    }
+}
+
+/// Maps an addressing mode into a wrap function.
+///
+/// Border colors are not supported, so the border mode is clamped to the edges instead.
+fn wrap_function(wrap: rw::WrapMode) -> SamplerWrapFunction {
+    use rw::WrapMode;
+    match wrap {
+        WrapMode::None | WrapMode::Repeat => SamplerWrapFunction::Repeat,
+        WrapMode::Mirror => SamplerWrapFunction::Mirror,
+        WrapMode::Clamp | WrapMode::Border => SamplerWrapFunction::Clamp,
+    }
+}
+
+/// Classifies how the alpha of a texture level affects rendering.
+///
+/// Compressed levels with an alpha channel are mostly opaque or cutouts, so they're decoded to
+/// tell them apart from the actually translucent ones.
+fn alpha_mode(level: &rw::TexLevel) -> AlphaMode {
+    use rw::TextureData;
+
+    match level.data {
+        TextureData::Dxt1c(_) | TextureData::Rgb8(_) => AlphaMode::Opaque,
+        TextureData::Dxt1a(_) => AlphaMode::Tested,
+        TextureData::Dxt3(_) | TextureData::Dxt5(_) => {
+            AlphaMode::classify(decode_rgba(level).into_iter().map(|p| p.3))
+        },
+        TextureData::Rgba8(ref data) => {
+            AlphaMode::classify(data.chunks(4).filter(|p| p.len() == 4).map(|p| p[3]))
+        },
+        TextureData::Paletted { ref palette, .. } => {
+            AlphaMode::classify(palette.iter().map(|c| c.3))
+        },
+    }
+}
+
+/// Decodes a level of a texture into RGBA 8 bits each.
+fn decode_rgba(level: &rw::TexLevel) -> Vec<(u8, u8, u8, u8)> {
+    use rw::TextureData;
+
+    let (width, height) = (level.width as usize, level.height as usize);
+    let mut rgba = match level.data {
+        TextureData::Dxt1c(ref data) | TextureData::Dxt1a(ref data) => {
+            dxt::decode(DxtFormat::Dxt1, width, height, data)
+        },
+        TextureData::Dxt3(ref data) => dxt::decode(DxtFormat::Dxt3, width, height, data),
+        TextureData::Dxt5(ref data) => dxt::decode(DxtFormat::Dxt5, width, height, data),
+        TextureData::Rgb8(ref data) => data.iter().map(|&(r, g, b)| (r, g, b, 255)).collect(),
+        TextureData::Rgba8(ref data) => {
+            data.chunks(4).filter(|p| p.len() == 4).map(|p| (p[0], p[1], p[2], p[3])).collect()
+        },
+        TextureData::Paletted { ref palette, ref indices } => indices.iter().map(|&i| {
+            palette.get(i as usize).map(|c| (c.0, c.1, c.2, c.3)).unwrap_or((0, 0, 0, 0))
+        }).collect(),
+    };
+
+    // Short data is padded with transparent black, so the upload doesn't fail.
+    rgba.resize(width * height, (0, 0, 0, 0));
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alpha_mode, AlphaMode};
+    use rw::{TexLevel, TextureData};
+
+    /// Builds a 4x4 level out of a DXT5 block with the specified alpha endpoints and indices.
+    fn dxt5(alpha0: u8, alpha1: u8, indices: [u8; 6]) -> TexLevel {
+        let mut block = vec![alpha0, alpha1];
+        block.extend(indices.iter().cloned());
+        block.extend([0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0].iter().cloned());
+        TexLevel { data: TextureData::Dxt5(block), width: 4, height: 4 }
+    }
+
+    #[test]
+    fn dxt_alpha() {
+        assert_eq!(alpha_mode(&dxt5(255, 255, [0; 6])), AlphaMode::Opaque);
+        // The first endpoint for half of the texels, the second for the rest.
+        assert_eq!(alpha_mode(&dxt5(255, 0, [0, 0, 0, 0x49, 0x92, 0x24])), AlphaMode::Tested);
+        // An interpolated value for all the texels.
+        assert_eq!(alpha_mode(&dxt5(255, 0, [0x92, 0x24, 0x49, 0x92, 0x24, 0x49])), AlphaMode::Blended);
+
+        let mut dxt3 = vec![0xF0; 8];
+        dxt3.extend([0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0].iter().cloned());
+        let level = TexLevel { data: TextureData::Dxt3(dxt3), width: 4, height: 4 };
+        assert_eq!(alpha_mode(&level), AlphaMode::Tested);
+    }
+}
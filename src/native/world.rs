@@ -0,0 +1,51 @@
+use super::{NativeGeometry, NativeDictionaryList};
+use super::Renderer;
+use cgmath::Matrix4;
+use std::rc::Rc;
+use rw;
+use glium::backend::Facade;
+use glium;
+use glium::Surface;
+
+/// Renderable static level geometry.
+///
+/// Each atomic sector of the world becomes a geometry on its own, all placed on the world space.
+#[derive(Debug)]
+pub struct World {
+    sectors: Vec<Rc<NativeGeometry>>,
+}
+
+impl World {
+    pub fn from_rw<F: Facade>(facade: &F, world: &rw::World, dicts: &NativeDictionaryList) -> Option<World> {
+        let mut sectors = Vec::new();
+        for sector in world.atomic_sectors() {
+            // Sectors with no triangles would only give empty buffers.
+            if sector.meshlist.meshes.is_empty() {
+                continue;
+            }
+            // A sector that can't be built is left out instead of the whole world.
+            match NativeGeometry::from_rw(facade, &sector.to_geometry(world), dicts) {
+                Some(geometry) => sectors.push(Rc::new(geometry)),
+                None => continue,
+            }
+        }
+        Some(World {
+            sectors: sectors,
+        })
+    }
+
+    pub fn update(&self, delta_time: f32) {
+        for sector in self.sectors.iter() {
+            sector.update(delta_time);
+        }
+    }
+
+    /// Renders the sectors of this world in the current pass of the `renderer`.
+    pub fn render<S: Surface>(&self, renderer: &mut Renderer<S>, program: &glium::Program,
+                                  proj: &Matrix4<f32>, model_view: &Matrix4<f32>) {
+        let pass = renderer.pass;
+        for sector in self.sectors.iter().filter(|sector| sector.has_pass(pass)) {
+            NativeGeometry::render(sector, renderer, program, proj, model_view, None);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use super::{Section, Struct, Result, Error, ReadExt, Stream, SectionBuf};
+
+use super::{StringExt, Vec3, Matrix};
+use std::sync::Arc;
+
+/// Keyframe animation.
+///
+/// Animations are made of one track of keyframes for each animated node. The keyframes of all
+/// tracks are stored together sorted by the time they're needed during playback, where each keyframe
+/// links to the previous keyframe of the same node.
+///
+/// The first keyframes in the list are the starting keyframes of each node, in node order.
+#[derive(Debug)]
+pub struct Animation {
+    /// Duration of the animation in seconds.
+    pub duration: f32,
+    /// Animation flags, specific to the interpolation scheme.
+    pub flags: u32,
+    /// Keyframes of the animation in its interpolation scheme.
+    pub data: AnimData,
+}
+
+/// Keyframes of an `Animation` in the supported interpolation schemes.
+#[derive(Debug)]
+pub enum AnimData {
+    /// UV animation whose keyframes store the UV transformation matrix.
+    UvLinear(UvAnimation),
+    /// UV animation whose keyframes store rotation, scale, skew and translation parameters.
+    UvParam(UvAnimation),
+    /// Hierarchical animation, either from standard or compressed keyframes.
+    HAnim(HAnimAnimation),
+}
+
+/// Keyframes of a hierarchical animation.
+///
+/// Each node of the animation corresponds to a node of a `HAnimHierarchy`, in the same order.
+/// The keyframes of each node are split into tracks when the animation is constructed.
+#[derive(Debug)]
+pub struct HAnimAnimation {
+    num_nodes: usize,
+    keyframes: Vec<HAnimKeyFrame>,
+    tracks: Vec<Vec<usize>>,
+}
+
+/// Keyframe of a hierarchical animation.
+#[derive(Debug, Copy, Clone)]
+pub struct HAnimKeyFrame {
+    /// Time of the keyframe in seconds.
+    pub time: f32,
+    /// Rotation quaternion as `(x, y, z, w)`.
+    pub rot: [f32; 4],
+    /// Translation.
+    pub trans: Vec3,
+    /// Index of the previous keyframe of the same node.
+    pub prev: Option<usize>,
+}
+
+/// Keyframes of a texture coordinates animation.
+///
+/// The keyframes of each node are split into tracks when the animation is constructed.
+#[derive(Debug)]
+pub struct UvAnimation {
+    /// Name of the animation, materials refer to the animation by this name.
+    pub name: String,
+    /// UV channel (texture coordinate set) animated by each node of the animation.
+    pub node_to_channel: [u32; 8],
+    num_nodes: usize,
+    keyframes: Vec<UvKeyFrame>,
+    tracks: Vec<Vec<usize>>,
+}
+
+/// Keyframe of a texture coordinates animation.
+#[derive(Debug, Copy, Clone)]
+pub struct UvKeyFrame {
+    /// Time of the keyframe in seconds.
+    pub time: f32,
+    /// Transformation of the keyframe, the meaning depends on the interpolation scheme.
+    pub uv: [f32; 6],
+    /// Index of the previous keyframe of the same node.
+    pub prev: Option<usize>,
+}
+
+/// 2D affine transformation for texture coordinates.
+///
+/// Stored as `[right.x, right.y, up.x, up.y, pos.x, pos.y]`.
+pub type UvMatrix = [f32; 6];
+
+/// Dictionary of UV animations.
+///
+/// Materials refer to animations in the current dictionary by name, and as such, the dictionary
+/// is usually stored just before the clump in the same RenderWare Stream.
+#[derive(Debug)]
+pub struct UvAnimDictionary {
+    pub animations: Vec<Arc<Animation>>,
+}
+
+/// Material UV Animation Plugin.
+///
+/// This plugin associates animations from the current `UvAnimDictionary` to a `Material`.
+#[derive(Debug)]
+pub struct MaterialUvAnimPlg {
+    /// Name of the animations used by the material.
+    pub names: Vec<String>,
+    /// Animations found in the dictionary binded while reading the material.
+    pub animations: Vec<Arc<Animation>>,
+}
+
+/// Size of a standard keyframe in memory, used to convert previous keyframe offsets into indices.
+const HANIM_STD_KEYFRAME_SIZE: i32 = 36;
+/// Size of a compressed keyframe in memory, used to convert previous keyframe offsets into indices.
+const HANIM_CMP_KEYFRAME_SIZE: i32 = 24;
+
+impl Section for Animation {
+    fn section_id() -> u32 { 0x001B }
+}
+
+impl Section for UvAnimDictionary {
+    fn section_id() -> u32 { 0x002B }
+}
+
+impl Section for MaterialUvAnimPlg {
+    fn section_id() -> u32 { 0x0135 }
+}
+
+impl Animation {
+    /// Gets the number of animated nodes.
+    pub fn num_nodes(&self) -> usize {
+        match self.data {
+            AnimData::UvLinear(ref uvanim) | AnimData::UvParam(ref uvanim) => uvanim.num_nodes(),
+            AnimData::HAnim(ref hanim) => hanim.num_nodes(),
+        }
+    }
+
+    /// Computes the local transformation of the specified node at the specified time.
+    ///
+    /// Rotations are interpolated spherically and translations linearly. Returns `None` if this
+    /// isn't a hierarchical animation or the node isn't animated.
+    pub fn node_matrix(&self, node: usize, time: f32) -> Option<Matrix> {
+        let hanim = match self.data {
+            AnimData::HAnim(ref hanim) => hanim,
+            _ => return None,
+        };
+
+        let track = match hanim.track(node) {
+            Some(track) if !track.is_empty() => track,
+            _ => return None,
+        };
+
+        let (a, b, t) = find_keyframes(track, time, |i| hanim.keyframes[i].time);
+        let (kfa, kfb) = (&hanim.keyframes[a], &hanim.keyframes[b]);
+
+        let rot = slerp(kfa.rot, kfb.rot, t);
+        let trans = Vec3(kfa.trans.0 + (kfb.trans.0 - kfa.trans.0) * t,
+                         kfa.trans.1 + (kfb.trans.1 - kfa.trans.1) * t,
+                         kfa.trans.2 + (kfb.trans.2 - kfa.trans.2) * t);
+        Some(Matrix::from_rotation_translation(rot, trans))
+    }
+
+    /// Computes the UV matrix of the specified node at the specified time.
+    ///
+    /// Returns `None` if this isn't an UV animation or the node isn't animated.
+    pub fn uv_matrix(&self, node: usize, time: f32) -> Option<UvMatrix> {
+        let (uvanim, is_param) = match self.data {
+            AnimData::UvLinear(ref uvanim) => (uvanim, false),
+            AnimData::UvParam(ref uvanim) => (uvanim, true),
+            _ => return None,
+        };
+
+        let track = match uvanim.track(node) {
+            Some(track) if !track.is_empty() => track,
+            _ => return None,
+        };
+
+        let (a, b, t) = find_keyframes(track, time, |i| uvanim.keyframes[i].time);
+        let (kfa, kfb) = (&uvanim.keyframes[a], &uvanim.keyframes[b]);
+
+        let mut uv = [0.0f32; 6];
+        for i in (0..6) {
+            uv[i] = kfa.uv[i] + (kfb.uv[i] - kfa.uv[i]) * t;
+        }
+
+        if is_param {
+            // Parameters are (rotation, scale u, scale v, skew, translation u, translation v).
+            let (sin, cos) = (uv[0].sin(), uv[0].cos());
+            Some([uv[1] * cos, uv[1] * sin, -uv[2] * sin + uv[3], uv[2] * cos, uv[4], uv[5]])
+        } else {
+            Some(uv)
+        }
+    }
+
+    /// Reads an Animation off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Animation> {
+        let _header = try!(Self::read_header(rws));
+
+        let _version = try!(rws.read_u32_ordered());  // 0x100
+        let type_id = try!(rws.read_u32_ordered());
+        let num_frames = try!(rws.read_u32_ordered());
+        let flags = try!(rws.read_u32_ordered());
+        let duration = try!(rws.read_f32_ordered());
+
+        let data = match type_id {
+            0x1 => AnimData::HAnim(try!(HAnimAnimation::read_std(rws, num_frames))),
+            0x2 => AnimData::HAnim(try!(HAnimAnimation::read_compressed(rws, num_frames))),
+            0x1C0 => AnimData::UvLinear(try!(UvAnimation::read(rws, num_frames))),
+            0x1C1 => AnimData::UvParam(try!(UvAnimation::read(rws, num_frames))),
+            _ => return Err(Error::Other(format!("Unknown animation type id {:#x}", type_id))),
+        };
+
+        Ok(Animation {
+            duration: duration,
+            flags: flags,
+            data: data,
+        })
+    }
+}
+
+impl UvAnimation {
+    /// Constructs an animation of `num_nodes` nodes, splitting `keyframes` into their tracks.
+    ///
+    /// The first `num_nodes` keyframes must be the starting keyframes of each node, in order.
+    pub fn new(name: String, node_to_channel: [u32; 8], num_nodes: usize,
+               keyframes: Vec<UvKeyFrame>) -> UvAnimation {
+        let prevs: Vec<_> = keyframes.iter().map(|kf| kf.prev).collect();
+        UvAnimation {
+            name: name,
+            node_to_channel: node_to_channel,
+            num_nodes: num_nodes,
+            tracks: node_tracks(num_nodes, &prevs),
+            keyframes: keyframes,
+        }
+    }
+
+    /// Gets the number of animated nodes.
+    pub fn num_nodes(&self) -> usize {
+        self.num_nodes
+    }
+
+    /// Gets the keyframes of all the nodes.
+    pub fn keyframes(&self) -> &[UvKeyFrame] {
+        &self.keyframes
+    }
+
+    /// Gets the keyframe indices of the specified node, in time order.
+    pub fn track(&self, node: usize) -> Option<&[usize]> {
+        self.tracks.get(node).map(|track| &track[..])
+    }
+
+    /// Reads the UV animation data (after the animation header) off the RenderWare Stream.
+    fn read<R: ReadExt>(rws: &mut Stream<R>, num_frames: u32) -> Result<UvAnimation> {
+        let _unused = try!(rws.read_u32_ordered());
+        let name = try!(String::read_fixed(rws, 32));
+
+        let mut node_to_channel = [0u32; 8];
+        for channel in node_to_channel.iter_mut() {
+            *channel = try!(rws.read_u32_ordered());
+        }
+
+        let mut keyframes = Vec::with_capacity(try!(rws.check_count(num_frames as u64, 32)));
+        for i in (0..num_frames as usize) {
+            let time = try!(rws.read_f32_ordered());
+            let mut uv = [0.0f32; 6];
+            for x in uv.iter_mut() {
+                *x = try!(rws.read_f32_ordered());
+            }
+            let prev = try!(rws.read_i32_ordered());
+            keyframes.push(UvKeyFrame {
+                time: time,
+                uv: uv,
+                prev: if prev >= 0 && (prev as usize) < i { Some(prev as usize) } else { None },
+            });
+        }
+
+        let num_nodes = count_tracks(&keyframes.iter().map(|kf| kf.prev).collect::<Vec<_>>());
+        Ok(UvAnimation::new(name, node_to_channel, num_nodes, keyframes))
+    }
+}
+
+impl HAnimAnimation {
+    /// Constructs an animation of `num_nodes` nodes, splitting `keyframes` into their tracks.
+    ///
+    /// The first `num_nodes` keyframes must be the starting keyframes of each node, in order.
+    pub fn new(num_nodes: usize, keyframes: Vec<HAnimKeyFrame>) -> HAnimAnimation {
+        let prevs: Vec<_> = keyframes.iter().map(|kf| kf.prev).collect();
+        HAnimAnimation {
+            num_nodes: num_nodes,
+            tracks: node_tracks(num_nodes, &prevs),
+            keyframes: keyframes,
+        }
+    }
+
+    /// Gets the number of animated nodes.
+    pub fn num_nodes(&self) -> usize {
+        self.num_nodes
+    }
+
+    /// Gets the keyframes of all the nodes.
+    pub fn keyframes(&self) -> &[HAnimKeyFrame] {
+        &self.keyframes
+    }
+
+    /// Gets the keyframe indices of the specified node, in time order.
+    pub fn track(&self, node: usize) -> Option<&[usize]> {
+        self.tracks.get(node).map(|track| &track[..])
+    }
+
+    /// Reads the standard keyframes (after the animation header) off the RenderWare Stream.
+    fn read_std<R: ReadExt>(rws: &mut Stream<R>, num_frames: u32) -> Result<HAnimAnimation> {
+        let num_frames = try!(rws.check_count(num_frames as u64, HANIM_STD_KEYFRAME_SIZE as u64));
+        let mut keyframes = Vec::with_capacity(num_frames);
+        for i in (0..num_frames as usize) {
+            let time = try!(rws.read_f32_ordered());
+            let mut rot = [0.0f32; 4];
+            for x in rot.iter_mut() {
+                *x = try!(rws.read_f32_ordered());
+            }
+            let trans = try!(Vec3::read(rws));
+            let prev = try!(rws.read_i32_ordered()) / HANIM_STD_KEYFRAME_SIZE;
+            keyframes.push(HAnimKeyFrame {
+                time: time,
+                rot: rot,
+                trans: trans,
+                prev: if prev >= 0 && (prev as usize) < i { Some(prev as usize) } else { None },
+            });
+        }
+
+        let num_nodes = count_tracks(&keyframes.iter().map(|kf| kf.prev).collect::<Vec<_>>());
+        Ok(HAnimAnimation::new(num_nodes, keyframes))
+    }
+
+    /// Reads the compressed keyframes (after the animation header) off the RenderWare Stream.
+    ///
+    /// Rotations are stored as 16 bit floats, and translations as 16 bit floats to be scaled and
+    /// offset by the values stored after the keyframes.
+    fn read_compressed<R: ReadExt>(rws: &mut Stream<R>, num_frames: u32) -> Result<HAnimAnimation> {
+        let num_frames = try!(rws.check_count(num_frames as u64, HANIM_CMP_KEYFRAME_SIZE as u64));
+        let mut keyframes = Vec::with_capacity(num_frames);
+        for i in (0..num_frames as usize) {
+            let time = try!(rws.read_f32_ordered());
+            let mut rot = [0.0f32; 4];
+            for x in rot.iter_mut() {
+                *x = uncompress_float(try!(rws.read_u16_ordered()));
+            }
+            let trans = Vec3(uncompress_float(try!(rws.read_u16_ordered())),
+                             uncompress_float(try!(rws.read_u16_ordered())),
+                             uncompress_float(try!(rws.read_u16_ordered())));
+            let prev = try!(rws.read_i32_ordered()) / HANIM_CMP_KEYFRAME_SIZE;
+            keyframes.push(HAnimKeyFrame {
+                time: time,
+                rot: rot,
+                trans: trans,
+                prev: if prev >= 0 && (prev as usize) < i { Some(prev as usize) } else { None },
+            });
+        }
+
+        let offset = try!(Vec3::read(rws));
+        let scalar = try!(Vec3::read(rws));
+        for kf in keyframes.iter_mut() {
+            kf.trans = Vec3(offset.0 + kf.trans.0 * scalar.0,
+                            offset.1 + kf.trans.1 * scalar.1,
+                            offset.2 + kf.trans.2 * scalar.2);
+        }
+
+        let num_nodes = count_tracks(&keyframes.iter().map(|kf| kf.prev).collect::<Vec<_>>());
+        Ok(HAnimAnimation::new(num_nodes, keyframes))
+    }
+}
+
+impl UvAnimDictionary {
+    /// Finds the UV animation with the specified name.
+    pub fn find(&self, name: &str) -> Option<Arc<Animation>> {
+        self.animations.iter().find(|anim| {
+            match anim.data {
+                AnimData::UvLinear(ref uvanim) | AnimData::UvParam(ref uvanim) => uvanim.name == name,
+                _ => false,
+            }
+        }).map(|rcanim| rcanim.clone())
+    }
+
+    /// Reads an UV Animation Dictionary off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Arc<UvAnimDictionary>> {
+        let _header = try!(Self::read_header(rws));
+
+        let num_anims = try!(Struct::read_up(rws, |rws| Ok(try!(rws.read_u32_ordered()))));
+
+        let mut animations = Vec::with_capacity(try!(rws.check_count(num_anims as u64, 12)));
+        for _ in (0..num_anims) {
+            animations.push(Arc::new(try!(Animation::read(rws))));
+        }
+
+        Ok(Arc::new(UvAnimDictionary {
+            animations: animations,
+        }))
+    }
+
+    /// Reads an UV Animation Dictionary off the RenderWare Stream if it's the next section.
+    ///
+    /// Returns `None` and leaves the stream untouched when the next section is something else.
+    pub fn read_optional<R: ReadExt>(rws: &mut Stream<R>) -> Result<Option<Arc<UvAnimDictionary>>> {
+        let header = try!(SectionBuf::peek_header(rws));
+        if header.id == Self::section_id() {
+            UvAnimDictionary::read(rws).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl MaterialUvAnimPlg {
+    /// Reads a Material UV Animation Plugin off the RenderWare Stream.
+    ///
+    /// The animations are searched in the UV animation dictionary binded to the `Instance`.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<MaterialUvAnimPlg> {
+        let _header = try!(Self::read_header(rws));
+
+        let names = try!(Struct::read_up(rws, |rws| {
+            let mask = try!(rws.read_u32_ordered());
+            let mut names = Vec::new();
+            for i in (0..8) {
+                if (mask & (1 << i)) != 0 {
+                    names.push(try!(String::read_fixed(rws, 32)));
+                }
+            }
+            Ok(names)
+        }));
+
+        let animations = names.iter().filter_map(|name| rws.rw.read_uv_animation(name)).collect();
+
+        Ok(MaterialUvAnimPlg {
+            names: names,
+            animations: animations,
+        })
+    }
+}
+
+/// Counts the number of nodes in an animation given the previous keyframe of each keyframe.
+///
+/// The keyframes of each node are chained by their previous keyframe, so there are as many
+/// nodes as keyframes no other keyframe links to, the last keyframe of each node.
+fn count_tracks(prevs: &[Option<usize>]) -> usize {
+    let mut linked = vec![false; prevs.len()];
+    for &prev in prevs.iter() {
+        if let Some(prev) = prev {
+            linked[prev] = true;
+        }
+    }
+    linked.iter().filter(|&&linked| !linked).count()
+}
+
+/// Splits the keyframes of an animation into the keyframe indices of each node.
+///
+/// Keyframes whose previous keyframe is unknown (other than the starting ones) are ignored.
+fn node_tracks(num_nodes: usize, prevs: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let mut nodes: Vec<Option<usize>> = Vec::with_capacity(prevs.len());
+    let mut tracks: Vec<Vec<usize>> = (0..num_nodes).map(|_| Vec::new()).collect();
+    for (i, prev) in prevs.iter().enumerate() {
+        let node = if i < num_nodes {
+            Some(i)
+        } else {
+            prev.and_then(|prev| nodes[prev])
+        };
+        if let Some(node) = node {
+            tracks[node].push(i);
+        }
+        nodes.push(node);
+    }
+    tracks
+}
+
+/// Finds the pair of keyframes in a (non-empty) track surrounding the specified time.
+///
+/// Returns the keyframe indices and the interpolation factor between them.
+fn find_keyframes<F: Fn(usize) -> f32>(track: &[usize], time: f32, time_of: F) -> (usize, usize, f32) {
+    for pair in track.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let (ta, tb) = (time_of(a), time_of(b));
+        if time <= tb {
+            let t = if tb > ta { (time - ta) / (tb - ta) } else { 0.0 };
+            return (a, b, t.max(0.0).min(1.0));
+        }
+    }
+    let last = track[track.len() - 1];
+    (last, last, 0.0)
+}
+
+/// Spherical linear interpolation between two `(x, y, z, w)` quaternions.
+fn slerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    let mut cos = a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3];
+
+    // Take the shortest path.
+    let b = if cos < 0.0 {
+        cos = -cos;
+        [-b[0], -b[1], -b[2], -b[3]]
+    } else {
+        b
+    };
+
+    let (wa, wb) = if cos > 0.9995 {
+        // Too close, fallback to linear interpolation to avoid dividing by zero.
+        (1.0 - t, t)
+    } else {
+        let angle = cos.acos();
+        let sin = angle.sin();
+        (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+    };
+
+    let q = [a[0] * wa + b[0] * wb, a[1] * wa + b[1] * wb, a[2] * wa + b[2] * wb, a[3] * wa + b[3] * wb];
+    let len = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+    if len > 0.0 {
+        [q[0] / len, q[1] / len, q[2] / len, q[3] / len]
+    } else {
+        a
+    }
+}
+
+/// Uncompresses a RenderWare 16 bit float (1 sign, 4 exponent and 11 mantissa bits).
+fn uncompress_float(x: u16) -> f32 {
+    use std::mem;
+    let x = x as u32;
+    let bits = if (x & 0x7FFF) != 0 {
+        ((x & 0x8000) << 16) | (((x & 0x7800) << 12) + 0x38000000) | ((x & 0x07FF) << 12)
+    } else {
+        (x & 0x8000) << 16
+    };
+    unsafe { mem::transmute::<u32, f32>(bits) }
+}
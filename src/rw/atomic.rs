@@ -1,10 +1,9 @@
-use byteorder::{ReadBytesExt, LittleEndian};
 use super::{Section, Struct, Result, Error, ReadExt, Stream};
 
-use super::{FrameList, Frame, FrameObjectValue, FrameObject};
-use super::{GeometryList, Geometry, Extension};
-use std::cell::RefCell;
-use std::rc::{Rc, Weak};
+use super::{FrameList, FrameId};
+use super::{GeometryList, Geometry, Extension, PluginList, PluginParent};
+use std::sync::Arc;
+use std::any::Any;
 
 bitflags! {
     /// Specifies the options available for controlling the behavior of atomics.
@@ -23,11 +22,15 @@ bitflags! {
 /// are usually grouped together by adding them to a Clump for convenience, although this is not mandatory.
 ///
 /// Atomics also contain a reference to it's associated `Geometry`, which holds the actual model data.
+///
+/// The frame of an atomic is a handle into the `FrameList` of the clump holding the atomic.
 #[derive(Debug)]
 pub struct Atomic {
-    parent: RefCell<Option<Weak<Frame>>>,
-    pub geometry: Rc<Geometry>,
+    frame: Option<FrameId>,
+    pub geometry: Arc<Geometry>,
     pub flags: AtomicFlags,
+    /// Extension plugins attached to this atomic.
+    pub plugins: PluginList,
 }
 
 
@@ -35,41 +38,40 @@ impl Section for Atomic {
     fn section_id() -> u32 { 0x0014 }
 }
 
-impl FrameObject for Rc<Atomic> {
-    fn to_frame_object(&self) -> FrameObjectValue {
-        FrameObjectValue::Atomic(self.clone())
-    }
-
-    fn frame_refcell(&self) -> &RefCell<Option<Weak<Frame>>> {
-        &self.parent
-    }
-}
-
 impl Atomic {
     /// Constructs an atomic containing the specified geometry.
-    pub fn new(flags: AtomicFlags, geometry: Rc<Geometry>) -> Atomic {
+    pub fn new(flags: AtomicFlags, geometry: Arc<Geometry>) -> Atomic {
         Atomic {
-            parent: RefCell::new(None),
+            frame: None,
             geometry: geometry,
             flags: flags,
+            plugins: PluginList::new(),
         }
     }
 
     /// Constructs an atomic attached to `frame` containing the specified geometry.
-    pub fn with_frame(frame: Option<Rc<Frame>>,
-                      flags: AtomicFlags, geometry: Rc<Geometry>)
-                      -> Rc<Atomic> {
-        let atomic = Rc::new(Atomic::new(flags, geometry));
-        atomic.set_frame(frame);
-        atomic
+    pub fn with_frame(frame: Option<FrameId>, flags: AtomicFlags, geometry: Arc<Geometry>) -> Atomic {
+        Atomic {
+            frame: frame,
+            .. Atomic::new(flags, geometry)
+        }
     }
 
     /// Gets the frame attached to this Atomic.
-    pub fn frame(&self) -> Option<Rc<Frame>> {
-        match *self.parent.borrow() {
-            Some(ref weak) => weak.upgrade(),
-            None => None,
-        }
+    pub fn frame(&self) -> Option<FrameId> {
+        self.frame
+    }
+
+    /// Sets the frame attached to this Atomic.
+    ///
+    /// If the `frame` parameter is `None` the atomic gets detached.
+    pub fn set_frame(&mut self, frame: Option<FrameId>) {
+        self.frame = frame;
+    }
+
+    /// Gets the plugin of type `T` attached to this atomic, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
     }
 
     /// Reads the `Atomic` off the RenderWare Stream.
@@ -77,29 +79,31 @@ impl Atomic {
     /// The previosly read geometry and frames from the same clump object in the stream must
     /// be passed to this read procedure.
     pub fn read<R: ReadExt>(rws: &mut Stream<R>, framelist: &FrameList, geolist: &GeometryList)
-                                                                           -> Result<Rc<Atomic>> {
+                                                                           -> Result<Arc<Atomic>> {
         let _header = try!(Self::read_header(rws));
 
         let (frame_index, geo_index, flags, _) = try!(Struct::read_up(rws, |rws| {
-            Ok((try!(rws.read_u32::<LittleEndian>()),
-                try!(rws.read_u32::<LittleEndian>()),
-                try!(rws.read_u32::<LittleEndian>()),
-                try!(rws.read_u32::<LittleEndian>())))  // unused
+            Ok((try!(rws.read_u32_ordered()),
+                try!(rws.read_u32_ordered()),
+                try!(rws.read_u32_ordered()),
+                try!(rws.read_u32_ordered())))  // unused
         }));
 
         // A geometry is available on the Atomic stream when the Clump geometry list is empty.
         let geometry = match geolist {
-            ref geolist if geolist.0.is_empty() => Rc::new(try!(Geometry::read(rws))),
-            ref geolist => try!(geolist.get(geo_index as usize)
-                                       .ok_or(Error::Other("Invalid 'Atomic' Geometry id".into()))),
+            ref geolist if geolist.0.is_empty() => Arc::new(try!(Geometry::read(rws))),
+            ref geolist => try!(geolist.get(geo_index as usize).ok_or_else(|| {
+                rws.annotate(Error::InvalidIndex { kind: "Geometry", index: geo_index as usize })
+            })),
         };
 
         // Extensions.
-        try!(Extension::skip_section(rws));
+        let plugins = try!(Extension::read(rws, PluginParent::Atomic));
 
-        Ok(Atomic::with_frame(
-             framelist.get(frame_index as usize),
-             AtomicFlags::from_bits_truncate(flags),
-             geometry))
+        Ok(Arc::new(Atomic {
+            plugins: plugins,
+            .. Atomic::with_frame(framelist.id(frame_index as usize),
+                                  AtomicFlags::from_bits_truncate(flags), geometry)
+        }))
     }
 }
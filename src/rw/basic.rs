@@ -1,6 +1,7 @@
 // TODO rename module to fundamentals or something alike
-use byteorder::{ReadBytesExt, LittleEndian};
+use byteorder::ReadBytesExt;
 use super::{Result, ReadExt, Stream};
+use std::ops::Mul;
 
 //#[cfg(feature="cgmath")]
 use cgmath;
@@ -86,8 +87,8 @@ impl Uv {
     /// Reads a `[f32; 2]` as a UV value off a RenderWare Stream.
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Uv> {
         Ok(Uv(
-            try!(rws.read_f32::<LittleEndian>()),
-            try!(rws.read_f32::<LittleEndian>()),
+            try!(rws.read_f32_ordered()),
+            try!(rws.read_f32_ordered()),
         ))
     }
 }
@@ -96,9 +97,9 @@ impl Vec3 {
     /// Reads a `[f32; 3]` as a 3D vector value off a RenderWare Stream.
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Vec3> {
         Ok(Vec3(
-            try!(rws.read_f32::<LittleEndian>()),
-            try!(rws.read_f32::<LittleEndian>()),
-            try!(rws.read_f32::<LittleEndian>()),
+            try!(rws.read_f32_ordered()),
+            try!(rws.read_f32_ordered()),
+            try!(rws.read_f32_ordered()),
         ))
     }
 }
@@ -108,7 +109,17 @@ impl Sphere {
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Sphere> {
         Ok(Sphere {
             center: try!(Vec3::read(rws)),
-            radius: try!(rws.read_f32::<LittleEndian>()),
+            radius: try!(rws.read_f32_ordered()),
+        })
+    }
+}
+
+impl BBox {
+    /// Reads a `[f32; 6]` as the supremum and infimum vertices off a RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<BBox> {
+        Ok(BBox {
+            sup: try!(Vec3::read(rws)),
+            inf: try!(Vec3::read(rws)),
         })
     }
 }
@@ -125,6 +136,52 @@ impl Matrix {
     }
 }
 
+impl Matrix {
+    /// Constructs the identity matrix.
+    pub fn identity() -> Matrix {
+        Matrix {
+            right: Vec3(1.0, 0.0, 0.0),
+            top: Vec3(0.0, 1.0, 0.0),
+            at: Vec3(0.0, 0.0, 1.0),
+            pos: Vec3(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Constructs a matrix from a rotation quaternion given as `(x, y, z, w)` and a translation.
+    pub fn from_rotation_translation(q: [f32; 4], pos: Vec3) -> Matrix {
+        let (x, y, z, w) = (q[0], q[1], q[2], q[3]);
+        Matrix {
+            right: Vec3(1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + w * z), 2.0 * (x * z - w * y)),
+            top: Vec3(2.0 * (x * y - w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + w * x)),
+            at: Vec3(2.0 * (x * z + w * y), 2.0 * (y * z - w * x), 1.0 - 2.0 * (x * x + y * y)),
+            pos: pos,
+        }
+    }
+}
+
+impl Mul<Matrix> for Matrix {
+    type Output = Matrix;
+
+    /// Composes two transformations, `rhs` being applied first.
+    ///
+    /// A child frame matrix composed into its parent world matrix (`parent * child`) gives the
+    /// world matrix of the child.
+    fn mul(self, rhs: Matrix) -> Matrix {
+        let rotate = |v: Vec3| Vec3(
+            v.0 * self.right.0 + v.1 * self.top.0 + v.2 * self.at.0,
+            v.0 * self.right.1 + v.1 * self.top.1 + v.2 * self.at.1,
+            v.0 * self.right.2 + v.1 * self.top.2 + v.2 * self.at.2,
+        );
+        let pos = rotate(rhs.pos);
+        Matrix {
+            right: rotate(rhs.right),
+            top: rotate(rhs.top),
+            at: rotate(rhs.at),
+            pos: Vec3(pos.0 + self.pos.0, pos.1 + self.pos.1, pos.2 + self.pos.2),
+        }
+    }
+}
+
 //#[cfg(feature="cgmath")]
 impl From<Rgba> for cgmath::Vector4<f32> {
     /// This additionally converts the RGBA range from 0-255 to 0-1.
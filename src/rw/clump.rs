@@ -1,66 +1,58 @@
-use byteorder::{ReadBytesExt, LittleEndian};
-use super::{Section, Struct, Result, ReadExt, Stream};
+use super::{Section, Struct, Result, Error, ReadExt, Stream};
 
-use super::{GeometryList, Atomic, Light, Extension};
-use super::{FrameList, Frame, FrameObjectValue, FrameObject};
-use std::cell::RefCell;
-use std::rc::{Rc, Weak};
+use super::{GeometryList, Atomic, Light, Extension, PluginList, PluginParent};
+use super::{FrameList, FrameId};
+use std::sync::Arc;
+use std::any::Any;
+use std::ascii::AsciiExt;
+use std::mem;
+use std::usize;
 
 /// Container for Atomics.
 ///
-/// Clumps are containers for `Atomic`s and can also be linked to their own `Frame`.
+/// Clumps are containers for `Atomic`s and own the `FrameList` hierarchy the atomics are
+/// attached to.
 ///
 /// Clumps are intended to group related Atomics together. The Atomics are stored by refcount,
-/// so an Atomic can be stored in more than one Clump if necessary.
+/// so an Atomic can be stored in more than one Clump if necessary, as long as their frame
+/// handles are meaningful in each clump's frame list.
 #[derive(Debug)]
 pub struct Clump {
-    parent: RefCell<Option<Weak<Frame>>>,
-    atomics: Vec<Rc<Atomic>>,
+    atomics: Vec<Arc<Atomic>>,
+    lights: Vec<Arc<Light>>,
     frames: FrameList,
+    plugins: PluginList,
 }
 
 impl Section for Clump {
     fn section_id() -> u32 { 0x0010 }
 }
 
-impl FrameObject for Rc<Clump> {
-    fn to_frame_object(&self) -> FrameObjectValue {
-        FrameObjectValue::Clump(self.clone())
-    }
-
-    fn frame_refcell(&self) -> &RefCell<Option<Weak<Frame>>> {
-        &self.parent
-    }
-}
-
 impl Clump {
     /// Constructs a clump containing the related atomics.
-    pub fn new(atomics: Vec<Rc<Atomic>>, frames: FrameList) -> Clump {
+    pub fn new(atomics: Vec<Arc<Atomic>>, frames: FrameList) -> Clump {
         Clump {
-            parent: RefCell::new(None),
             atomics: atomics,
+            lights: Vec::new(),
             frames: frames,
+            plugins: PluginList::new(),
         }
     }
 
-    /// Constructs a clump attached to `frame` and containing the related atomics.
-    pub fn with_frame(frame: Option<Rc<Frame>>,
-                      atomics: Vec<Rc<Atomic>>, frames: FrameList)
-                      -> Rc<Clump> {
-
-        let clump = Rc::new(Clump::new(atomics, frames));
-        clump.set_frame(frame);
-        clump
-    }
-
     /// Reads a Clump off a RenderWare Stream.
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Clump> {
         let _header = try!(Self::read_header(rws));
 
         let (natomics, nlights, ncameras) = try!(Struct::read_up(rws, |rws| {
-            Ok((try!(rws.read_u32::<LittleEndian>()),
-                try!(rws.read_u32::<LittleEndian>()),
-                try!(rws.read_u32::<LittleEndian>())))
+            let natomics = try!(rws.read_u32_ordered());
+            // Older streams (such as GTA III ones) have no lights and cameras count.
+            if rws.remaining().unwrap_or(0) >= 8 {
+                Ok((natomics,
+                    try!(rws.read_u32_ordered()),
+                    try!(rws.read_u32_ordered())))
+            } else {
+                Ok((natomics, 0, 0))
+            }
         }));
 
         let framelist = try!(FrameList::read(rws));
@@ -68,11 +60,13 @@ impl Clump {
 
         let atomics = try!((0..natomics).map(|_| Atomic::read(rws, &framelist, &geolist)).collect());
 
-        // TODO Light
+        let mut lights = Vec::with_capacity(try!(rws.check_count(nlights as u64, 12)));
         for _ in (0..nlights) {
-            
-            try!(Struct::skip_section(rws));
-            try!(Light::skip_section(rws));
+            let frame_index = try!(Struct::read_up(rws, |rws| rws.read_u32_ordered()));
+            if framelist.id(frame_index as usize).is_none() {
+                try!(rws.tolerate(Error::InvalidIndex { kind: "Frame", index: frame_index as usize }));
+            }
+            lights.push(try!(Light::read(rws, &framelist, frame_index)));
         }
 
         // TODO Camera
@@ -80,37 +74,157 @@ impl Clump {
             // ----
         }
 
-        try!(Extension::skip_section(rws));
+        let plugins = try!(Extension::read(rws, PluginParent::Clump));
 
-        Ok(Clump::new(atomics, framelist))
+        Ok(Clump {
+            lights: lights,
+            plugins: plugins,
+            .. Clump::new(atomics, framelist)
+        })
     }
 
     /// Converts this `Clump` into a single `Atomic`.
     ///
-    /// This extracts the last atomic of the clump's atomic list.
+    /// This extracts the atomic attached to the frame closest to the root of the hierarchy,
+    /// the first one in the atomic list if there are several. Atomics with no frame are only
+    /// selected if no other atomic is available.
     ///
-    /// It's recommend to detach the returned atomic from any frame using `Atomic::detach` on the
-    /// returned atomic but not required.
-    pub fn into_atomic(mut self) -> Option<Rc<Atomic>> {
-        let x = self.atomics.pop();
-        match x {
-            Some(ref atomic) => {
-                match atomic.frame() {
-                    Some(frame) => {
-                        println!("->{:?}", frame.name());
-                    },
-                    _ => {},
+    /// The frame handle of the returned atomic refers to this clump's frame list, which is
+    /// dropped, so the atomic should be detached from it using `Atomic::set_frame`.
+    pub fn into_atomic(mut self) -> Option<Arc<Atomic>> {
+        let index = {
+            let frames = &self.frames;
+            let depth = |atomic: &Arc<Atomic>| match atomic.frame() {
+                Some(id) => frames.ancestors(id).count(),
+                None => usize::MAX,
+            };
+            let mut best: Option<(usize, usize)> = None;
+            for (i, atomic) in self.atomics.iter().enumerate() {
+                let d = depth(atomic);
+                if best.map(|(_, best_d)| d < best_d).unwrap_or(true) {
+                    best = Some((i, d));
                 }
             }
-            _ => {},
+            best.map(|(i, _)| i)
+        };
+        index.map(|i| self.atomics.swap_remove(i))
+    }
+
+    /// Gets the atomics in this clump.
+    pub fn atomics(&self) -> &[Arc<Atomic>] {
+        &self.atomics
+    }
+
+    /// Gets the atomics attached to the specified frame.
+    pub fn atomics_of(&self, frame: FrameId) -> Vec<Arc<Atomic>> {
+        self.atomics.iter().filter(|atomic| atomic.frame() == Some(frame)).cloned().collect()
+    }
+
+    /// Finds the first atomic attached to a frame named `name`.
+    ///
+    /// Names are compared case-insensitively, as in `FrameList::find`.
+    pub fn find_atomic(&self, name: &str) -> Option<Arc<Atomic>> {
+        self.atomics.iter().find(|atomic| {
+            atomic.frame().and_then(|id| self.frames.get(id))
+                          .map(|frame| frame.name().eq_ignore_ascii_case(name))
+                          .unwrap_or(false)
+        }).cloned()
+    }
+
+    /// Adds an atomic into this clump.
+    ///
+    /// Fails if the atomic frame, if any, is not a handle into this clump's frame list.
+    pub fn add_atomic(&mut self, atomic: Arc<Atomic>) -> Result<()> {
+        try!(self.check_frame(atomic.frame()));
+        self.atomics.push(atomic);
+        Ok(())
+    }
+
+    /// Removes the atomic at the specified index off this clump, keeping the order of the others.
+    ///
+    /// Returns `None` if the index is out of range.
+    pub fn remove_atomic(&mut self, index: usize) -> Option<Arc<Atomic>> {
+        if index < self.atomics.len() { Some(self.atomics.remove(index)) } else { None }
+    }
+
+    /// Removes the atomics attached to the specified frame off this clump.
+    ///
+    /// Returns the removed atomics.
+    pub fn remove_atomics_of(&mut self, frame: FrameId) -> Vec<Arc<Atomic>> {
+        let atomics = mem::replace(&mut self.atomics, Vec::new());
+        let (removed, kept) = atomics.into_iter().partition(|atomic| atomic.frame() == Some(frame));
+        self.atomics = kept;
+        removed
+    }
+
+    /// Adds a copy of the atomic at the specified index attached to `frame`.
+    ///
+    /// The copy shares the geometry of the original atomic and gets a copy of its unrecognized
+    /// plugins, while the recognized ones are dropped, see `PluginList::clone_unknown`. Returns
+    /// the index of the copy, or `None` if the index is out of range or `frame` is not a handle
+    /// into this clump's frame list.
+    pub fn clone_atomic(&mut self, index: usize, frame: Option<FrameId>) -> Option<usize> {
+        if self.check_frame(frame).is_err() {
+            return None;
+        }
+        let copy = match self.atomics.get(index) {
+            Some(atomic) => {
+                let mut copy = Atomic::with_frame(frame, atomic.flags, atomic.geometry.clone());
+                copy.plugins = atomic.plugins.clone_unknown();
+                copy
+            },
+            None => return None,
+        };
+        self.atomics.push(Arc::new(copy));
+        Some(self.atomics.len() - 1)
+    }
+
+    /// Gets the lights in this clump.
+    pub fn lights(&self) -> &[Arc<Light>] {
+        &self.lights
+    }
+
+    /// Adds a light into this clump.
+    ///
+    /// Fails if the light frame, if any, is not a handle into this clump's frame list.
+    pub fn add_light(&mut self, light: Arc<Light>) -> Result<()> {
+        try!(self.check_frame(light.frame()));
+        self.lights.push(light);
+        Ok(())
+    }
+
+    /// Fails with `Error::InvalidIndex` if `frame` is not a handle into this clump's frame list.
+    fn check_frame(&self, frame: Option<FrameId>) -> Result<()> {
+        match frame {
+            Some(id) if !self.frames.contains(id) => {
+                Err(Error::InvalidIndex { kind: "Frame", index: id.index() })
+            },
+            _ => Ok(()),
         }
+    }
+
+    /// Gets the extension plugins attached to this clump.
+    pub fn plugins(&self) -> &PluginList {
+        &self.plugins
+    }
+
+    /// Gets the plugin of type `T` attached to this clump, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
+    }
+
+    /// Gets the frame hierarchy of this clump.
+    pub fn frames(&self) -> &FrameList {
+        &self.frames
+    }
 
-        //self.atomics.pop()
-        x
+    /// Gets the frame hierarchy of this clump mutably.
+    pub fn frames_mut(&mut self) -> &mut FrameList {
+        &mut self.frames
     }
 
     /// Gets the root frame in the atomic list / frame list.
-    pub fn atomic_root(&self) -> Option<Rc<Frame>> {
+    pub fn atomic_root(&self) -> Option<FrameId> {
         self.frames.root()
     }
 }
@@ -1,78 +1,70 @@
-use byteorder::{ReadBytesExt, LittleEndian};
-use super::{Section, Struct, Result, ReadExt, Stream};
+use super::{Section, Struct, Result, Error, ReadExt, Stream};
 
-use super::{Matrix, Clump, Atomic, Extension};
-use std::cell::{Ref, RefCell};
-use std::rc::{Rc, Weak};
+use super::{Matrix, Extension, PluginList, PluginParent};
+use std::any::Any;
+use std::ascii::AsciiExt;
+use std::collections::VecDeque;
+use std::iter::Map;
+use std::ops::{Index, IndexMut, Range};
 
-/// Holds a list of `Frame`s to be passed around.
+/// Handle to a `Frame` owned by a `FrameList`.
+///
+/// Handles are only meaningful for the list that gave them away.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FrameId(usize);
+
+/// Hierarchy of `Frame`s.
+///
+/// The list owns all of its frames and links them by `FrameId` handles, the index of the frame
+/// in the list. Frames read off a stream keep their stream order, so the frame index stored
+/// in an `Atomic` section is also its `FrameId`.
 #[derive(Debug)]
-pub struct FrameList(pub Vec<Rc<Frame>>);
+pub struct FrameList {
+    frames: Vec<Frame>,
+}
 
 /// Hierarchical (transform and name) wrapper for objects.
 ///
 /// This object provides a frame of reference for other objects, allowing them to be positioned
 /// relative to each other in world space. In addition, a frame object can also be linked to parent
 /// and/or child frames in a hierarchical manner.
+///
+/// The links are stored in the owning `FrameList`, see its methods to navigate the hierarchy.
 #[derive(Debug)]
 pub struct Frame {
-    parent: RefCell<Option<Weak<Frame>>>,
-    childs: RefCell<Vec<FrameObjectValue>>,
+    parent: Option<FrameId>,
+    first_child: Option<FrameId>,
+    last_child: Option<FrameId>,
+    prev_sibling: Option<FrameId>,
+    next_sibling: Option<FrameId>,
     matrix: Matrix,
-    // I wish this wasn't a RefCell but an extension is responssible for setting it up.
-    // May find a solution for this later.
-    name: RefCell<String>,
+    name: String,
+    plugins: PluginList,
 }
 
-/// Holds an object that can be attached to a frame.
-#[derive(Debug, Clone)]
-pub enum FrameObjectValue {
-    Clump(Rc<Clump>),
-    Atomic(Rc<Atomic>),
-    Frame(Rc<Frame>),
+/// Iterator over the childs of a frame, in the order they were attached.
+pub struct Childs<'a> {
+    frames: &'a FrameList,
+    next: Option<FrameId>,
 }
 
-// TODO free Weak node if it's not upgradeable
-
-// Implemented by Rc FrameObjects (Rc<Frame>, Rc<Atomic>, ...).
-pub trait FrameObject {
-    /// Gets a `FrameObjectValue` from this.
-    fn to_frame_object(&self) -> FrameObjectValue;
-
-    /// Gets the parent RefCell holder.
-    #[doc(hidden)]
-    fn frame_refcell(&self) -> &RefCell<Option<Weak<Frame>>>;
-
-    /// Gets the parent frame associated with this object.
-    fn frame(&self) -> Option<Rc<Frame>> {
-        self.frame_refcell().borrow().clone().and_then(|weak| weak.upgrade())
-    }
+/// Iterator over the ancestors of a frame, from its parent up to the root.
+pub struct Ancestors<'a> {
+    frames: &'a FrameList,
+    next: Option<FrameId>,
+}
 
-    /// Sets the parent frame associated with this object.
-    ///
-    /// If the `frame` parameter is `None` this has the same effect as the `detach_frame` method.
-    ///
-    /// Any previosly attached frame gets detached.
-    fn set_frame(&self, frame: Option<Rc<Frame>>) {
-        self.detach_frame();
-        if let Some(frame) = frame {
-            *self.frame_refcell().borrow_mut() = Some(frame.downgrade());
-            Frame::push_child(&frame, self.to_frame_object());
-        }
-    }
+/// Iterator over a frame and its descendants in depth-first (pre-)order.
+pub struct DepthFirst<'a> {
+    frames: &'a FrameList,
+    root: FrameId,
+    next: Option<FrameId>,
+}
 
-    /// Detaches this object off any parent frame.
-    ///
-    /// Returns the previosly attached frame.
-    fn detach_frame(&self) -> Option<Rc<Frame>> {
-        if let Some(parent) = self.frame() {
-            *self.frame_refcell().borrow_mut() = None;
-            Frame::remove_child(&parent, self.to_frame_object());
-            Some(parent)
-        } else {
-            None
-        }
-    }
+/// Iterator over a frame and its descendants in breadth-first order.
+pub struct BreadthFirst<'a> {
+    frames: &'a FrameList,
+    queue: VecDeque<FrameId>,
 }
 
 /// Rockstar North's Node Name Plugin.
@@ -81,6 +73,37 @@ pub trait FrameObject {
 #[derive(Debug)]
 pub struct NodeNamePlg(String);
 
+/// Hierarchical Animation Plugin.
+///
+/// This plugin associates a node id into a `Frame`, and the root frame of an animated hierarchy
+/// additionally stores the list of nodes in the hierarchy.
+#[derive(Debug)]
+pub struct HAnimPlg {
+    /// Node id of the frame.
+    pub id: i32,
+    /// Hierarchy whose root is this frame, if any.
+    pub hierarchy: Option<HAnimHierarchy>,
+}
+
+/// List of nodes in a hierarchical animation.
+///
+/// The nodes of animations (and skinning bones) refer to the nodes in the order stored here.
+#[derive(Debug)]
+pub struct HAnimHierarchy {
+    pub flags: u32,
+    pub nodes: Vec<HAnimNode>,
+}
+
+/// Node in a `HAnimHierarchy`.
+#[derive(Debug, Copy, Clone)]
+pub struct HAnimNode {
+    /// Node id, matches the `HAnimPlg::id` of the frame representing this node.
+    pub id: i32,
+    /// Index of the node in the hierarchy.
+    pub index: i32,
+    pub flags: u32,
+}
+
 impl Section for FrameList {
     fn section_id() -> u32 { 0x000E }
 }
@@ -89,145 +112,450 @@ impl Section for NodeNamePlg {
     fn section_id() -> u32 { 0x0253F2FE }
 }
 
-impl FrameObjectValue {
-    /// Checks if two `FrameObjectValue`s point to exactly same object (same address).
-    pub fn is_same_object(&self, other: &FrameObjectValue) -> bool {
-        use std::ops::Deref;
-        use self::FrameObjectValue::*;
-        match (self, other) {
-            // Frames may be same as another frame...
-            (&Frame(ref rca), &Frame(ref rcb)) => {
-                rca.deref() as *const _ == rcb.deref() as *const _
-            },
-            // Atomics may be same as another atomic...
-            (&Atomic(ref rca), &Atomic(ref rcb)) => {
-                rca.deref() as *const _ == rcb.deref() as *const _
-            },
-            // Clumps may be same as another clump...
-            (&Clump(ref rca), &Clump(ref rcb)) => {
-                rca.deref() as *const _ == rcb.deref() as *const _
-            },
-            // Inequal object types...
-            _ => false,
-        }
+impl Section for HAnimPlg {
+    fn section_id() -> u32 { 0x011E }
+}
+
+impl FrameId {
+    /// Gets the index of the frame in its `FrameList`.
+    pub fn index(&self) -> usize {
+        self.0
     }
 }
 
-impl FrameObject for Rc<Frame> {
-    fn to_frame_object(&self) -> FrameObjectValue {
-        FrameObjectValue::Frame(self.clone())
+impl Frame {
+    /// Gets the name of this frame object.
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    fn frame_refcell(&self) -> &RefCell<Option<Weak<Frame>>> {
-        &self.parent
+    /// Sets the name of this frame object.
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
     }
-}
 
-impl Frame {
+    /// Gets the extension plugins attached to this frame object.
+    pub fn plugins(&self) -> &PluginList {
+        &self.plugins
+    }
 
-    /// Gets the name of this frame object.
-    ///
-    // TODO, better way, allocating the String everytime? meh
-    pub fn name(&self) -> String {
-        self.name.borrow().clone()
+    /// Gets the plugin of type `T` attached to this frame object, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
     }
 
-    /// TODO
+    /// Gets the transformation of this frame relative to its parent.
     pub fn matrix(&self) -> Matrix {
         self.matrix
     }
 
-    /// TODO
-    pub fn childs(&self) -> Ref<Vec<FrameObjectValue>> {
-        self.childs.borrow()
+    /// Sets the transformation of this frame relative to its parent.
+    ///
+    /// The descendants of this frame move along with it.
+    pub fn set_matrix(&mut self, matrix: Matrix) {
+        self.matrix = matrix;
+    }
+
+    /// Gets the parent of this frame, if any.
+    pub fn parent(&self) -> Option<FrameId> {
+        self.parent
     }
 
-    /// TODO
-    pub fn num_childs(&self) -> usize {
-        self.childs().len()
+    /// Gets the first child of this frame, if any.
+    pub fn first_child(&self) -> Option<FrameId> {
+        self.first_child
     }
 
-    /// Adds a child object into the specified `Frame`.
+    /// Gets the sibling attached before this frame in its parent, if any.
+    pub fn prev_sibling(&self) -> Option<FrameId> {
+        self.prev_sibling
+    }
+
+    /// Gets the sibling attached after this frame in its parent, if any.
+    pub fn next_sibling(&self) -> Option<FrameId> {
+        self.next_sibling
+    }
+
+    /// Reads a Frame structure off the RenderWare Stream.
+    ///
+    /// Returns the frame matrix and the index of its parent frame, if any.
+    fn read_struct<R: ReadExt>(rws: &mut Stream<R>) -> Result<(Matrix, Option<usize>)> {
+        let matrix = try!(Matrix::read(rws));
+        let parent_id = try!(rws.read_i32_ordered());
+        let _flags = try!(rws.read_u32_ordered());  // ignored
+        Ok((matrix, if parent_id >= 0 { Some(parent_id as usize) } else { None }))
+    }
+}
+
+impl FrameList {
+    /// Constructs an empty frame list.
+    pub fn new() -> FrameList {
+        FrameList {
+            frames: Vec::new(),
+        }
+    }
+
+    /// Gets the number of frames in the list.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Checks whether the list has no frames.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Gets the handle of the frame at the specified index or `None` if out of range.
+    pub fn id(&self, index: usize) -> Option<FrameId> {
+        if index < self.frames.len() { Some(FrameId(index)) } else { None }
+    }
+
+    /// Iterates over the handles of all the frames in the list order.
+    pub fn ids(&self) -> Map<Range<usize>, fn(usize) -> FrameId> {
+        fn to_id(index: usize) -> FrameId { FrameId(index) }
+        (0..self.frames.len()).map(to_id as fn(usize) -> FrameId)
+    }
+
+    /// Checks whether the specified handle refers to a frame in this list.
+    pub fn contains(&self, id: FrameId) -> bool {
+        id.0 < self.frames.len()
+    }
+
+    /// Gets the frame with the specified handle or `None` if out of range.
+    pub fn get(&self, id: FrameId) -> Option<&Frame> {
+        self.frames.get(id.0)
+    }
+
+    /// Gets the frame with the specified handle mutably or `None` if out of range.
+    pub fn get_mut(&mut self, id: FrameId) -> Option<&mut Frame> {
+        self.frames.get_mut(id.0)
+    }
+
+    /// Gets the root frame in the list.
     ///
-    /// This is a helper to `FrameObject`.
-    fn push_child(myself: &Rc<Frame>, child: FrameObjectValue) {
-        myself.childs.borrow_mut().push(child);
+    /// This is the root of the hierarchy the first frame belongs to.
+    pub fn root(&self) -> Option<FrameId> {
+        self.id(0).map(|id| self.root_of(id))
+    }
+
+    /// Finds the root of the hierarchy `id` belongs to, or if it is the root, returns itself.
+    pub fn root_of(&self, id: FrameId) -> FrameId {
+        self.ancestors(id).last().unwrap_or(id)
+    }
+
+    /// Gets the parent of the specified frame, if any.
+    pub fn parent(&self, id: FrameId) -> Option<FrameId> {
+        self.get(id).and_then(|frame| frame.parent)
     }
 
-    /// Removes the specified child object off the `Frame`.
+    /// Iterates over the childs of the specified frame.
     ///
-    /// This is a helper to `FrameObject`.
-    fn remove_child(myself: &Rc<Frame>, child: FrameObjectValue) {
-        let mut childs = myself.childs.borrow_mut();
-        if let Some(pos) = childs.iter().position(|obj| obj.is_same_object(&child)) {
-            childs.remove(pos);
+    /// Handles out of range have no childs.
+    pub fn childs(&self, id: FrameId) -> Childs {
+        Childs { frames: self, next: self.get(id).and_then(|frame| frame.first_child) }
+    }
+
+    /// Iterates over the siblings of the specified frame, including itself.
+    ///
+    /// Frames without parent have no siblings other than themselves, and handles out of range
+    /// have none at all.
+    pub fn siblings(&self, id: FrameId) -> Childs {
+        match self.get(id).map(|frame| frame.parent) {
+            Some(Some(parent)) => self.childs(parent),
+            Some(None) => Childs { frames: self, next: Some(id) },
+            None => Childs { frames: self, next: None },
         }
     }
 
-    /// Finds the root of the hierarchy, or if this is the root, returns itself.
-    pub fn root(myself: &Rc<Frame>) -> Rc<Frame> {
-        let root = match *myself.parent.borrow() {
-            Some(ref weak) => weak.upgrade().map(|rcframe| Frame::root(&rcframe)),
-            None => None,
-        };
-        root.unwrap_or_else(|| myself.clone())
+    /// Iterates over the ancestors of the specified frame, from its parent up to the root.
+    ///
+    /// Handles out of range have no ancestors.
+    pub fn ancestors(&self, id: FrameId) -> Ancestors {
+        Ancestors { frames: self, next: self.parent(id) }
     }
 
-    /// Reads a Frame object off the RenderWare Stream.
-    pub fn read<R: ReadExt>(rws: &mut Stream<R>, frames: &[Rc<Frame>]) -> Result<Rc<Frame>> {
-        let matrix = try!(Matrix::read(rws));
-        let parent_id = try!(rws.read_i32::<LittleEndian>());
-        let _flags = try!(rws.read_u32::<LittleEndian>());  // ignored
+    /// Iterates over the specified frame and its descendants in depth-first order.
+    ///
+    /// Handles out of range give an empty iteration.
+    pub fn depth_first(&self, id: FrameId) -> DepthFirst {
+        let next = if self.contains(id) { Some(id) } else { None };
+        DepthFirst { frames: self, root: id, next: next }
+    }
 
-        let frame = Rc::new(Frame {
-            parent: RefCell::new(None),
-            childs: RefCell::new(Vec::new()),
+    /// Iterates over the specified frame and its descendants in breadth-first order.
+    ///
+    /// Handles out of range give an empty iteration.
+    pub fn breadth_first(&self, id: FrameId) -> BreadthFirst {
+        let mut queue = VecDeque::new();
+        if self.contains(id) {
+            queue.push_back(id);
+        }
+        BreadthFirst { frames: self, queue: queue }
+    }
+
+    /// Finds the first frame in the list order named `name`.
+    ///
+    /// Names are compared case-insensitively, as the game does.
+    pub fn find(&self, name: &str) -> Option<FrameId> {
+        self.ids().find(|&id| self[id].name.eq_ignore_ascii_case(name))
+    }
+
+    /// Finds the first frame named `name` under the specified frame, including itself.
+    ///
+    /// The frames are searched in depth-first order and names are compared case-insensitively.
+    pub fn find_under(&self, id: FrameId, name: &str) -> Option<FrameId> {
+        self.depth_first(id).find(|&id| self[id].name.eq_ignore_ascii_case(name))
+    }
+
+    /// Gets the path of the specified frame, the names from its root down to it separated by `/`,
+    /// such as `chassis_dummy/wheel_lf_dummy`.
+    ///
+    /// Panics if the frame is not in the list.
+    pub fn path(&self, id: FrameId) -> String {
+        let mut names: Vec<&str> = self.ancestors(id).map(|ancestor| self[ancestor].name()).collect();
+        names.reverse();
+        names.push(self[id].name());
+        names.join("/")
+    }
+
+    /// Finds the frame at `path`, in the format returned by the `path` method.
+    ///
+    /// The path starts at any root frame and its names are compared case-insensitively.
+    pub fn find_path(&self, path: &str) -> Option<FrameId> {
+        let names: Vec<&str> = path.split('/').collect();
+        let roots = self.ids().filter(|&id| self[id].parent.is_none());
+        roots.filter(|&id| self[id].name.eq_ignore_ascii_case(names[0])).filter_map(|root| {
+            names[1..].iter().fold(Some(root), |id, name| id.and_then(|id| {
+                self.childs(id).find(|&child| self[child].name.eq_ignore_ascii_case(name))
+            }))
+        }).next()
+    }
+
+    /// Computes the transformation of the specified frame relative to the world.
+    ///
+    /// This composes the frame matrices up the parent chain. Panics if the frame is not in the list.
+    pub fn world_matrix(&self, id: FrameId) -> Matrix {
+        self.ancestors(id).fold(self[id].matrix, |matrix, ancestor| self[ancestor].matrix * matrix)
+    }
+
+    /// Computes the transformation of the specified frame relative to its ancestor `ancestor`.
+    ///
+    /// Returns `None` if `ancestor` is not an ancestor of the frame, or the frame is not in the list.
+    pub fn relative_matrix(&self, id: FrameId, ancestor: FrameId) -> Option<Matrix> {
+        let mut matrix = match self.get(id) {
+            Some(frame) => frame.matrix,
+            None => return None,
+        };
+        for parent in self.ancestors(id) {
+            if parent == ancestor {
+                return Some(matrix);
+            }
+            matrix = self[parent].matrix * matrix;
+        }
+        None
+    }
+
+    /// Adds a frame attached to `parent` at the end of the list.
+    ///
+    /// Fails, leaving the list untouched, if `parent` is not in the list.
+    pub fn add(&mut self, parent: Option<FrameId>, matrix: Matrix,
+               name: String, plugins: PluginList) -> Result<FrameId> {
+        if let Some(parent) = parent {
+            try!(self.check_id(parent));
+        }
+        let id = FrameId(self.frames.len());
+        self.frames.push(Frame {
+            parent: None,
+            first_child: None,
+            last_child: None,
+            prev_sibling: None,
+            next_sibling: None,
             matrix: matrix,
-            name: RefCell::new(String::new()),
+            name: name,
+            plugins: plugins,
         });
+        // A new frame has no descendants, so it can be attached to any frame in the list.
+        try!(self.set_parent(id, parent));
+        Ok(id)
+    }
 
-        if parent_id >= 0 {
-            let parent = frames.get(parent_id as usize).map(|rc| rc.clone());
-            frame.set_frame(parent);
+    /// Attaches the specified frame as the last child of `parent`.
+    ///
+    /// If the `parent` parameter is `None` this has the same effect as the `detach` method.
+    ///
+    /// Any previosly attached parent gets detached.
+    ///
+    /// Fails, leaving the hierarchy untouched, if either frame is not in the list or `parent` is
+    /// the frame itself or one of its descendants.
+    pub fn set_parent(&mut self, id: FrameId, parent: Option<FrameId>) -> Result<()> {
+        try!(self.check_id(id));
+        if let Some(parent) = parent {
+            try!(self.check_id(parent));
+            if parent == id || self.ancestors(parent).any(|ancestor| ancestor == id) {
+                return Err(Error::Other("frame cannot be attached into its own subtree".to_owned()));
+            }
         }
-
-        Ok(frame)
+        self.detach(id);
+        if let Some(parent) = parent {
+            let prev = self[parent].last_child;
+            match prev {
+                Some(prev) => self[prev].next_sibling = Some(id),
+                None => self[parent].first_child = Some(id),
+            }
+            self[parent].last_child = Some(id);
+            self[id].parent = Some(parent);
+            self[id].prev_sibling = prev;
+        }
+        Ok(())
     }
-}
 
-impl FrameList {
-    /// Gets the frame at the specified index or `None` if out of range.
-    pub fn get(&self, index: usize) -> Option<Rc<Frame>> {
-        self.0.get(index).map(|rcframe| rcframe.clone())
+    /// Detaches the specified frame off its parent, making it the root of its own hierarchy.
+    ///
+    /// Returns the previosly attached parent, `None` if there was none or the frame is not in
+    /// the list.
+    pub fn detach(&mut self, id: FrameId) -> Option<FrameId> {
+        let parent = match self.parent(id) {
+            Some(parent) => parent,
+            None => return None,
+        };
+        let (prev, next) = (self[id].prev_sibling, self[id].next_sibling);
+        match prev {
+            Some(prev) => self[prev].next_sibling = next,
+            None => self[parent].first_child = next,
+        }
+        match next {
+            Some(next) => self[next].prev_sibling = prev,
+            None => self[parent].last_child = prev,
+        }
+        let frame = &mut self[id];
+        frame.parent = None;
+        frame.prev_sibling = None;
+        frame.next_sibling = None;
+        Some(parent)
     }
 
-    /// Gets the root frame in the list.
-    pub fn root(&self) -> Option<Rc<Frame>> {
-        self.get(0).map(|rcframe| Frame::root(&rcframe))
+    /// Fails with `Error::InvalidIndex` if the specified handle is not in the list.
+    fn check_id(&self, id: FrameId) -> Result<()> {
+        if self.contains(id) {
+            Ok(())
+        } else {
+            Err(Error::InvalidIndex { kind: "Frame", index: id.0 })
+        }
     }
 
     /// Reads the Frame List off the RenderWare Stream.
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<FrameList> {
         let _header = try!(Self::read_header(rws));
 
-        let nframes = try!(Struct::read_up(rws, |rws| Ok(try!(rws.read_u32::<LittleEndian>()))));
-
-        // Read frame by frame as usual...
-        let mut frames: Vec<Rc<Frame>> = Vec::with_capacity(nframes as usize);
-        for _ in (0..nframes) {
-            let frame = try!(Frame::read(rws, &frames));
-            frames.push(frame);
-        }
-
-        // We need to assign the Node Name Plugin afterwards...
-        for i in (0..nframes as usize) {
-            match try!(Extension::read_for(rws, |rws| NodeNamePlg::read(rws))) {
-                Some(NodeNamePlg(name)) => *frames[i].name.borrow_mut() = name,
-                None => {},
+        let structs: Vec<(Matrix, Option<usize>)> = try!(Struct::read_up(rws, |rws| {
+            let nframes = try!(rws.read_u32_ordered());
+            try!(rws.check_count(nframes as u64, 56));
+            (0..nframes).map(|_| Frame::read_struct(rws)).collect()
+        }));
+
+        // Frames are built only after their extensions are read, since the name and the other
+        // plugins of a frame are stored there.
+        let mut frames = FrameList { frames: Vec::with_capacity(structs.len()) };
+        for (matrix, parent_id) in structs.into_iter() {
+            let mut plugins = try!(Extension::read_up(rws, PluginParent::Frame, |rws, header| {
+                match header.id {
+                    id if id == NodeNamePlg::section_id() => {
+                        NodeNamePlg::read(rws).map(|plg| Some(Box::new(plg) as Box<Any + Send + Sync>))
+                    },
+                    id if id == HAnimPlg::section_id() => {
+                        HAnimPlg::read(rws).map(|plg| Some(Box::new(plg) as Box<Any + Send + Sync>))
+                    },
+                    _ => Ok(None),
+                }
+            }));
+            let name = match plugins.take::<NodeNamePlg>() {
+                Some(NodeNamePlg(name)) => name,
+                None => String::new(),
             };
+            // Only frames already read can be parents, which also rules out cycles.
+            let parent = parent_id.and_then(|index| frames.id(index));
+            try!(frames.add(parent, matrix, name, plugins));
         }
 
-        Ok(FrameList(frames))
+        Ok(frames)
+    }
+}
+
+/// Panics if the frame is not in the list, see `FrameList::get` for the checked access.
+impl Index<FrameId> for FrameList {
+    type Output = Frame;
+
+    fn index(&self, id: FrameId) -> &Frame {
+        &self.frames[id.0]
+    }
+}
+
+impl IndexMut<FrameId> for FrameList {
+    fn index_mut(&mut self, id: FrameId) -> &mut Frame {
+        &mut self.frames[id.0]
+    }
+}
+
+impl<'a> Iterator for Childs<'a> {
+    type Item = FrameId;
+
+    fn next(&mut self) -> Option<FrameId> {
+        self.next.map(|id| {
+            self.next = self.frames[id].next_sibling;
+            id
+        })
+    }
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = FrameId;
+
+    fn next(&mut self) -> Option<FrameId> {
+        let current = match self.next {
+            Some(id) => id,
+            None => return None,
+        };
+        // Descend into the first child, otherwise climb up (never above the root) to the first
+        // ancestor having a next sibling.
+        let (frames, root) = (self.frames, self.root);
+        self.next = frames[current].first_child.or_else(|| {
+            let mut id = current;
+            loop {
+                if id == root {
+                    return None;
+                }
+                match frames[id].next_sibling {
+                    Some(sibling) => return Some(sibling),
+                    None => id = match frames[id].parent {
+                        Some(parent) => parent,
+                        None => return None,
+                    },
+                }
+            }
+        });
+        Some(current)
+    }
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = FrameId;
+
+    fn next(&mut self) -> Option<FrameId> {
+        self.queue.pop_front().map(|id| {
+            self.queue.extend(self.frames.childs(id));
+            id
+        })
+    }
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = FrameId;
+
+    fn next(&mut self) -> Option<FrameId> {
+        self.next.map(|id| {
+            self.next = self.frames[id].parent;
+            id
+        })
     }
 }
 
@@ -239,3 +567,81 @@ impl NodeNamePlg {
         Ok(NodeNamePlg(String::from_utf8_lossy(&bytes).into_owned()))
     }
 }
+
+impl HAnimPlg {
+    /// Reads a Hierarchical Animation Plugin off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<HAnimPlg> {
+        let _header = try!(Self::read_header(rws));
+
+        let _version = try!(rws.read_u32_ordered());    // 0x100
+        let id = try!(rws.read_i32_ordered());
+        let num_nodes = try!(rws.read_u32_ordered());
+
+        let hierarchy = if num_nodes > 0 {
+            let flags = try!(rws.read_u32_ordered());
+            let _keyframe_size = try!(rws.read_u32_ordered());
+            let mut nodes = Vec::with_capacity(try!(rws.check_count(num_nodes as u64, 12)));
+            for _ in (0..num_nodes) {
+                nodes.push(HAnimNode {
+                    id: try!(rws.read_i32_ordered()),
+                    index: try!(rws.read_i32_ordered()),
+                    flags: try!(rws.read_u32_ordered()),
+                });
+            }
+            Some(HAnimHierarchy {
+                flags: flags,
+                nodes: nodes,
+            })
+        } else {
+            None
+        };
+
+        Ok(HAnimPlg {
+            id: id,
+            hierarchy: hierarchy,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameList;
+    use super::super::{Matrix, PluginList};
+
+    fn add(frames: &mut FrameList, parent: Option<super::FrameId>) -> super::FrameId {
+        frames.add(parent, Matrix::identity(), String::new(), PluginList::new()).unwrap()
+    }
+
+    #[test]
+    fn foreign_handles() {
+        let mut other = FrameList::new();
+        let a = add(&mut other, None);
+        let b = add(&mut other, Some(a));
+
+        // Handles past the end of a list are rejected instead of panicking.
+        let mut frames = FrameList::new();
+        let root = add(&mut frames, None);
+        assert!(!frames.contains(b));
+        assert!(frames.add(Some(b), Matrix::identity(), String::new(), PluginList::new()).is_err());
+        assert_eq!(frames.len(), 1);
+        assert!(frames.set_parent(b, Some(root)).is_err());
+        assert!(frames.set_parent(root, Some(b)).is_err());
+        assert_eq!(frames.detach(b), None);
+        assert_eq!(frames.childs(b).count(), 0);
+        assert_eq!(frames.ancestors(b).count(), 0);
+        assert_eq!(frames.depth_first(b).count(), 0);
+        assert_eq!(frames.breadth_first(b).count(), 0);
+        assert!(frames.relative_matrix(b, root).is_none());
+    }
+
+    #[test]
+    fn cycles() {
+        let mut frames = FrameList::new();
+        let a = add(&mut frames, None);
+        let b = add(&mut frames, Some(a));
+        assert!(frames.set_parent(a, Some(b)).is_err());
+        assert!(frames.set_parent(a, Some(a)).is_err());
+        assert_eq!(frames.parent(b), Some(a));
+        assert_eq!(frames.parent(a), None);
+    }
+}
@@ -1,13 +1,38 @@
-use byteorder::{ReadBytesExt, LittleEndian};
+use byteorder::ReadBytesExt;
 use super::{Section, Struct, Result, Error, ReadExt, Stream};
 
-use super::{Vec3, Uv, Sphere, Rgba};
-use super::{Material, MaterialList, Extension};
-use std::rc::Rc;
+use super::{Vec3, Uv, Sphere, Rgba, Matrix};
+use super::{Material, MaterialList, SurfaceProperties, Extension, PluginList, PluginParent, NativeDataPlg};
+use std::sync::Arc;
+use std::any::Any;
+use std::io::{Seek, SeekFrom};
+use std::u16;
+
+bitflags! {
+    /// Specifies the format of a geometry, that is which vertex data it has and how it should be rendered.
+    flags GeometryFlags: u16 {
+        /// The geometry is made of triangle strips.
+        const TRISTRIP = 0x01,
+        /// The geometry has vertex positions.
+        const POSITIONS = 0x02,
+        /// The geometry has a single set of texture coordinates.
+        const TEXTURED = 0x04,
+        /// The geometry has pre-light colors.
+        const PRELIT = 0x08,
+        /// The geometry has vertex normals.
+        const NORMALS = 0x10,
+        /// The geometry should be lit by the lights of the scene.
+        const LIGHT = 0x20,
+        /// The vertex colors should be modulated by the color of the material.
+        const MODULATE_MATERIAL_COLOR = 0x40,
+        /// The geometry has two or more sets of texture coordinates.
+        const TEXTURED2 = 0x80,
+    }
+}
 
 /// Holds a list of `Geometry`s to be passed around.
 #[derive(Debug)]
-pub struct GeometryList(pub Vec<Rc<Geometry>>);
+pub struct GeometryList(pub Vec<Arc<Geometry>>);
 
 /// Primary container object for dynamic model data.
 ///
@@ -24,6 +49,18 @@ pub struct GeometryList(pub Vec<Rc<Geometry>>);
 pub struct Geometry {
     /// Render as triangle strips.
     pub is_tri_strip: bool,
+    /// Format of the geometry.
+    pub flags: GeometryFlags,
+    /// Flags of native geometries, whose vertex data came from a `NativeDataPlg`.
+    ///
+    /// Zero for platform independent geometries.
+    pub native_flags: u8,
+    /// Number of texture coordinate sets as stored on the stream.
+    ///
+    /// This may differ from the length of `uv_sets` when the native data could not be decoded.
+    pub num_uv_sets: u8,
+    /// Surface coefficients of the whole geometry, only present on 3.4.0.3 and below.
+    pub surf: Option<SurfaceProperties>,
     /// Pre-light colors.
     ///
     /// One element for each vertex.
@@ -46,6 +83,8 @@ pub struct Geometry {
     /// that is the `is_tri_strip` of the `Geometry` must be ignored in favor of the one in the
     /// `MeshHeader`.
     pub meshlist: MeshHeader,
+    /// Extension plugins attached to this geometry.
+    pub plugins: PluginList,
 }
 
 /// Meshes are a caching system designed to speed up rendering.
@@ -59,7 +98,7 @@ pub struct Geometry {
 pub struct Mesh {
     // TODO priv data?
     /// Material associated with this mesh triangles.
-    pub material: Rc<Material>,
+    pub material: Arc<Material>,
     /// Indices of triangles making the mesh.
     pub indices: Vec<u16>,
 }
@@ -75,6 +114,34 @@ pub struct MeshHeader {
     pub meshes: Vec<Mesh>,
 }
 
+/// Skinning information of a geometry (Skin PLG).
+///
+/// Each vertex is influenced by up to four bones, the bones being the nodes of the `HAnimHierarchy`
+/// of the clump in the order stored in the hierarchy.
+#[derive(Debug)]
+pub struct SkinPlg {
+    /// Number of bones in the skin.
+    pub num_bones: u8,
+    /// Maximum number of bones influencing a single vertex.
+    pub max_weights: u8,
+    /// Indices of the bones actually influencing any vertex.
+    ///
+    /// Empty on older streams.
+    pub used_bones: Vec<u8>,
+    /// Bones influencing each vertex.
+    ///
+    /// One element for each vertex.
+    pub bone_indices: Vec<[u8; 4]>,
+    /// Weights of the bones influencing each vertex.
+    ///
+    /// One element for each vertex, the weights of a vertex sum up to one.
+    pub bone_weights: Vec<[f32; 4]>,
+    /// Transforms vertices into the local space of each bone on the bind pose.
+    ///
+    /// One element for each bone.
+    pub inverse_matrices: Vec<Matrix>,
+}
+
 /// Represents a triangle in a geometry.
 ///
 /// This is specified by three indices into the geometry's vertex list together with an index in to
@@ -121,9 +188,26 @@ impl Section for MeshHeader {
     fn section_id() -> u32 { 0x050E }   // Bin Mesh PLG
 }
 
+impl Section for SkinPlg {
+    fn section_id() -> u32 { 0x0116 }
+}
+
+impl MorphTarget {
+    /// Constructs a morph target bounded by `sphere` with the specified vertex data.
+    pub fn new(sphere: Sphere, verts: Option<Vec<Vec3>>, normals: Option<Vec<Vec3>>) -> MorphTarget {
+        MorphTarget {
+            sphere: sphere,
+            unk1: 0,
+            unk2: 0,
+            verts: verts,
+            normals: normals,
+        }
+    }
+}
+
 impl GeometryList {
     /// Gets the geometry at the specified index or `None` if out of range.
-    pub fn get(&self, index: usize) -> Option<Rc<Geometry>> {
+    pub fn get(&self, index: usize) -> Option<Arc<Geometry>> {
         self.0.get(index).map(|rcgeo| rcgeo.clone())
     }
 
@@ -132,12 +216,12 @@ impl GeometryList {
         let _header = try!(Self::read_header(rws));
 
         let numgeo = try!(Struct::read_up(rws, |rws| {
-            Ok(try!(rws.read_u32::<LittleEndian>()))
+            Ok(try!(rws.read_u32_ordered()))
         }));
         
-        let mut geolist = Vec::with_capacity(numgeo as usize);
+        let mut geolist = Vec::with_capacity(try!(rws.check_count(numgeo as u64, 12)));
         for _ in (0..numgeo) {
-            geolist.push( Rc::new(try!(Geometry::read(rws))) );
+            geolist.push( Arc::new(try!(Geometry::read(rws))) );
         }
 
         Ok(GeometryList(geolist))
@@ -145,27 +229,35 @@ impl GeometryList {
 }
 
 impl Geometry {
+    /// Gets the plugin of type `T` attached to this geometry, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
+    }
+
+    /// Checks whether the vertex data of this geometry came from a `NativeDataPlg`.
+    pub fn is_native(&self) -> bool {
+        (self.native_flags & 1) != 0
+    }
+
     /// Reads a Geometry off the RenderWare Stream.
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Geometry> {
         let header = try!(Self::read_header(rws));
 
-        let (flags, colors, uv_sets, faces, targets) = try!(Struct::read_up(rws, |rws| {
+        let (flags, num_uv, natflags, surf, num_verts, mut colors, mut uv_sets, faces, mut targets) = try!(Struct::read_up(rws, |rws| {
 
-            let flags = try!(rws.read_u16::<LittleEndian>());
+            let flags = try!(rws.read_u16_ordered());
             let num_uv = try!(rws.read_u8());
-            let _natflags = try!(rws.read_u8()); // TODO what is this?
-            let num_tris = try!(rws.read_u32::<LittleEndian>());
-            let num_verts = try!(rws.read_u32::<LittleEndian>());
-            let num_morphs = try!(rws.read_u32::<LittleEndian>());
+            let natflags = try!(rws.read_u8());
+            // Native geometries keep their vertex data in the Native Data PLG instead.
+            let is_native = (natflags & 1) != 0;
+            let num_tris = try!(rws.read_u32_ordered());
+            let num_verts = try!(rws.read_u32_ordered());
+            let num_morphs = try!(rws.read_u32_ordered());
 
             // On 3.4.0.3 and below there are some additional information
-            let _amb_difu_spec = {
+            let surf = {
                 if header.version <= 0x1003FFFF {
-                    Some((
-                        try!(rws.read_f32::<LittleEndian>()),
-                        try!(rws.read_f32::<LittleEndian>()),
-                        try!(rws.read_f32::<LittleEndian>()),
-                    ))
+                    Some(try!(SurfaceProperties::read(rws)))
                 } else {
                     None
                 }
@@ -173,8 +265,8 @@ impl Geometry {
 
             // This geometry has pre-light colors?
             let colors = {
-                if (flags & 8) != 0 {
-                    let mut v = Vec::with_capacity(num_verts as usize);
+                if !is_native && (flags & 8) != 0 {
+                    let mut v = Vec::with_capacity(try!(rws.check_count(num_verts as u64, 4)));
                     for _ in (0..num_verts) {
                         v.push(try!(Rgba::read(rws)));
                     }
@@ -186,9 +278,10 @@ impl Geometry {
 
             // Texture coordinates sets.
             let uv_sets = {
-                let mut sets = Vec::with_capacity(num_uv as usize);
-                for _ in (0..num_uv) {
-                    let mut v = Vec::with_capacity(num_verts as usize);
+                let num_sets = if is_native { 0 } else { num_uv };
+                let mut sets = Vec::with_capacity(num_sets as usize);
+                for _ in (0..num_sets) {
+                    let mut v = Vec::with_capacity(try!(rws.check_count(num_verts as u64, 8)));
                     for _ in (0..num_verts) {
                         v.push(try!(Uv::read(rws)));
                     }
@@ -199,13 +292,14 @@ impl Geometry {
 
             // Triangles that make up the model.
             let faces = {
-                let mut v = Vec::with_capacity(num_tris as usize);
+                let num_tris = if is_native { 0 } else { num_tris };
+                let mut v = Vec::with_capacity(try!(rws.check_count(num_tris as u64, 8)));
                 for _ in (0..num_tris) {
                     v.push(Triangle {
-                        y_id: try!(rws.read_u16::<LittleEndian>()),
-                        x_id: try!(rws.read_u16::<LittleEndian>()),
-                        mat_id: try!(rws.read_u16::<LittleEndian>()),
-                        z_id: try!(rws.read_u16::<LittleEndian>()),
+                        y_id: try!(rws.read_u16_ordered()),
+                        x_id: try!(rws.read_u16_ordered()),
+                        mat_id: try!(rws.read_u16_ordered()),
+                        z_id: try!(rws.read_u16_ordered()),
                     });
                 }
                 v
@@ -213,16 +307,16 @@ impl Geometry {
             
             // Morph targets.
             let targets = {
-                let mut v = Vec::with_capacity(num_morphs as usize);
+                let mut v = Vec::with_capacity(try!(rws.check_count(num_morphs as u64, 24)));
                 for _ in (0..num_morphs) {
                     v.push(MorphTarget {
                         sphere: try!(Sphere::read(rws)),
-                        unk1: try!(rws.read_u32::<LittleEndian>()),
-                        unk2: try!(rws.read_u32::<LittleEndian>()),
+                        unk1: try!(rws.read_u32_ordered()),
+                        unk2: try!(rws.read_u32_ordered()),
                         verts: {
                             // This geometry has positions?
-                            if (flags & 2) != 0 {
-                                let mut verts = Vec::with_capacity(num_verts as usize);
+                            if !is_native && (flags & 2) != 0 {
+                                let mut verts = Vec::with_capacity(try!(rws.check_count(num_verts as u64, 12)));
                                 for _ in (0..num_verts) {
                                     verts.push(try!(Vec3::read(rws)));
                                 }
@@ -233,8 +327,8 @@ impl Geometry {
                         },
                         normals: {
                             // This geometry has vertex normals?
-                            if (flags & 16) != 0 {
-                                let mut normz = Vec::with_capacity(num_verts as usize);
+                            if !is_native && (flags & 16) != 0 {
+                                let mut normz = Vec::with_capacity(try!(rws.check_count(num_verts as u64, 12)));
                                 for _ in (0..num_verts) {
                                     normz.push(try!(Vec3::read(rws)));
                                 }
@@ -248,58 +342,308 @@ impl Geometry {
                 v
             };
 
-            Ok((flags, colors, uv_sets, faces, targets))
+            Ok((flags, num_uv, natflags, surf, num_verts, colors, uv_sets, faces, targets))
         }));
 
         let matlist = try!(MaterialList::read(rws));
-        let meshlist = try!(Extension::read_for(rws, |rws| MeshHeader::read(rws, &matlist)));
+        let mut plugins = try!(Extension::read_up(rws, PluginParent::Geometry, |rws, header| {
+            match header.id {
+                id if id == MeshHeader::section_id() => {
+                    MeshHeader::read(rws, &matlist, num_verts).map(|plg| Some(Box::new(plg) as Box<Any + Send + Sync>))
+                },
+                id if id == SkinPlg::section_id() => {
+                    SkinPlg::read(rws, num_verts).map(|plg| Some(Box::new(plg) as Box<Any + Send + Sync>))
+                },
+                id if id == NativeDataPlg::section_id() => {
+                    NativeDataPlg::read(rws).map(|plg| Some(Box::new(plg) as Box<Any + Send + Sync>))
+                },
+                _ => Ok(None),
+            }
+        }));
+        // TODO calculate meshlist ourselves
+        let mut meshlist = try!(plugins.take::<MeshHeader>().ok_or_else(|| {
+            rws.annotate(Error::MissingSection(MeshHeader::section_id()))
+        }));
+
+        let flags = GeometryFlags::from_bits_truncate(flags);
+        let is_native = (natflags & 1) != 0;
+
+        // The Native Data PLG is kept raw, but its vertex data is decoded into the geometry.
+        if is_native {
+            let decoded = plugins.get::<NativeDataPlg>().map(|plg| {
+                plg.decode(flags, num_uv as usize, &meshlist)
+            });
+            match decoded {
+                Some(Ok(native)) => {
+                    if let Some(target) = targets.get_mut(0) {
+                        target.verts = Some(native.verts);
+                        target.normals = native.normals;
+                    }
+                    colors = native.colors;
+                    uv_sets = native.uv_sets;
+                    meshlist.is_tri_strip = native.is_tri_strip;
+                    for (mesh, indices) in meshlist.meshes.iter_mut().zip(native.indices.into_iter()) {
+                        mesh.indices = indices;
+                    }
+                },
+                Some(Err(err)) => {
+                    try!(rws.tolerate(err));
+                },
+                None => {
+                    try!(rws.tolerate(Error::MissingSection(NativeDataPlg::section_id())));
+                },
+            }
+        }
 
         Ok(Geometry {
-            is_tri_strip: (flags & 1) != 0,
+            is_tri_strip: if is_native { meshlist.is_tri_strip } else { flags.contains(TRISTRIP) },
+            flags: flags,
+            native_flags: natflags,
+            num_uv_sets: num_uv,
+            surf: surf,
             colors: colors,
             uv_sets: uv_sets,
             faces: faces,
             targets: targets,
             matlist: matlist,
-            meshlist: meshlist.unwrap_or_else(|| {
-                unimplemented!()    // TODO calculate meshlist ourselves
-            }),
+            meshlist: meshlist,
+            plugins: plugins,
         })
     }
 }
 
 impl MeshHeader {
-    /// Reads a Bin Mesh PLG off the RenderWare Stream.
-    pub fn read<R: ReadExt>(rws: &mut Stream<R>, matlist: &MaterialList) -> Result<MeshHeader> {
-        let _header = try!(Self::read_header(rws));
+    /// Reads a Bin Mesh PLG for a geometry of `num_verts` vertices off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>, matlist: &MaterialList, num_verts: u32) -> Result<MeshHeader> {
+        let header = try!(Self::read_header(rws));
+
+        let flags = try!(rws.read_u32_ordered());
+        let num_mesh = try!(rws.read_u32_ordered());
+        let total_idx = try!(rws.read_u32_ordered());
 
-        let flags = try!(rws.read_u32::<LittleEndian>());
-        let num_mesh = try!(rws.read_u32::<LittleEndian>());
-        let total_idx = try!(rws.read_u32::<LittleEndian>());
+        // Meshes of native geometries have no indices, or 16 bits ones on some platforms.
+        let index_bytes = (header.size as u64).saturating_sub(12 + num_mesh as u64 * 8);
+        let index_size = if total_idx == 0 { 4 } else { index_bytes / total_idx as u64 };
+        if index_size != 0 && index_size != 2 && index_size != 4 {
+            return Err(rws.annotate(Error::Other(format!("invalid mesh index size {}", index_size))));
+        }
+
+        let mut meshes = Vec::with_capacity(try!(rws.check_count(num_mesh as u64, 8)));
+        for _ in (0..num_mesh) {
+            meshes.push(try!(Mesh::read(rws, matlist, num_verts, index_size)));
+        }
 
         Ok(MeshHeader {
             is_tri_strip: (flags & 1) != 0, // TODO better analyze?
             total_indices: total_idx,
-            meshes: try!((0..num_mesh).map(|_| Mesh::read(rws, matlist)).collect()),
+            meshes: meshes,
         })
     }
 }
 
 impl Mesh {
     /// Reads a single Mesh (from a Bin Mesh PLG) off the RenderWare Stream.
-    pub fn read<R: ReadExt>(rws: &mut Stream<R>, matlist: &MaterialList) -> Result<Mesh> {
-        let nidx = try!(rws.read_u32::<LittleEndian>()) as usize;
-        let matid = try!(rws.read_u32::<LittleEndian>()) as usize;
-        Ok(Mesh {
-            material: try!(matlist.get(matid)
-                                  .ok_or(Error::Other("Invalid 'Mesh' material id".into()))),
-            indices: {
-                let mut v = Vec::with_capacity(nidx);
-                for _ in (0..nidx) {
-                    v.push(try!(rws.read_u32::<LittleEndian>().map(|x| x as u16)));
-                }
-                v
+    ///
+    /// Each index takes `index_size` bytes, either 4, 2 or 0 when the indices are not stored,
+    /// and must refer to one of the `num_verts` vertices of the geometry.
+    ///
+    /// When lenient, meshes referring to an invalid material get the first material instead, or
+    /// a blank one if there's none, so that they keep their place among the other meshes.
+    /// When lenient, indices past the vertices are replaced by the first vertex.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>, matlist: &MaterialList,
+                            num_verts: u32, index_size: u64) -> Result<Mesh> {
+        let nidx = try!(rws.read_u32_ordered()) as usize;
+        let matid = try!(rws.read_u32_ordered()) as usize;
+
+        let material = match matlist.get(matid) {
+            Some(material) => material,
+            None => {
+                try!(rws.tolerate(Error::InvalidIndex { kind: "Material", index: matid }));
+                matlist.get(0).unwrap_or_else(|| Arc::new(Material::new_blank()))
             },
+        };
+
+        let mut indices = Vec::new();
+        if index_size > 0 {
+            indices.reserve(try!(rws.check_count(nidx as u64, index_size)));
+            for _ in (0..nidx) {
+                let index = match index_size {
+                    2 => try!(rws.read_u16_ordered()) as u32,
+                    _ => try!(rws.read_u32_ordered()),
+                };
+                // Also rules out 32 bits indices that wouldn't fit the 16 bits ones we keep.
+                if index >= num_verts || index > u16::MAX as u32 {
+                    try!(rws.tolerate(Error::InvalidIndex { kind: "Vertex", index: index as usize }));
+                    indices.push(0);
+                } else {
+                    indices.push(index as u16);
+                }
+            }
+        }
+
+        Ok(Mesh {
+            material: material,
+            indices: indices,
+        })
+    }
+}
+
+impl SkinPlg {
+    /// Reads a Skin PLG for a geometry of `num_verts` vertices off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>, num_verts: u32) -> Result<SkinPlg> {
+        let header = try!(Self::read_header(rws));
+        let endoff = (header.size as u64) + try!(rws.seek(SeekFrom::Current(0)));
+
+        let num_bones = try!(rws.read_u8());
+        let num_used_bones = try!(rws.read_u8());
+        let max_weights = try!(rws.read_u8());
+        let _pad = try!(rws.read_u8());
+
+        // Older streams have no used bones and each inverse matrix is preceded by 0xDEADDEAD.
+        let is_old_format = num_used_bones == 0;
+
+        let used_bones = try!(rws.read_bytes(num_used_bones as usize));
+
+        let mut bone_indices = Vec::with_capacity(try!(rws.check_count(num_verts as u64, 4)));
+        for _ in (0..num_verts) {
+            bone_indices.push([
+                try!(rws.read_u8()), try!(rws.read_u8()),
+                try!(rws.read_u8()), try!(rws.read_u8()),
+            ]);
+        }
+
+        let mut bone_weights = Vec::with_capacity(try!(rws.check_count(num_verts as u64, 16)));
+        for _ in (0..num_verts) {
+            bone_weights.push([
+                try!(rws.read_f32_ordered()), try!(rws.read_f32_ordered()),
+                try!(rws.read_f32_ordered()), try!(rws.read_f32_ordered()),
+            ]);
+        }
+
+        let mut inverse_matrices = Vec::with_capacity(num_bones as usize);
+        for _ in (0..num_bones) {
+            if is_old_format {
+                let _deaddead = try!(rws.read_u32_ordered());
+            }
+            inverse_matrices.push(try!(SkinPlg::read_matrix(rws)));
+        }
+
+        // Newer streams carry mesh splitting data for hardware skinning afterwards, we don't need it.
+        try!(rws.seek(SeekFrom::Start(endoff)));
+
+        Ok(SkinPlg {
+            num_bones: num_bones,
+            max_weights: max_weights,
+            used_bones: used_bones,
+            bone_indices: bone_indices,
+            bone_weights: bone_weights,
+            inverse_matrices: inverse_matrices,
+        })
+    }
+
+    /// Reads a 4x4 matrix, the fourth component of each axis being padding.
+    fn read_matrix<R: ReadExt>(rws: &mut Stream<R>) -> Result<Matrix> {
+        let mut axes = [Vec3(0.0, 0.0, 0.0); 4];
+        for axis in axes.iter_mut() {
+            *axis = try!(Vec3::read(rws));
+            let _pad = try!(rws.read_f32_ordered());
+        }
+        Ok(Matrix {
+            right: axes[0],
+            top: axes[1],
+            at: axes[2],
+            pos: axes[3],
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Instance, Strictness, Stream, Material, MaterialList};
+    use super::MeshHeader;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn push_u32(v: &mut Vec<u8>, x: u32) {
+        v.extend((0..4).map(|i| (x >> (i * 8)) as u8));
+    }
+
+    /// Builds a Bin Mesh PLG of tri-lists with the specified material and 16 bits indices each.
+    fn bin_mesh(meshes: &[(u32, &[u16])]) -> Vec<u8> {
+        let total = meshes.iter().map(|&(_, indices)| indices.len()).fold(0, |a, b| a + b);
+        let mut data = Vec::new();
+        push_u32(&mut data, 0x050E);
+        push_u32(&mut data, (12 + meshes.len() * 8 + total * 2) as u32);
+        push_u32(&mut data, 0x1803FFFF);
+        push_u32(&mut data, 0);
+        push_u32(&mut data, meshes.len() as u32);
+        push_u32(&mut data, total as u32);
+        for &(matid, indices) in meshes.iter() {
+            push_u32(&mut data, indices.len() as u32);
+            push_u32(&mut data, matid);
+            for &index in indices.iter() {
+                data.extend([index as u8, (index >> 8) as u8].iter().cloned());
+            }
+        }
+        data
+    }
+
+    fn read(data: Vec<u8>, strictness: Strictness) -> Option<MeshHeader> {
+        let matlist = MaterialList(vec![Arc::new(Material::new_blank())]);
+        let mut rw = Instance::new();
+        rw.set_strictness(strictness);
+        MeshHeader::read(&mut Stream::new(Cursor::new(data), &mut rw), &matlist, 3).ok()
+    }
+
+    #[test]
+    fn invalid_material() {
+        let data = bin_mesh(&[(7, &[0, 1, 2]), (0, &[2, 1, 0])]);
+        assert!(read(data.clone(), Strictness::Strict).is_none());
+
+        // The mesh keeps its place, so the following ones are not shifted.
+        let meshlist = read(data, Strictness::Lenient).unwrap();
+        assert_eq!(meshlist.meshes.len(), 2);
+        assert_eq!(meshlist.meshes[0].indices, vec![0, 1, 2]);
+        assert_eq!(meshlist.meshes[1].indices, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn invalid_vertex() {
+        let data = bin_mesh(&[(0, &[0, 3, 2])]);
+        assert!(read(data.clone(), Strictness::Strict).is_none());
+
+        let meshlist = read(data, Strictness::Lenient).unwrap();
+        assert_eq!(meshlist.meshes[0].indices, vec![0, 0, 2]);
+    }
+
+    #[test]
+    fn wide_indices() {
+        let mut data = Vec::new();
+        push_u32(&mut data, 0x050E);
+        push_u32(&mut data, 12 + 8 + 3 * 4);
+        push_u32(&mut data, 0x1803FFFF);
+        for &x in [0, 1, 3, 3, 0, 2, 0, 0x10001].iter() {
+            push_u32(&mut data, x);
+        }
+        assert!(read(data.clone(), Strictness::Strict).is_none());
+
+        // An index that doesn't fit 16 bits is not truncated into a valid one.
+        let meshlist = read(data, Strictness::Lenient).unwrap();
+        assert_eq!(meshlist.meshes[0].indices, vec![2, 0, 0]);
+    }
+
+    #[test]
+    fn odd_index_size() {
+        // Three indices taking 9 bytes.
+        let mut data = Vec::new();
+        push_u32(&mut data, 0x050E);
+        push_u32(&mut data, 12 + 8 + 9);
+        push_u32(&mut data, 0x1803FFFF);
+        for &x in [0, 1, 3, 3, 0].iter() {
+            push_u32(&mut data, x);
+        }
+        data.extend([0u8; 9].iter().cloned());
+        assert!(read(data.clone(), Strictness::Strict).is_none());
+        assert!(read(data, Strictness::Lenient).is_none());
+    }
+}
@@ -0,0 +1,239 @@
+//! Decoding of the GameCube (GX) texture formats into RGBA 8 bits each.
+//!
+//! GX textures are stored in tiles of a few pixels (the block), the blocks being ordered from
+//! left to right and top to bottom, as are the pixels inside each block. Images are padded
+//! to a whole number of blocks.
+
+/// Texture formats of the GX hardware.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GxFormat {
+    I4,
+    I8,
+    IA4,
+    IA8,
+    RGB565,
+    RGB5A3,
+    RGBA8,
+    CI4,
+    CI8,
+    CMPR,
+}
+
+impl GxFormat {
+    pub fn from_raw(raw: u8) -> Option<GxFormat> {
+        match raw {
+            0x0 => Some(GxFormat::I4),
+            0x1 => Some(GxFormat::I8),
+            0x2 => Some(GxFormat::IA4),
+            0x3 => Some(GxFormat::IA8),
+            0x4 => Some(GxFormat::RGB565),
+            0x5 => Some(GxFormat::RGB5A3),
+            0x6 => Some(GxFormat::RGBA8),
+            0x8 => Some(GxFormat::CI4),
+            0x9 => Some(GxFormat::CI8),
+            0xE => Some(GxFormat::CMPR),
+            _ => None,
+        }
+    }
+
+    /// Gets the width and height of the blocks, and the size in bytes of a single block.
+    fn block(&self) -> (usize, usize, usize) {
+        match *self {
+            GxFormat::I4 | GxFormat::CI4 | GxFormat::CMPR => (8, 8, 32),
+            GxFormat::I8 | GxFormat::IA4 | GxFormat::CI8 => (8, 4, 32),
+            GxFormat::IA8 | GxFormat::RGB565 | GxFormat::RGB5A3 => (4, 4, 32),
+            GxFormat::RGBA8 => (4, 4, 64),
+        }
+    }
+
+    /// Gets the size in bytes of an image of the specified dimensions.
+    pub fn image_size(&self, width: usize, height: usize) -> usize {
+        let (bw, bh, bsize) = self.block();
+        ((width + bw - 1) / bw) * ((height + bh - 1) / bh) * bsize
+    }
+}
+
+/// Decodes an image of the specified format and dimensions into RGBA 8 bits each.
+///
+/// The `palette` is used by the color indexed formats, as RGB5A3 colors. Returns `None` if
+/// `data` is too short or a palette is missing.
+pub fn decode(format: GxFormat, width: usize, height: usize,
+              data: &[u8], palette: Option<&[u16]>) -> Option<Vec<u8>> {
+    if data.len() < format.image_size(width, height) {
+        return None;
+    }
+    if (format == GxFormat::CI4 || format == GxFormat::CI8) && palette.is_none() {
+        return None;
+    }
+
+    let (bw, bh, bsize) = format.block();
+    let blocks_x = (width + bw - 1) / bw;
+    let blocks_y = (height + bh - 1) / bh;
+    let mut rgba = vec![0u8; width * height * 4];
+
+    for by in (0..blocks_y) {
+        for bx in (0..blocks_x) {
+            let block = &data[(by * blocks_x + bx) * bsize..][..bsize];
+            for py in (0..bh) {
+                for px in (0..bw) {
+                    let (x, y) = (bx * bw + px, by * bh + py);
+                    if x >= width || y >= height {
+                        continue;
+                    }
+                    let color = decode_pixel(format, block, px, py, palette);
+                    let offset = (y * width + x) * 4;
+                    for c in (0..4) {
+                        rgba[offset + c] = color[c];
+                    }
+                }
+            }
+        }
+    }
+
+    Some(rgba)
+}
+
+/// Decodes the pixel at the specified position in a block.
+fn decode_pixel(format: GxFormat, block: &[u8], px: usize, py: usize,
+                palette: Option<&[u16]>) -> [u8; 4] {
+    let (bw, _, _) = format.block();
+    let i = py * bw + px;
+    match format {
+        GxFormat::I4 => {
+            let v = nibble(block, i) * 17;
+            [v, v, v, v]
+        },
+        GxFormat::I8 => {
+            let v = block[i];
+            [v, v, v, v]
+        },
+        GxFormat::IA4 => {
+            let (a, v) = ((block[i] >> 4) * 17, (block[i] & 0xF) * 17);
+            [v, v, v, a]
+        },
+        GxFormat::IA8 => {
+            let (a, v) = (block[i * 2], block[i * 2 + 1]);
+            [v, v, v, a]
+        },
+        GxFormat::RGB565 => rgb565(be16(block, i * 2)),
+        GxFormat::RGB5A3 => rgb5a3(be16(block, i * 2)),
+        GxFormat::RGBA8 => {
+            // Alpha and red of all the pixels come first, then green and blue.
+            [block[i * 2 + 1], block[32 + i * 2], block[32 + i * 2 + 1], block[i * 2]]
+        },
+        GxFormat::CI4 => lookup(palette, nibble(block, i) as usize),
+        GxFormat::CI8 => lookup(palette, block[i] as usize),
+        GxFormat::CMPR => {
+            // Each block is made of four DXT1 sub-blocks of 4x4 pixels.
+            let sub = &block[((py / 4) * 2 + (px / 4)) * 8..][..8];
+            let (c0, c1) = (be16(sub, 0), be16(sub, 2));
+            let index = (sub[4 + (py % 4)] >> (6 - (px % 4) * 2)) & 0x3;
+            dxt1_color(c0, c1, index)
+        },
+    }
+}
+
+fn nibble(block: &[u8], i: usize) -> u8 {
+    if i % 2 == 0 { block[i / 2] >> 4 } else { block[i / 2] & 0xF }
+}
+
+fn be16(block: &[u8], offset: usize) -> u16 {
+    ((block[offset] as u16) << 8) | (block[offset + 1] as u16)
+}
+
+fn lookup(palette: Option<&[u16]>, index: usize) -> [u8; 4] {
+    palette.and_then(|palette| palette.get(index)).map(|&c| rgb5a3(c)).unwrap_or([0, 0, 0, 0])
+}
+
+/// Expands a value of `bits` bits into 8 bits.
+fn expand(value: u16, bits: u32) -> u8 {
+    let value = value as u32;
+    ((value << (8 - bits)) | (value >> (2 * bits - 8))) as u8
+}
+
+fn rgb565(c: u16) -> [u8; 4] {
+    [expand(c >> 11, 5), expand((c >> 5) & 0x3F, 6), expand(c & 0x1F, 5), 255]
+}
+
+fn rgb5a3(c: u16) -> [u8; 4] {
+    if (c & 0x8000) != 0 {
+        [expand((c >> 10) & 0x1F, 5), expand((c >> 5) & 0x1F, 5), expand(c & 0x1F, 5), 255]
+    } else {
+        let a = ((c >> 12) & 0x7) as u8;
+        [expand((c >> 8) & 0xF, 4), expand((c >> 4) & 0xF, 4), expand(c & 0xF, 4),
+         (a << 5) | (a << 2) | (a >> 1)]
+    }
+}
+
+fn dxt1_color(c0: u16, c1: u16, index: u8) -> [u8; 4] {
+    let (a, b) = (rgb565(c0), rgb565(c1));
+    let mix = |wa: u32, wb: u32| {
+        let channel = |i: usize| ((a[i] as u32 * wa + b[i] as u32 * wb) / (wa + wb)) as u8;
+        [channel(0), channel(1), channel(2), 255]
+    };
+    match index {
+        0 => a,
+        1 => b,
+        2 if c0 > c1 => mix(2, 1),
+        3 if c0 > c1 => mix(1, 2),
+        2 => mix(1, 1),
+        _ => [0, 0, 0, 0],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, GxFormat};
+
+    fn pixel(rgba: &[u8], i: usize) -> &[u8] {
+        &rgba[i * 4..i * 4 + 4]
+    }
+
+    #[test]
+    fn rgb5a3() {
+        let mut data = vec![0u8; 32];
+        data[0] = 0xFC; data[1] = 0x00;     // opaque, red
+        data[2] = 0x30; data[3] = 0xF0;     // alpha 3 of 7, green
+        let rgba = decode(GxFormat::RGB5A3, 3, 1, &data, None).unwrap();
+        assert_eq!(pixel(&rgba, 0), &[255, 0, 0, 255][..]);
+        assert_eq!(pixel(&rgba, 1), &[0, 255, 0, 109][..]);
+        assert_eq!(pixel(&rgba, 2), &[0, 0, 0, 0][..]);
+    }
+
+    #[test]
+    fn cmpr() {
+        let mut data = vec![0u8; 32];
+        // First sub-block, four colors mode from red to blue.
+        data[0] = 0xF8; data[1] = 0x00;
+        data[2] = 0x00; data[3] = 0x1F;
+        data[4] = 0x1B;                     // indices 0, 1, 2, 3
+        // Second sub-block, three colors mode from black to white.
+        data[10] = 0xFF; data[11] = 0xFF;
+        data[12] = 0xC0;                    // indices 3, 0, 0, 0
+        let rgba = decode(GxFormat::CMPR, 8, 1, &data, None).unwrap();
+        assert_eq!(pixel(&rgba, 0), &[255, 0, 0, 255][..]);
+        assert_eq!(pixel(&rgba, 1), &[0, 0, 255, 255][..]);
+        assert_eq!(pixel(&rgba, 2), &[170, 0, 85, 255][..]);
+        assert_eq!(pixel(&rgba, 3), &[85, 0, 170, 255][..]);
+        assert_eq!(pixel(&rgba, 4), &[0, 0, 0, 0][..]);
+        assert_eq!(pixel(&rgba, 5), &[0, 0, 0, 255][..]);
+    }
+
+    #[test]
+    fn color_indexed() {
+        let palette = [0xFC00u16, 0x83E0];  // opaque red and green
+        let mut data = vec![0u8; 32];
+        data[0] = 0x10;                     // indices 1 and 0
+        let rgba = decode(GxFormat::CI4, 2, 1, &data, Some(&palette)).unwrap();
+        assert_eq!(pixel(&rgba, 0), &[0, 255, 0, 255][..]);
+        assert_eq!(pixel(&rgba, 1), &[255, 0, 0, 255][..]);
+
+        data[0] = 1; data[1] = 0; data[2] = 2;
+        let rgba = decode(GxFormat::CI8, 3, 1, &data, Some(&palette)).unwrap();
+        assert_eq!(pixel(&rgba, 0), &[0, 255, 0, 255][..]);
+        assert_eq!(pixel(&rgba, 1), &[255, 0, 0, 255][..]);
+        assert_eq!(pixel(&rgba, 2), &[0, 0, 0, 0][..]);    // out of the palette
+
+        assert!(decode(GxFormat::CI8, 3, 1, &data, None).is_none());
+    }
+}
@@ -1,24 +1,135 @@
-use super::Section;
+use super::{Section, Struct, Result, ReadExt, Stream};
 
-// STUB! TODO
+use super::{FrameList, FrameId, Extension, PluginList, PluginParent};
+use std::sync::Arc;
+use std::any::Any;
+
+bitflags! {
+    /// Specifies which objects a light illuminates.
+    flags LightFlags: u32 {
+        /// The light illuminates the atomics of the scene.
+        const LIGHT_ATOMICS = 0x1,
+        /// The light illuminates the static geometry of the world.
+        const LIGHT_WORLD = 0x2,
+    }
+}
+
+/// Kinds of light sources.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LightKind {
+    /// Light coming from a direction, as the sun, pointing towards the *at* vector of its frame.
+    Directional,
+    /// Light illuminating everything evenly.
+    Ambient,
+    /// Light coming from the position of its frame into every direction.
+    Point,
+    /// Light coming from the position of its frame, within a cone.
+    Spot,
+    /// Same as `Spot`, but fading towards the edges of the cone.
+    SoftSpot,
+    /// A kind of light unknown to us.
+    Unknown(u16),
+}
 
 /// Lights are used to illuminate atomics and worlds.
 ///
 /// This object represents dynamic lighting in a RenderWare Graphics Retained Mode scene.
-/// Lighting models available are: 
+/// Lighting models available are:
 ///   * Ambient
 ///   * Directional
 ///   * Point
 ///   * Spotlight
 ///   * Soft Spotlight
 ///
+/// As atomics, the position and direction of a light is given by the frame it's attached to.
 #[derive(Debug)]
-pub struct Light;
-
-impl Light {
-
+pub struct Light {
+    frame: Option<FrameId>,
+    pub kind: LightKind,
+    pub flags: LightFlags,
+    /// Color of the light, as red, green and blue, usually in the range 0.0 to 1.0.
+    pub color: [f32; 3],
+    /// Distance reached by point and spot lights.
+    pub radius: f32,
+    /// Negated cosine of the half angle of the cone of spot lights.
+    pub minus_cos_angle: f32,
+    /// Extension plugins attached to this light.
+    pub plugins: PluginList,
 }
 
 impl Section for Light {
     fn section_id() -> u32 { 0x0012 }
 }
+
+impl LightKind {
+    fn from_raw(raw: u16) -> LightKind {
+        match raw {
+            0x01 => LightKind::Directional,
+            0x02 => LightKind::Ambient,
+            0x80 => LightKind::Point,
+            0x81 => LightKind::Spot,
+            0x82 => LightKind::SoftSpot,
+            raw => LightKind::Unknown(raw),
+        }
+    }
+}
+
+impl Light {
+    /// Constructs a light of the specified kind and color, illuminating atomics and worlds.
+    pub fn new(kind: LightKind, color: [f32; 3]) -> Light {
+        Light {
+            frame: None,
+            kind: kind,
+            flags: LIGHT_ATOMICS | LIGHT_WORLD,
+            color: color,
+            radius: 0.0,
+            minus_cos_angle: 0.0,
+            plugins: PluginList::new(),
+        }
+    }
+
+    /// Gets the frame attached to this light.
+    pub fn frame(&self) -> Option<FrameId> {
+        self.frame
+    }
+
+    /// Sets the frame attached to this light.
+    ///
+    /// If the `frame` parameter is `None` the light gets detached.
+    pub fn set_frame(&mut self, frame: Option<FrameId>) {
+        self.frame = frame;
+    }
+
+    /// Gets the plugin of type `T` attached to this light, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
+    }
+
+    /// Reads a light attached to the frame at `frame_index` off the RenderWare Stream.
+    ///
+    /// In clumps, the frame index is stored in a structure before the light itself.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>, framelist: &FrameList, frame_index: u32)
+                                                                    -> Result<Arc<Light>> {
+        let _header = try!(Self::read_header(rws));
+
+        let (radius, color, minus_cos_angle, flags, kind) = try!(Struct::read_up(rws, |rws| {
+            Ok((try!(rws.read_f32_ordered()),
+                [try!(rws.read_f32_ordered()), try!(rws.read_f32_ordered()), try!(rws.read_f32_ordered())],
+                try!(rws.read_f32_ordered()),
+                try!(rws.read_u16_ordered()),
+                try!(rws.read_u16_ordered())))
+        }));
+
+        let plugins = try!(Extension::read(rws, PluginParent::Light));
+
+        Ok(Arc::new(Light {
+            frame: framelist.id(frame_index as usize),
+            kind: LightKind::from_raw(kind),
+            flags: LightFlags::from_bits_truncate(flags as u32),
+            color: color,
+            radius: radius,
+            minus_cos_angle: minus_cos_angle,
+            plugins: plugins,
+        }))
+    }
+}
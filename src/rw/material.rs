@@ -1,12 +1,13 @@
-use byteorder::{ReadBytesExt, LittleEndian};
 use super::{Section, Struct, Result, Error, ReadExt, Stream};
 
-use super::{Texture, Extension, Rgba};
-use std::rc::Rc;
+use super::{Texture, Extension, PluginList, PluginParent, Rgba};
+use super::MaterialUvAnimPlg;
+use std::sync::Arc;
+use std::any::Any;
 
 /// Holds a list of `Material`s to be passed around.
 #[derive(Debug)]
-pub struct MaterialList(pub Vec<Rc<Material>>);
+pub struct MaterialList(pub Vec<Arc<Material>>);
 
 /// Materials describe how things are to appear when rendered.
 ///
@@ -17,9 +18,13 @@ pub struct MaterialList(pub Vec<Rc<Material>>);
 /// rendering nodes on a per-material basis (not implemented).
 #[derive(Debug)]
 pub struct Material {
-    pub texture: Option<Rc<Texture>>,
+    pub texture: Option<Arc<Texture>>,
     pub color: Rgba,
     pub surf: SurfaceProperties,
+    /// Extension plugins attached to this material.
+    pub plugins: PluginList,
+    /// Extension plugins attached to the texture section of this material.
+    pub texture_plugins: PluginList,
 }
 
 /// Surface coefficients.
@@ -48,16 +53,16 @@ impl Section for Material {
 impl SurfaceProperties {
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<SurfaceProperties> {
         Ok(SurfaceProperties {
-            ambient: try!(rws.read_f32::<LittleEndian>()),
-            specular: try!(rws.read_f32::<LittleEndian>()),
-            diffuse: try!(rws.read_f32::<LittleEndian>()),
+            ambient: try!(rws.read_f32_ordered()),
+            specular: try!(rws.read_f32_ordered()),
+            diffuse: try!(rws.read_f32_ordered()),
         })
     }
 }
 
 impl MaterialList {
     /// Gets the material at the specified index or `None` if out of range.
-    pub fn get(&self, index: usize) -> Option<Rc<Material>> {
+    pub fn get(&self, index: usize) -> Option<Arc<Material>> {
         self.0.get(index).map(|rcmat| rcmat.clone())
     }
 
@@ -66,16 +71,18 @@ impl MaterialList {
         let _header = try!(Self::read_header(rws));
 
         let mats_id: Vec<i32> = try!(Struct::read_up(rws, |rws| {
-            let matcount = try!(rws.read_u32::<LittleEndian>());
-            (0..matcount).map(|_| Ok(try!(rws.read_i32::<LittleEndian>()))).collect()
+            let matcount = try!(rws.read_u32_ordered());
+            try!(rws.check_count(matcount as u64, 4));
+            (0..matcount).map(|_| Ok(try!(rws.read_i32_ordered()))).collect()
         }));
 
-        let mut mats: Vec<Rc<Material>> = Vec::with_capacity(mats_id.len());
+        let mut mats: Vec<Arc<Material>> = Vec::with_capacity(mats_id.len());
         for &id in mats_id.iter() {
             let mat = match id {
-                id if id < 0 => Rc::new(try!(Material::read(rws))),
-                id => try!(mats.get(id as usize).map(|rcmat| rcmat.clone())
-                               .ok_or(Error::Other("Invalid 'MaterialList' Material id".into()))),
+                id if id < 0 => Arc::new(try!(Material::read(rws))),
+                id => try!(mats.get(id as usize).map(|rcmat| rcmat.clone()).ok_or_else(|| {
+                    rws.annotate(Error::InvalidIndex { kind: "Material", index: id as usize })
+                })),
             };
             mats.push(mat);
         }
@@ -85,36 +92,51 @@ impl MaterialList {
 }
 
 impl Material {
+    /// Constructs a white untextured material.
+    pub fn new_blank() -> Material {
+        Material {
+            texture: None,
+            color: Rgba(255, 255, 255, 255),
+            surf: SurfaceProperties { ambient: 1.0, specular: 1.0, diffuse: 1.0 },
+            plugins: PluginList::new(),
+            texture_plugins: PluginList::new(),
+        }
+    }
+
+    /// Gets the plugin of type `T` attached to this material, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
+    }
+
     /// Reads a Material off the RenderWare Stream.
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Material> {
         let _header = try!(Self::read_header(rws));
 
         let (_, color, _, has_tex, surf) = try!(Struct::read_up(rws, |rws| {
-            Ok((try!(rws.read_u32::<LittleEndian>()),       // unused flags
+            Ok((try!(rws.read_u32_ordered()),       // unused flags
                 try!(Rgba::read(rws)),
-                try!(rws.read_u32::<LittleEndian>()),       // unused
-                try!(rws.read_u32::<LittleEndian>()) != 0,
+                try!(rws.read_u32_ordered()),       // unused
+                try!(rws.read_u32_ordered()) != 0,
                 try!(SurfaceProperties::read(rws))))
         }));
 
         // Associated texture...
-        let texture = if has_tex {
-            match Texture::read(rws) {
-                Ok(tex) => Some(tex),
-                Err(Error::TextureNotFound(_)) => None,
-                Err(err) => return Err(err),
-            }
+        let (texture, texture_plugins) = if has_tex {
+            try!(Texture::read(rws))
         } else {
-            None
+            (None, PluginList::new())
         };
 
         // Extension...
-        try!(Extension::skip_section(rws));
+        let plugins = try!(Extension::read_for(rws, PluginParent::Material,
+                                               |rws| MaterialUvAnimPlg::read(rws)));
 
         Ok(Material {
             texture: texture,
             color: color,
             surf: surf,
+            plugins: plugins,
+            texture_plugins: texture_plugins,
         })
     }
 }
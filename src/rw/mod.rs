@@ -1,8 +1,11 @@
 #![allow(dead_code)]
 #![feature(alloc)]
-use byteorder::{self, ReadBytesExt, LittleEndian};
+use byteorder::{self, ReadBytesExt, LittleEndian, BigEndian};
 use std::io::{self, Read, Seek, SeekFrom};
-use std::rc::Rc;
+use std::sync::Arc;
+use std::any::Any;
+use std::collections::HashMap;
+use std::{error, fmt, mem, u64};
 
 // TODO replace all the occ to ok_or to something more performancy because of string creation
 // TODO fix version detection on streams
@@ -16,16 +19,29 @@ mod geometry;
 mod material;
 mod texture;
 mod light;
+mod anim;
+mod world;
+mod gx;
+mod object;
+mod native_data;
 
 pub use self::basic::{Rgba, Uv, Vec3, Sphere, Matrix, BBox, Rect, Line};
-pub use self::section::{Struct, StringExt, Extension};
+pub use self::section::{Struct, StringExt, Extension, PluginList, PluginParent};
 pub use self::clump::Clump;
-pub use self::frame::{FrameList, Frame, FrameObjectValue, FrameObject, NodeNamePlg};
+pub use self::frame::{FrameList, FrameId, Frame, Childs, Ancestors, DepthFirst, BreadthFirst, NodeNamePlg};
+pub use self::frame::{HAnimPlg, HAnimHierarchy, HAnimNode};
 pub use self::atomic::Atomic;
-pub use self::geometry::{GeometryList, Geometry};
+pub use self::geometry::{GeometryList, Geometry, MeshHeader, Mesh, Triangle, MorphTarget, SkinPlg};
+pub use self::geometry::{GeometryFlags, TRISTRIP, POSITIONS, TEXTURED, PRELIT, NORMALS, LIGHT};
+pub use self::geometry::{MODULATE_MATERIAL_COLOR, TEXTURED2};
 pub use self::material::{MaterialList, Material, SurfaceProperties};
 pub use self::texture::{Texture, SimpleTexture, TexDictionary, TexNative, FilterMode, WrapMode, TextureData, TexLevel};
-pub use self::light::Light;
+pub use self::light::{Light, LightKind, LightFlags, LIGHT_ATOMICS, LIGHT_WORLD};
+pub use self::anim::{Animation, AnimData, UvAnimation, UvKeyFrame, UvMatrix, UvAnimDictionary, MaterialUvAnimPlg};
+pub use self::anim::{HAnimAnimation, HAnimKeyFrame};
+pub use self::object::{Object, read_stream};
+pub use self::world::{World, WorldSector, PlaneSector, PlaneAxis, AtomicSector};
+pub use self::native_data::{NativeDataPlg, NativeData};
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -35,10 +51,90 @@ pub enum Error {
     ExpectedSection { expect: u32, found: u32 },
     MissingSection(u32),
     IoError(byteorder::Error),
-    TextureNotFound(String),
+    UnsupportedPlatform(u32),
+    UnsupportedRasterFormat(u32),
+    InvalidIndex { kind: &'static str, index: usize },
+    EmptyRaster,
+    SectionOverflow { id: u32 },
+    SectionUnderflow { id: u32, unread: u64 },
+    InvalidCount(u64),
+    AllocationLimit { size: u64, limit: u64 },
+    /// Where in the stream the `cause` error happened, see `Stream::annotate`.
+    Context { path: String, offset: u64, cause: Box<Error> },
     Other(String), // TODO Find all calls to this (ok_or) and optimize to be lazy to avoid alloc
 }
 
+impl Error {
+    /// Gets the error underlying any context.
+    pub fn root_cause(&self) -> &Error {
+        match *self {
+            Error::Context { ref cause, .. } => cause.root_cause(),
+            ref err => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ExpectedSection { expect, found } => {
+                write!(f, "expected {:#x} found {:#x}", expect, found)
+            },
+            Error::MissingSection(id) => write!(f, "missing section {:#x}", id),
+            Error::IoError(ref err) => write!(f, "{}", err),
+            Error::UnsupportedPlatform(id) => write!(f, "unsupported platform {:#x}", id),
+            Error::UnsupportedRasterFormat(format) => {
+                write!(f, "unsupported raster format {:#x}", format)
+            },
+            Error::InvalidIndex { kind, index } => write!(f, "invalid {} index {}", kind, index),
+            Error::EmptyRaster => write!(f, "raster has no levels"),
+            Error::SectionOverflow { id } => write!(f, "read past the end of section {:#x}", id),
+            Error::SectionUnderflow { id, unread } => {
+                write!(f, "{} bytes left unread in section {:#x}", unread, id)
+            },
+            Error::InvalidCount(count) => write!(f, "count {} doesn't fit in the section", count),
+            Error::AllocationLimit { size, limit } => {
+                write!(f, "allocation of {} bytes exceeds the limit of {} bytes", size, limit)
+            },
+            Error::Context { ref path, offset, ref cause } if path.is_empty() => {
+                write!(f, "@ {:#X}: {}", offset, cause)
+            },
+            Error::Context { ref path, offset, ref cause } => {
+                write!(f, "{} @ {:#X}: {}", path, offset, cause)
+            },
+            Error::Other(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::ExpectedSection { .. } => "unexpected section",
+            Error::MissingSection(_) => "missing section",
+            Error::IoError(ref err) => err.description(),
+            Error::UnsupportedPlatform(_) => "unsupported platform",
+            Error::UnsupportedRasterFormat(_) => "unsupported raster format",
+            Error::InvalidIndex { .. } => "invalid index",
+            Error::EmptyRaster => "raster has no levels",
+            Error::SectionOverflow { .. } => "read past the end of section",
+            Error::SectionUnderflow { .. } => "section not fully read",
+            Error::InvalidCount(_) => "count doesn't fit in the section",
+            Error::AllocationLimit { .. } => "allocation limit exceeded",
+            Error::Context { ref cause, .. } => cause.description(),
+            Error::Other(ref msg) => msg,
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::IoError(ref err) => Some(err),
+            Error::Context { ref cause, .. } => Some(&**cause),
+            _ => None,
+        }
+    }
+}
+
 impl From<byteorder::Error> for Error {
     fn from(e: byteorder::Error) -> Error {
         Error::IoError(e)
@@ -61,7 +157,7 @@ pub struct Header {
     pub version: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SectionBuf {
     pub header: Header,
     pub data: Vec<u8>,
@@ -102,21 +198,35 @@ impl SectionBuf {
         })
     }
 
+    /// Reads the next section header off the RenderWare Stream without consuming it.
+    pub fn peek_header<R: ReadExt>(rws: &mut Stream<R>) -> Result<Header> {
+        let header = try!(SectionBuf::read_header(rws));
+        try!(rws.seek(SeekFrom::Current(-12)));
+        Ok(header)
+    }
+
     fn read_header<R: ReadExt>(rws: &mut Stream<R>) -> Result<Header> {
-        Ok(Header {
+        let offset = rws.position();
+        let header = Header {
             id: try!(rws.read_u32::<LittleEndian>()),
             size: try!(rws.read_u32::<LittleEndian>()),
             version: try!(rws.read_u32::<LittleEndian>()),
-        })
+        };
+        rws.enter_section(&header, offset);
+        if rws.path.len() == 2 {
+            try!(rws.detect_endianness(&header));
+        }
+        Ok(header)
     }
 
     fn read_header_id<R: ReadExt>(rws: &mut Stream<R>, id: u32) -> Result<Header> {
+        let offset = rws.position();
         match SectionBuf::read_header(rws) {
             Ok(header) if header.id != id => {
-                Err(Error::ExpectedSection { expect: id, found: header.id })
+                Err(rws.annotate_at(Error::ExpectedSection { expect: id, found: header.id }, offset))
             },
             Ok(header) => Ok(header),
-            Err(err) => Err(Error::from(err)),
+            Err(err) => Err(rws.annotate_at(err, offset)),
         }
     }
 
@@ -153,10 +263,42 @@ impl SectionBuf {
 }
 
 
+/// How the reader reacts to recoverable problems in the streams.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Strictness {
+    /// Fail on any problem.
+    Strict,
+    /// Record recoverable problems as diagnostics and go on with a best-effort result, as the
+    /// game itself does with slightly broken files.
+    Lenient,
+}
+
+/// Byte order of the struct payloads in the streams.
+///
+/// Section headers are always little-endian, but the data inside them is stored in the byte
+/// order of the platform the stream was made for, such as big-endian on the GameCube.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// Default limit for a single allocation driven by stream data, see `Instance::set_alloc_limit`.
+pub const DEFAULT_ALLOC_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// Reads a registered plugin off its raw chunk.
+pub type PluginHandler = Box<Fn(&SectionBuf) -> Result<Box<Any + Send + Sync>> + Send + Sync>;
+
 pub struct Instance {
-    curr_dict: Option<Rc<TexDictionary>>,   // binded dictionary
-    //dictionary: Rc<TexDictionary>,          // default dictionary
-    //tex_blank: Rc<Texture>,                 // default texture (stored at default dictionary)
+    curr_dict: Option<Arc<TexDictionary>>,   // binded dictionary
+    curr_uvdict: Option<Arc<UvAnimDictionary>>,  // binded uv animation dictionary
+    plugins: HashMap<(PluginParent, u32), PluginHandler>,   // user registered plugins
+    strictness: Strictness,
+    diagnostics: Vec<Error>,                // recoverable problems found while reading
+    alloc_limit: u64,                       // max bytes of a single allocation from stream data
+    endianness: Endianness,                 // byte order of the struct payloads
+    //dictionary: Arc<TexDictionary>,          // default dictionary
+    //tex_blank: Arc<Texture>,                 // default texture (stored at default dictionary)
 }
 
 impl Instance {
@@ -182,12 +324,18 @@ impl Instance {
 */
         Instance {
             curr_dict: None,
+            curr_uvdict: None,
+            plugins: HashMap::new(),
+            strictness: Strictness::Strict,
+            diagnostics: Vec::new(),
+            alloc_limit: DEFAULT_ALLOC_LIMIT,
+            endianness: Endianness::Little,
             //tex_blank: dictionary.read_texture("__RwBlank__", None).unwrap(),
             //dictionary: dictionary,
         }
     }
 
-    pub fn bind_dictionary(&mut self, dictionary: &Rc<TexDictionary>) {
+    pub fn bind_dictionary(&mut self, dictionary: &Arc<TexDictionary>) {
         self.curr_dict = Some(dictionary.clone());
     }
 
@@ -195,60 +343,513 @@ impl Instance {
         self.curr_dict = None;
     }
 
-    pub fn read_texture(&self, name: &str, mask: Option<&str>) -> Option<Rc<Texture>> {
+    pub fn read_texture(&self, name: &str, mask: Option<&str>) -> Option<Arc<Texture>> {
         match self.curr_dict {
             Some(ref dict) => dict.read_texture(name, mask),
             None => None,
         }
     }
+
+    pub fn bind_uv_dictionary(&mut self, dictionary: &Arc<UvAnimDictionary>) {
+        self.curr_uvdict = Some(dictionary.clone());
+    }
+
+    pub fn unbind_uv_dictionary(&mut self) {
+        self.curr_uvdict = None;
+    }
+
+    pub fn read_uv_animation(&self, name: &str) -> Option<Arc<Animation>> {
+        match self.curr_uvdict {
+            Some(ref dict) => dict.find(name),
+            None => None,
+        }
+    }
+
+    /// Registers a reader for the plugin `id` when attached to objects of the `parent` type.
+    ///
+    /// The plugin gets stored in the `PluginList` of the owning object, and can then be retrieved
+    /// as a `T` from it (e.g. `geometry.plugin::<T>()`). Any previosly registered reader for the
+    /// same plugin and parent is replaced.
+    pub fn register_plugin<T, F>(&mut self, parent: PluginParent, id: u32, f: F)
+                                where T: Any + Send + Sync,
+                                      F: Fn(&SectionBuf) -> Result<T> + Send + Sync + 'static {
+        let handler = move |buf: &SectionBuf| f(buf).map(|val| Box::new(val) as Box<Any + Send + Sync>);
+        self.plugins.insert((parent, id), Box::new(handler));
+    }
+
+    /// Unregisters the reader for the plugin `id` attached to objects of the `parent` type.
+    ///
+    /// Returns whether there was such a reader registered.
+    pub fn unregister_plugin(&mut self, parent: PluginParent, id: u32) -> bool {
+        self.plugins.remove(&(parent, id)).is_some()
+    }
+
+    /// Sets how the reader reacts to recoverable problems, `Strictness::Strict` by default.
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.strictness
+    }
+
+    /// Sets the maximum bytes a single buffer sized by the stream data may take.
+    ///
+    /// Streams asking for more fail with `Error::AllocationLimit`, the default being
+    /// `DEFAULT_ALLOC_LIMIT`.
+    pub fn set_alloc_limit(&mut self, limit: u64) {
+        self.alloc_limit = limit;
+    }
+
+    pub fn alloc_limit(&self) -> u64 {
+        self.alloc_limit
+    }
+
+    /// Sets the default byte order of the struct payloads in the streams, little-endian by default.
+    ///
+    /// The byte order of the well known top-level sections, such as clumps and texture
+    /// dictionaries, is detected by the stream itself, so this only applies to the other ones.
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    /// Gets the recoverable problems found while reading streams.
+    ///
+    /// Sections not fully read are always recorded here, while other problems are recorded only
+    /// with `Strictness::Lenient`, being errors otherwise.
+    pub fn diagnostics(&self) -> &[Error] {
+        &self.diagnostics
+    }
+
+    /// Takes the recoverable problems found while reading streams, clearing them.
+    pub fn take_diagnostics(&mut self) -> Vec<Error> {
+        mem::replace(&mut self.diagnostics, Vec::new())
+    }
+
+    /// Reads a raw plugin chunk with the registered reader, or `None` if there's no such reader.
+    fn read_plugin(&self, parent: PluginParent, buf: &SectionBuf) -> Option<Result<Box<Any + Send + Sync>>> {
+        self.plugins.get(&(parent, buf.header.id)).map(|handler| (**handler)(buf))
+    }
 }
 
 pub struct Stream<'a, R> where R: ReadExt {
     inner: R,
     rw: &'a mut Instance,
+    pos: u64,                   // current offset in the inner reader
+    limits: Vec<u64>,           // end offsets of the bounded sections being read
+    endianness: Endianness,     // byte order of the top-level section being read
+    path: Vec<PathEntry>,       // sections being read, the first one being the stream itself
+}
+
+/// Section being read off a `Stream`.
+///
+/// Entries are popped lazily, only once a header is read past the end (or before the start) of
+/// them, so the path may contain already finished sections, see `Stream::path_at`.
+#[derive(Debug)]
+struct PathEntry {
+    id: u32,
+    start: u64,
+    end: u64,
+    index: usize,               // index among the sibling sections with the same id
+    childs: Vec<(u32, u64)>,    // id and start of the child sections seen so far
 }
 
 impl<'a, R: ReadExt> Stream<'a, R> {
-    pub fn new(inner: R, rw: &'a mut Instance) -> Stream<'a, R> {
+    pub fn new(mut inner: R, rw: &'a mut Instance) -> Stream<'a, R> {
+        let pos = inner.seek(SeekFrom::Current(0)).unwrap_or(0);
+        let endianness = rw.endianness;
         Stream {
             inner: inner,
             rw: rw,
+            pos: pos,
+            limits: Vec::new(),
+            endianness: endianness,
+            path: vec![PathEntry { id: 0, start: 0, end: u64::MAX, index: 0, childs: Vec::new() }],
         }
     }
 
     pub fn into_inner(self) -> R {
         self.inner
     }
+
+    /// Gets the current offset in the underlying reader.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Gets the number of bytes left in the section being read, if any.
+    pub fn remaining(&self) -> Option<u64> {
+        self.limits.last().map(|&end| end.saturating_sub(self.pos))
+    }
+
+    /// Validates a count read off the stream, returning it as an `usize`.
+    ///
+    /// The `count` elements, taking at least `elem_size` bytes each on the stream, must fit in
+    /// what's left of the section being read, as well as in the allocation limit of the `Instance`.
+    pub fn check_count(&self, count: u64, elem_size: u64) -> Result<usize> {
+        let size = match count.checked_mul(elem_size) {
+            Some(size) => size,
+            None => return Err(self.annotate(Error::InvalidCount(count))),
+        };
+        if self.remaining().map(|remaining| size > remaining).unwrap_or(false) {
+            return Err(self.annotate(Error::InvalidCount(count)));
+        }
+        if size > self.rw.alloc_limit {
+            return Err(self.annotate(Error::AllocationLimit { size: size, limit: self.rw.alloc_limit }));
+        }
+        Ok(count as usize)
+    }
+
+    /// Gets the byte order of the struct payloads in the top-level section being read.
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    /// Reads an `u16` in the byte order of this stream.
+    pub fn read_u16_ordered(&mut self) -> Result<u16> {
+        Ok(try!(match self.endianness {
+            Endianness::Little => ReadBytesExt::read_u16::<LittleEndian>(self),
+            Endianness::Big => ReadBytesExt::read_u16::<BigEndian>(self),
+        }))
+    }
+
+    /// Reads an `i16` in the byte order of this stream.
+    pub fn read_i16_ordered(&mut self) -> Result<i16> {
+        Ok(try!(match self.endianness {
+            Endianness::Little => ReadBytesExt::read_i16::<LittleEndian>(self),
+            Endianness::Big => ReadBytesExt::read_i16::<BigEndian>(self),
+        }))
+    }
+
+    /// Reads an `u32` in the byte order of this stream.
+    pub fn read_u32_ordered(&mut self) -> Result<u32> {
+        Ok(try!(match self.endianness {
+            Endianness::Little => ReadBytesExt::read_u32::<LittleEndian>(self),
+            Endianness::Big => ReadBytesExt::read_u32::<BigEndian>(self),
+        }))
+    }
+
+    /// Reads an `i32` in the byte order of this stream.
+    pub fn read_i32_ordered(&mut self) -> Result<i32> {
+        Ok(try!(match self.endianness {
+            Endianness::Little => ReadBytesExt::read_i32::<LittleEndian>(self),
+            Endianness::Big => ReadBytesExt::read_i32::<BigEndian>(self),
+        }))
+    }
+
+    /// Reads a `f32` in the byte order of this stream.
+    pub fn read_f32_ordered(&mut self) -> Result<f32> {
+        Ok(try!(match self.endianness {
+            Endianness::Little => ReadBytesExt::read_f32::<LittleEndian>(self),
+            Endianness::Big => ReadBytesExt::read_f32::<BigEndian>(self),
+        }))
+    }
+
+    /// Reads `size` bytes, validating the size first, see `check_count`.
+    pub fn read_bytes(&mut self, size: usize) -> Result<Vec<u8>> {
+        let size = try!(self.check_count(size as u64, 1));
+        ReadExt::read_bytes(self, size)
+    }
+
+    /// Calls `f` with reads bounded to the specified end offset.
+    ///
+    /// Reads past the end of the bound behave as if the stream ended there.
+    fn read_bounded<T, F>(&mut self, end: u64, f: F) -> Result<T>
+                            where F: FnOnce(&mut Stream<R>) -> Result<T> {
+        let end = match self.limits.last() {
+            Some(&outer_end) if outer_end < end => outer_end,
+            _ => end,
+        };
+        self.limits.push(end);
+        let result = f(self);
+        self.limits.pop();
+        result
+    }
+
+    /// Calls `f` to read the contents of the section `id` ending at the specified offset.
+    ///
+    /// Reading past the end of the section is an error, while leaving part of it unread is
+    /// recorded as a warning on the `Instance`, the rest of the section being skipped.
+    fn read_section<T, F>(&mut self, id: u32, end: u64, f: F) -> Result<T>
+                            where F: FnOnce(&mut Stream<R>) -> Result<T> {
+        match self.read_bounded(end, f) {
+            Ok(_) if self.pos > end => {
+                Err(self.annotate_at(Error::SectionOverflow { id: id }, end))
+            },
+            Ok(val) => {
+                if self.pos < end {
+                    let unread = end - self.pos;
+                    let warning = self.annotate(Error::SectionUnderflow { id: id, unread: unread });
+                    self.rw.diagnostics.push(warning);
+                    try!(self.seek(SeekFrom::Start(end)));
+                }
+                Ok(val)
+            },
+            Err(ref err) if is_eof(err) && self.pos >= end => {
+                Err(self.annotate_at(Error::SectionOverflow { id: id }, end))
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Handles a recoverable problem according to the strictness of the `Instance`.
+    ///
+    /// The problem is recorded as a diagnostic when lenient, so the caller can go on with a
+    /// best-effort result, or returned as an error when strict.
+    pub fn tolerate(&mut self, err: Error) -> Result<()> {
+        let err = self.annotate(err);
+        match self.rw.strictness {
+            Strictness::Lenient => {
+                self.rw.diagnostics.push(err);
+                Ok(())
+            },
+            Strictness::Strict => Err(err),
+        }
+    }
+
+    /// Gets the path of the sections containing the specified offset.
+    ///
+    /// The path looks like `Clump/GeometryList/Geometry[3]/MaterialList/Material[1]`.
+    pub fn path_at(&self, offset: u64) -> String {
+        let mut path = String::new();
+        for entry in self.path[1..].iter().filter(|e| e.start < offset && offset <= e.end) {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            match section_name(entry.id) {
+                Some(name) => path.push_str(name),
+                None => path.push_str(&format!("{:#x}", entry.id)),
+            }
+            if entry.index > 0 || is_list_item(entry.id) {
+                path.push_str(&format!("[{}]", entry.index));
+            }
+        }
+        path
+    }
+
+    /// Wraps an error with the current section path and offset.
+    ///
+    /// Errors already carrying a context are returned untouched.
+    pub fn annotate(&self, err: Error) -> Error {
+        let offset = self.pos;
+        self.annotate_at(err, offset)
+    }
+
+    /// Wraps an error with the section path and offset where it happened.
+    ///
+    /// Errors already carrying a context are returned untouched.
+    pub fn annotate_at(&self, err: Error, offset: u64) -> Error {
+        match err {
+            err @ Error::Context { .. } => err,
+            err => Error::Context {
+                path: self.path_at(offset),
+                offset: offset,
+                cause: Box::new(err),
+            },
+        }
+    }
+
+    /// Detects the byte order of the top-level section whose header was just read.
+    ///
+    /// Well known sections are told apart by the platform or device id of textures, otherwise by
+    /// counts in their payload, which read as greater values in the wrong byte order. Zero counts
+    /// say nothing, so the next count is tried; any other section takes the `Instance` default.
+    fn detect_endianness(&mut self, header: &Header) -> Result<()> {
+        self.endianness = self.rw.endianness;
+
+        match header.id {
+            0x000B | 0x0010 | 0x0015 | 0x0016 | 0x001B | 0x002B => {},
+            _ => return Ok(()),
+        }
+
+        // Peeks as much of the payload as available, sections too short to tell are left for
+        // the reader itself to fail on.
+        let start = self.pos;
+        let mut buf = [0u8; 64];
+        let mut len = 0;
+        while len < buf.len() {
+            match io::Read::read(self, &mut buf[len..]) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => len += n,
+            }
+        }
+        try!(self.seek(SeekFrom::Start(start)));
+
+        // Reads the little-endian word at `offset` of the payload, if peeked. Words at 12 onwards
+        // are the contents of the Struct most sections start with.
+        let word = |offset: usize| -> Option<u32> {
+            if offset + 4 <= len {
+                (&buf[offset..offset + 4]).read_u32::<LittleEndian>().ok()
+            } else {
+                None
+            }
+        };
+
+        let is_big = match header.id {
+            0x0015 => word(12).map(|platform| platform.swap_bytes() == 6),     // GameCube
+            0x0016 if header.version >= 0x1803FFFF => {                         // GameCube device
+                word(12).map(|value| ((value >> 16) as u16).swap_bytes() == 3)
+            },
+            0x000B => {
+                // Older worlds store surface properties before the counts.
+                let counts = if word(4).unwrap_or(0) >= 76 { 12 + 28 } else { 12 + 16 };
+                order_of_counts(&[word(counts + 12), word(counts + 4), word(counts)])
+            },
+            0x0010 => {
+                // The counts of atomics, and on newer streams lights and cameras, are followed by
+                // the Frame List, so clumps with none of those go on to its count of frames.
+                let size = word(4).unwrap_or(0) as usize;
+                let count = |i: usize| if 4 * (i + 1) <= size { word(12 + 4 * i) } else { None };
+                let num_frames = size.checked_add(12 + 24).and_then(&word);
+                order_of_counts(&[count(0), count(1), count(2), num_frames])
+            },
+            0x001B => order_of_counts(&[word(0)]),                              // version 0x100
+            _ => order_of_counts(&[word(12)]),
+        };
+        self.endianness = match is_big {
+            Some(true) => Endianness::Big,
+            Some(false) => Endianness::Little,
+            None => self.rw.endianness,
+        };
+        Ok(())
+    }
+
+    /// Tracks the section whose header was just read at the specified offset.
+    fn enter_section(&mut self, header: &Header, offset: u64) {
+        while self.path.len() > 1 {
+            let finished = {
+                let last = &self.path[self.path.len() - 1];
+                last.end <= offset || last.start >= offset
+            };
+            if !finished {
+                break;
+            }
+            self.path.pop();
+        }
+
+        let index = {
+            let parent = self.path.last_mut().unwrap();     // the stream entry is never popped
+            let index = parent.childs.iter().filter(|&&(id, start)| {
+                id == header.id && start < offset
+            }).count();
+            if !parent.childs.contains(&(header.id, offset)) {
+                parent.childs.push((header.id, offset));
+            }
+            index
+        };
+
+        self.path.push(PathEntry {
+            id: header.id,
+            start: offset,
+            end: offset + 12 + header.size as u64,
+            index: index,
+            childs: Vec::new(),
+        });
+    }
+}
+
+/// Tells whether little-endian reads of counts were stored in big-endian, by the first count
+/// that reads differently in each byte order.
+///
+/// Counts are smaller than their bytes swapped, so the smaller reading is the right one.
+fn order_of_counts(counts: &[Option<u32>]) -> Option<bool> {
+    counts.iter().filter_map(|&count| count).find(|&count| count != count.swap_bytes())
+                 .map(|count| count.swap_bytes() < count)
+}
+
+/// Checks whether an error comes from an unexpected end of stream.
+fn is_eof(err: &Error) -> bool {
+    match *err {
+        Error::IoError(byteorder::Error::UnexpectedEOF) => true,
+        Error::Context { ref cause, .. } => is_eof(cause),
+        _ => false,
+    }
+}
+
+/// Gets the name of a well known section.
+fn section_name(id: u32) -> Option<&'static str> {
+    match id {
+        0x0001 => Some("Struct"),
+        0x0002 => Some("String"),
+        0x0003 => Some("Extension"),
+        0x0006 => Some("Texture"),
+        0x0007 => Some("Material"),
+        0x0008 => Some("MaterialList"),
+        0x0009 => Some("AtomicSector"),
+        0x000A => Some("PlaneSector"),
+        0x000B => Some("World"),
+        0x000E => Some("FrameList"),
+        0x000F => Some("Geometry"),
+        0x0010 => Some("Clump"),
+        0x0012 => Some("Light"),
+        0x0014 => Some("Atomic"),
+        0x0015 => Some("TexNative"),
+        0x0016 => Some("TexDictionary"),
+        0x001A => Some("GeometryList"),
+        0x001B => Some("Animation"),
+        0x002B => Some("UvAnimDictionary"),
+        0x0116 => Some("SkinPlg"),
+        0x011E => Some("HAnimPlg"),
+        0x0135 => Some("MaterialUvAnimPlg"),
+        0x050E => Some("BinMeshPlg"),
+        0x0510 => Some("NativeDataPlg"),
+        0x0253F2FE => Some("NodeNamePlg"),
+        _ => None,
+    }
+}
+
+/// Checks whether a section is usually one of many siblings, and so always gets its index on paths.
+fn is_list_item(id: u32) -> bool {
+    match id {
+        0x0007 | 0x000F | 0x0012 | 0x0014 | 0x0015 | 0x001B => true,
+        _ => false,
+    }
 }
 
 impl<'a, R: ReadExt> io::Read for Stream<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
+        let len = match self.remaining() {
+            Some(remaining) if remaining < buf.len() as u64 => remaining as usize,
+            _ => buf.len(),
+        };
+        let n = try!(self.inner.read(&mut buf[..len]));
+        self.pos += n as u64;
+        Ok(n)
     }
 }
 
 impl<'a, R: ReadExt + io::BufRead> io::BufRead for Stream<'a, R> {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        self.inner.fill_buf()
+        let remaining = self.remaining();
+        let buf = try!(self.inner.fill_buf());
+        match remaining {
+            Some(remaining) if remaining < buf.len() as u64 => Ok(&buf[..remaining as usize]),
+            _ => Ok(buf),
+        }
     }
     fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
         self.inner.consume(amt)
     }
 }
 impl<'a, R: ReadExt> io::Seek for Stream<'a, R> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        self.inner.seek(pos)
+        let pos = try!(self.inner.seek(pos));
+        self.pos = pos;
+        Ok(pos)
     }
 }
 
 pub trait ReadExt : Seek + byteorder::ReadBytesExt {
     // TODO common function read_bytes
     fn read_bytes(&mut self, size: usize) -> Result<Vec<u8>> {
-        unsafe {
-            let mut v = Vec::with_capacity(size);
-            v.set_len(size);
-            Ok(try!(self.read_full(&mut v[..]).map(|_| v)))
-        }
+        let mut v = vec![0u8; size];
+        try!(self.read_full(&mut v[..]));
+        Ok(v)
     }
 
     // TODO common function read_full
@@ -269,3 +870,80 @@ pub trait ReadExt : Seek + byteorder::ReadBytesExt {
 }
 
 impl<T: Seek + byteorder::ReadBytesExt> ReadExt for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Instance, Stream, SectionBuf, Endianness};
+    use std::io::Cursor;
+
+    /// Pushes a word in little-endian, as in section headers.
+    fn push_le(v: &mut Vec<u8>, x: u32) {
+        v.extend((0..4).map(|i| (x >> (i * 8)) as u8));
+    }
+
+    /// Pushes a word in the specified byte order, as in struct payloads.
+    fn push(v: &mut Vec<u8>, x: u32, endianness: Endianness) {
+        match endianness {
+            Endianness::Little => push_le(v, x),
+            Endianness::Big => push_le(v, x.swap_bytes()),
+        }
+    }
+
+    fn push_header(v: &mut Vec<u8>, id: u32, size: u32) {
+        push_le(v, id);
+        push_le(v, size);
+        push_le(v, 0x1803FFFF);
+    }
+
+    /// Reads the top-level header of `data`, giving the byte order detected for it.
+    fn detect(data: Vec<u8>) -> Endianness {
+        let mut rw = Instance::new();
+        let mut rws = Stream::new(Cursor::new(data), &mut rw);
+        SectionBuf::read_header(&mut rws).unwrap();
+        rws.endianness()
+    }
+
+    /// Builds the start of a world whose root is not an atomic sector.
+    fn world(endianness: Endianness) -> Vec<u8> {
+        let mut data = Vec::new();
+        push_header(&mut data, 0x000B, 12 + 64);
+        push_header(&mut data, 0x0001, 64);
+        push(&mut data, 0, endianness);             // root is world sector
+        for _ in (0..3) {
+            push(&mut data, 0, endianness);         // origin
+        }
+        for &count in [12, 30, 1, 2, 0, 0x40000000].iter() {
+            push(&mut data, count, endianness);     // counts, sector size and flags
+        }
+        for _ in (0..6) {
+            push(&mut data, 0, endianness);         // bounding box
+        }
+        data
+    }
+
+    /// Builds the start of a clump with no atomics, lights nor cameras and two frames.
+    fn empty_clump(endianness: Endianness) -> Vec<u8> {
+        let mut data = Vec::new();
+        push_header(&mut data, 0x0010, 0);
+        push_header(&mut data, 0x0001, 12);
+        for _ in (0..3) {
+            push(&mut data, 0, endianness);
+        }
+        push_header(&mut data, 0x000E, 0);
+        push_header(&mut data, 0x0001, 4 + 2 * 56);
+        push(&mut data, 2, endianness);
+        data
+    }
+
+    #[test]
+    fn world_endianness() {
+        assert_eq!(detect(world(Endianness::Little)), Endianness::Little);
+        assert_eq!(detect(world(Endianness::Big)), Endianness::Big);
+    }
+
+    #[test]
+    fn empty_clump_endianness() {
+        assert_eq!(detect(empty_clump(Endianness::Little)), Endianness::Little);
+        assert_eq!(detect(empty_clump(Endianness::Big)), Endianness::Big);
+    }
+}
@@ -0,0 +1,471 @@
+use byteorder::{ReadBytesExt, LittleEndian};
+use super::{Section, Struct, Result, Error, ReadExt, Stream};
+
+use super::{Vec3, Uv, Rgba, MeshHeader, GeometryFlags, PRELIT, NORMALS};
+use std::io::Cursor;
+use std::u16;
+
+/// Native Data PLG.
+///
+/// Geometries instanced for a console keep their vertex data in this plugin, in the format the
+/// hardware consumes, instead of in the Geometry structure. The data is kept raw here, see
+/// `NativeDataPlg::decode`.
+#[derive(Debug)]
+pub struct NativeDataPlg {
+    /// Platform the data was instanced for, 4 for the PS2 and 5 for the Xbox.
+    pub platform: u32,
+    /// Data after the platform id.
+    pub data: Vec<u8>,
+}
+
+/// Vertex data of a geometry decoded off a `NativeDataPlg`.
+#[derive(Debug)]
+pub struct NativeData {
+    pub verts: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub colors: Option<Vec<Rgba>>,
+    /// Texture coordinate sets, one element for each set and then one for each vertex.
+    pub uv_sets: Vec<Vec<Uv>>,
+    /// Whether the indices form triangle strips (or lists).
+    pub is_tri_strip: bool,
+    /// Vertex indices of each mesh, in the order of the `MeshHeader`.
+    pub indices: Vec<Vec<u16>>,
+}
+
+impl Section for NativeDataPlg {
+    fn section_id() -> u32 { 0x0510 }
+}
+
+impl NativeDataPlg {
+    /// Reads a Native Data PLG off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<NativeDataPlg> {
+        let _header = try!(Self::read_header(rws));
+        let (platform, data) = try!(Struct::read_up(rws, |rws| {
+            let platform = try!(rws.read_u32_ordered());
+            let size = rws.remaining().unwrap_or(0) as usize;
+            Ok((platform, try!(rws.read_bytes(size))))
+        }));
+        Ok(NativeDataPlg {
+            platform: platform,
+            data: data,
+        })
+    }
+
+    /// Decodes the vertex data of a geometry with the specified flags and number of texture
+    /// coordinate sets, whose meshes are `meshlist`.
+    pub fn decode(&self, flags: GeometryFlags, num_uv: usize, meshlist: &MeshHeader) -> Result<NativeData> {
+        match self.platform {
+            4 => decode_ps2(&self.data, num_uv, meshlist),
+            5 => decode_xbox(&self.data, flags, num_uv, meshlist),
+            platform => Err(Error::UnsupportedPlatform(platform)),
+        }
+    }
+}
+
+fn invalid(what: &str) -> Error {
+    Error::Other(format!("invalid native data: {}", what))
+}
+
+/// Gets `len` bytes of `data` starting at `start`, failing with `what` if out of bounds.
+fn slice<'a>(data: &'a [u8], start: usize, len: usize, what: &str) -> Result<&'a [u8]> {
+    match start.checked_add(len) {
+        Some(end) if end <= data.len() => Ok(&data[start..end]),
+        _ => Err(invalid(what)),
+    }
+}
+
+/// Vertices of a PS2 batch, as unpacked into the vector unit memory.
+#[derive(Default)]
+struct Batch {
+    verts: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    colors: Vec<Rgba>,
+    uv_sets: Vec<Vec<Uv>>,
+}
+
+/// Decodes the DMA chains of each mesh of a PS2 geometry.
+///
+/// Each mesh is a chain of DMA tags whose VIF codes unpack the vertex attributes of a batch
+/// into the vector unit, the batch being drawn as a triangle strip or list, as the mesh header
+/// says, once the microprogram is started. The attributes are recognized by their unpack format.
+fn decode_ps2(data: &[u8], num_uv: usize, meshlist: &MeshHeader) -> Result<NativeData> {
+    let mut result = NativeData {
+        verts: Vec::new(),
+        normals: Some(Vec::new()),
+        colors: Some(Vec::new()),
+        uv_sets: (0..num_uv).map(|_| Vec::new()).collect(),
+        is_tri_strip: meshlist.is_tri_strip,
+        indices: Vec::with_capacity(meshlist.meshes.len()),
+    };
+    let (mut has_normals, mut has_colors) = (false, false);
+
+    let mut f = Cursor::new(data);
+    for _ in (0..meshlist.meshes.len()) {
+        let size = try!(f.read_u32::<LittleEndian>()) as usize;
+        let _no_pointers = try!(f.read_u32::<LittleEndian>());
+        let start = f.position() as usize;
+        let chain = try!(slice(data, start, size, "mesh data out of bounds"));
+        f.set_position((start + size) as u64);
+
+        let mut indices = Vec::new();
+        for batch in try!(read_dma_chain(chain)) {
+            if batch.verts.is_empty() {
+                continue;
+            }
+            let base = result.verts.len();
+            let count = batch.verts.len();
+            if base + count > u16::MAX as usize + 1 {
+                return Err(invalid("too many vertices"));
+            }
+
+            // Batches are independent strips, joined by degenerate triangles keeping the winding.
+            if meshlist.is_tri_strip {
+                if let Some(&last) = indices.last() {
+                    indices.push(last);
+                    if indices.len() % 2 == 1 {
+                        indices.push(last);
+                    }
+                    indices.push(base as u16);
+                }
+            }
+            indices.extend((base..base + count).map(|i| i as u16));
+
+            has_normals = has_normals || !batch.normals.is_empty();
+            has_colors = has_colors || !batch.colors.is_empty();
+            let normals = result.normals.as_mut().unwrap();
+            normals.extend((0..count).map(|i| batch.normals.get(i).cloned().unwrap_or(Vec3(0.0, 0.0, 1.0))));
+            let colors = result.colors.as_mut().unwrap();
+            colors.extend((0..count).map(|i| batch.colors.get(i).cloned().unwrap_or(Rgba(255, 255, 255, 255))));
+            for (set, uvs) in result.uv_sets.iter_mut().enumerate() {
+                let batch_uvs = batch.uv_sets.get(set);
+                uvs.extend((0..count).map(|i| {
+                    batch_uvs.and_then(|uvs| uvs.get(i)).cloned().unwrap_or(Uv(0.0, 0.0))
+                }));
+            }
+            result.verts.extend(batch.verts.into_iter());
+        }
+        result.indices.push(indices);
+    }
+
+    if !has_normals { result.normals = None; }
+    if !has_colors { result.colors = None; }
+    Ok(result)
+}
+
+/// Walks a DMA chain, returning the batches unpacked by its VIF codes.
+fn read_dma_chain(chain: &[u8]) -> Result<Vec<Batch>> {
+    let mut batches = Vec::new();
+    let mut batch = Batch::default();
+    let mut pos = 0;
+
+    while pos + 16 <= chain.len() {
+        let mut f = Cursor::new(&chain[pos..pos + 16]);
+        let tag = try!(f.read_u32::<LittleEndian>());
+        let addr = try!(f.read_u32::<LittleEndian>()) as usize;
+        let qwc = (tag & 0xFFFF) as usize;
+        let id = (tag >> 28) & 0x7;
+
+        // The VIF codes in the upper half of the tag come before the transferred data.
+        let mut vif = chain[pos + 8..pos + 16].to_vec();
+        let (data_start, next) = match id {
+            // refe, ref, refs: the data is somewhere else, addressed in quadwords.
+            0 | 3 | 4 => (addr * 16, pos + 16),
+            // cnt, ret, end: the data follows the tag.
+            1 | 6 | 7 => (pos + 16, pos + 16 + qwc * 16),
+            _ => return Err(invalid("unsupported DMA tag")),
+        };
+        let data = try!(slice(chain, data_start, qwc * 16, "DMA transfer out of bounds"));
+        vif.extend(data.iter().cloned());
+        try!(run_vif(&vif, &mut batch, &mut batches));
+
+        match id {
+            0 | 6 | 7 => break,
+            _ => pos = next,
+        }
+    }
+
+    if !batch.verts.is_empty() {
+        batches.push(batch);
+    }
+    Ok(batches)
+}
+
+/// Runs the VIF codes in `vif`, unpacking vertex attributes into `batch`.
+///
+/// Starting the microprogram finishes the batch, pushing it into `batches`.
+fn run_vif(vif: &[u8], batch: &mut Batch, batches: &mut Vec<Batch>) -> Result<()> {
+    let mut f = Cursor::new(vif);
+    while (f.position() as usize) + 4 <= vif.len() {
+        let code = try!(f.read_u32::<LittleEndian>());
+        let cmd = (code >> 24) & 0x7F;
+        let num = (code >> 16) & 0xFF;
+        let imm = code & 0xFFFF;
+        let skip = match cmd {
+            // STMASK
+            0x20 => 4,
+            // STROW, STCOL
+            0x30 | 0x31 => 16,
+            // MPG
+            0x4A => (if num == 0 { 256 } else { num }) as u64 * 8,
+            // DIRECT, DIRECTHL
+            0x50 | 0x51 => (if imm == 0 { 65536 } else { imm }) as u64 * 16,
+            // MSCAL, MSCALF, MSCNT: the batch gets drawn.
+            0x14 | 0x15 | 0x17 => {
+                let done = ::std::mem::replace(batch, Batch::default());
+                if !done.verts.is_empty() {
+                    batches.push(done);
+                }
+                0
+            },
+            cmd if (cmd & 0x60) == 0x60 => {
+                try!(unpack(&mut f, cmd, num, (imm & 0x4000) != 0, batch));
+                0
+            },
+            _ => 0,
+        };
+        let position = f.position() + skip;
+        f.set_position(position);
+    }
+    Ok(())
+}
+
+/// Reads the data of an UNPACK code, recognizing the attribute by its format.
+fn unpack(f: &mut Cursor<&[u8]>, cmd: u32, num: u32, unsigned: bool, batch: &mut Batch) -> Result<()> {
+    let num = (if num == 0 { 256 } else { num }) as usize;
+    let components = ((cmd >> 2) & 0x3) as usize + 1;
+    let bits = 32 >> (cmd & 0x3);
+    let size = (num * components * bits + 31) / 32 * 4;
+    let start = f.position();
+
+    match (components, bits, unsigned) {
+        // V3-32: positions.
+        (3, 32, _) => for _ in (0..num) {
+            batch.verts.push(Vec3(try!(f.read_f32::<LittleEndian>()),
+                                  try!(f.read_f32::<LittleEndian>()),
+                                  try!(f.read_f32::<LittleEndian>())));
+        },
+        // V2-32: texture coordinates.
+        (2, 32, _) => {
+            let mut uvs = Vec::with_capacity(num);
+            for _ in (0..num) {
+                uvs.push(Uv(try!(f.read_f32::<LittleEndian>()), try!(f.read_f32::<LittleEndian>())));
+            }
+            batch.uv_sets.push(uvs);
+        },
+        // V2-16: fixed point texture coordinates.
+        (2, 16, _) => {
+            let mut uvs = Vec::with_capacity(num);
+            for _ in (0..num) {
+                uvs.push(Uv(try!(f.read_i16::<LittleEndian>()) as f32 / 4096.0,
+                            try!(f.read_i16::<LittleEndian>()) as f32 / 4096.0));
+            }
+            batch.uv_sets.push(uvs);
+        },
+        // V4-8 unsigned: prelight colors.
+        (4, 8, true) => for _ in (0..num) {
+            batch.colors.push(Rgba(try!(f.read_u8()), try!(f.read_u8()),
+                                   try!(f.read_u8()), try!(f.read_u8())));
+        },
+        // V3-8 and V4-8 signed: normals.
+        (3, 8, _) | (4, 8, false) => for _ in (0..num) {
+            let (x, y, z) = (try!(f.read_i8()), try!(f.read_i8()), try!(f.read_i8()));
+            if components == 4 {
+                let _pad = try!(f.read_i8());
+            }
+            batch.normals.push(Vec3(x as f32 / 127.0, y as f32 / 127.0, z as f32 / 127.0));
+        },
+        // Anything else (batch headers, skinning weights...) is not needed.
+        _ => {},
+    }
+
+    f.set_position(start + size as u64);
+    Ok(())
+}
+
+/// Decodes the vertex and index buffers of an Xbox geometry.
+///
+/// The data starts with a header describing the meshes and holding their indices, followed by
+/// the interleaved vertex buffer, whose layout is given by the geometry flags.
+fn decode_xbox(data: &[u8], flags: GeometryFlags, num_uv: usize, meshlist: &MeshHeader) -> Result<NativeData> {
+    let mut f = Cursor::new(data);
+    let header_size = try!(f.read_u32::<LittleEndian>()) as usize;
+    let header = try!(slice(data, 4, header_size, "header out of bounds"));
+
+    let mut h = Cursor::new(header);
+    let _unused = try!(h.read_u32::<LittleEndian>());
+    let _serial = try!(h.read_u16::<LittleEndian>());
+    let num_meshes = try!(h.read_u16::<LittleEndian>()) as usize;
+    let prim_type = try!(h.read_u32::<LittleEndian>());
+    let num_verts = try!(h.read_u32::<LittleEndian>()) as usize;
+    let stride = try!(h.read_u32::<LittleEndian>()) as usize;
+
+    if num_meshes != meshlist.meshes.len() {
+        return Err(invalid("mesh count mismatch"));
+    }
+
+    // Meshes are described after the header, their indices following all the descriptions.
+    let mut counts = Vec::with_capacity(num_meshes);
+    for i in (0..num_meshes) {
+        h.set_position((0x24 + i * 0x18 + 4) as u64);
+        let _min_vert = try!(h.read_u32::<LittleEndian>());
+        let _num_verts = try!(h.read_u32::<LittleEndian>());
+        counts.push(try!(h.read_u32::<LittleEndian>()) as usize);
+    }
+    h.set_position((0x24 + num_meshes * 0x18) as u64);
+    let mut indices = Vec::with_capacity(num_meshes);
+    for &count in counts.iter() {
+        if count > header.len().saturating_sub(h.position() as usize) / 2 {
+            return Err(invalid("index count out of bounds"));
+        }
+        let mut v = Vec::with_capacity(count);
+        for _ in (0..count) {
+            v.push(try!(h.read_u16::<LittleEndian>()));
+        }
+        indices.push(v);
+    }
+
+    let has_normals = flags.contains(NORMALS);
+    let has_colors = flags.contains(PRELIT);
+    let min_stride = 12 + if has_normals { 4 } else { 0 } + if has_colors { 4 } else { 0 } + num_uv * 8;
+    if stride < min_stride {
+        return Err(invalid("vertex stride too small"));
+    }
+    let vbo_start = 4 + header_size;
+    let vbo_size = try!(num_verts.checked_mul(stride).ok_or_else(|| invalid("vertex buffer too big")));
+    let vbo = try!(slice(data, vbo_start, vbo_size, "vertex buffer out of bounds"));
+
+    let mut result = NativeData {
+        verts: Vec::with_capacity(num_verts),
+        normals: if has_normals { Some(Vec::with_capacity(num_verts)) } else { None },
+        colors: if has_colors { Some(Vec::with_capacity(num_verts)) } else { None },
+        uv_sets: (0..num_uv).map(|_| Vec::with_capacity(num_verts)).collect(),
+        // The Xbox D3DPT_TRIANGLESTRIP, otherwise D3DPT_TRIANGLELIST.
+        is_tri_strip: prim_type == 6,
+        indices: indices,
+    };
+
+    for vertex in vbo.chunks(stride) {
+        let mut v = Cursor::new(vertex);
+        result.verts.push(Vec3(try!(v.read_f32::<LittleEndian>()),
+                               try!(v.read_f32::<LittleEndian>()),
+                               try!(v.read_f32::<LittleEndian>())));
+        if let Some(ref mut normals) = result.normals {
+            // Packed into 11, 11 and 10 signed bits.
+            let packed = try!(v.read_u32::<LittleEndian>());
+            let x = ((packed << 21) as i32 >> 21) as f32 / 1023.0;
+            let y = ((packed << 10) as i32 >> 21) as f32 / 1023.0;
+            let z = (packed as i32 >> 22) as f32 / 511.0;
+            normals.push(Vec3(x, y, z));
+        }
+        if let Some(ref mut colors) = result.colors {
+            // D3DCOLOR, stored as BGRA.
+            let (b, g, r, a) = (try!(v.read_u8()), try!(v.read_u8()), try!(v.read_u8()), try!(v.read_u8()));
+            colors.push(Rgba(r, g, b, a));
+        }
+        for uvs in result.uv_sets.iter_mut() {
+            uvs.push(Uv(try!(v.read_f32::<LittleEndian>()), try!(v.read_f32::<LittleEndian>())));
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_vif, decode_xbox};
+    use super::super::{Vec3, Rgba, Material, Mesh, MeshHeader, SurfaceProperties, PluginList, NORMALS};
+    use std::sync::Arc;
+
+    fn push_u32(v: &mut Vec<u8>, x: u32) {
+        v.extend((0..4).map(|i| (x >> (i * 8)) as u8));
+    }
+
+    fn push_f32(v: &mut Vec<u8>, x: f32) {
+        push_u32(v, unsafe { ::std::mem::transmute::<f32, u32>(x) });
+    }
+
+    fn pad_to(v: &mut Vec<u8>, len: usize) {
+        while v.len() < len {
+            v.push(0);
+        }
+    }
+
+    fn meshlist(is_tri_strip: bool) -> MeshHeader {
+        let material = Material {
+            texture: None,
+            color: Rgba(255, 255, 255, 255),
+            surf: SurfaceProperties { ambient: 1.0, specular: 1.0, diffuse: 1.0 },
+            plugins: PluginList::new(),
+            texture_plugins: PluginList::new(),
+        };
+        MeshHeader {
+            is_tri_strip: is_tri_strip,
+            total_indices: 3,
+            meshes: vec![Mesh { material: Arc::new(material), indices: vec![0, 1, 2] }],
+        }
+    }
+
+    #[test]
+    fn vif_unpack() {
+        let mut vif = Vec::new();
+        // UNPACK V3-32 of two positions.
+        push_u32(&mut vif, 0x68020000);
+        for &x in [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0].iter() {
+            push_f32(&mut vif, x);
+        }
+        // UNPACK V4-8 unsigned of two prelight colors.
+        push_u32(&mut vif, 0x6E024000);
+        vif.extend([10u8, 20, 30, 40, 50, 60, 70, 80].iter().cloned());
+        // UNPACK V3-8 signed of two normals, padded to a word.
+        push_u32(&mut vif, 0x6A020000);
+        vif.extend([127u8, 0, 0x81, 0, 127, 0, 0, 0].iter().cloned());
+        // MSCAL, drawing the batch.
+        push_u32(&mut vif, 0x14000000);
+
+        let mut batch = Default::default();
+        let mut batches = Vec::new();
+        run_vif(&vif, &mut batch, &mut batches).unwrap();
+
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.verts, vec![Vec3(1.0, 2.0, 3.0), Vec3(4.0, 5.0, 6.0)]);
+        assert_eq!(batch.colors, vec![Rgba(10, 20, 30, 40), Rgba(50, 60, 70, 80)]);
+        assert_eq!(batch.normals, vec![Vec3(1.0, 0.0, -1.0), Vec3(0.0, 1.0, 0.0)]);
+    }
+
+    #[test]
+    fn xbox_packed_normals() {
+        let mut header = Vec::new();
+        push_u32(&mut header, 0);               // unused
+        header.extend([0u8, 0, 1, 0].iter().cloned());     // serial, one mesh
+        push_u32(&mut header, 6);               // triangle strip
+        push_u32(&mut header, 1);               // vertices
+        push_u32(&mut header, 16);              // stride
+        pad_to(&mut header, 0x24 + 4);
+        push_u32(&mut header, 0);               // min vertex
+        push_u32(&mut header, 1);               // vertices of the mesh
+        push_u32(&mut header, 3);               // indices of the mesh
+        pad_to(&mut header, 0x24 + 0x18);
+        header.extend([0u8, 0, 0, 0, 0, 0].iter().cloned());
+
+        let mut data = Vec::new();
+        push_u32(&mut data, header.len() as u32);
+        data.extend(header.iter().cloned());
+        for &x in [1.0f32, 2.0, 3.0].iter() {
+            push_f32(&mut data, x);
+        }
+        push_u32(&mut data, 0x7FE00BFF);        // 1023, -1023 and 511
+
+        let native = decode_xbox(&data, NORMALS, 0, &meshlist(true)).unwrap();
+        assert!(native.is_tri_strip);
+        assert_eq!(native.indices, vec![vec![0, 0, 0]]);
+        assert_eq!(native.verts, vec![Vec3(1.0, 2.0, 3.0)]);
+        assert_eq!(native.normals, Some(vec![Vec3(1.0, -1.0, 1.0)]));
+
+        // Index counts past the end of the header are rejected before allocating.
+        let mut data = data.clone();
+        data[4 + 0x24 + 12] = 0xFF;
+        data[4 + 0x24 + 15] = 0x7F;
+        assert!(decode_xbox(&data, NORMALS, 0, &meshlist(true)).is_err());
+    }
+}
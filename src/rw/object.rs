@@ -0,0 +1,86 @@
+use super::{Section, Result, ReadExt, Stream, SectionBuf, Instance, is_eof};
+
+use super::{Clump, Geometry, GeometryList, Atomic, FrameList, TexDictionary};
+use super::{Animation, UvAnimDictionary, World};
+use std::sync::Arc;
+
+/// Object read off the top level of a RenderWare Stream.
+#[derive(Debug)]
+pub enum Object {
+    Clump(Clump),
+    World(World),
+    Geometry(Geometry),
+    /// Standalone atomic, holding its own geometry and attached to no frame.
+    Atomic(Arc<Atomic>),
+    FrameList(FrameList),
+    TexDictionary(Arc<TexDictionary>),
+    UvAnimDictionary(Arc<UvAnimDictionary>),
+    Animation(Animation),
+    /// Section of unknown kind, kept raw.
+    Unknown(SectionBuf),
+}
+
+impl Object {
+    /// Reads the object in the next top level section off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Object> {
+        let header = try!(SectionBuf::peek_header(rws));
+        Ok(match header.id {
+            id if id == Clump::section_id() => Object::Clump(try!(Clump::read(rws))),
+            id if id == World::section_id() => Object::World(try!(World::read(rws))),
+            id if id == Geometry::section_id() => Object::Geometry(try!(Geometry::read(rws))),
+            id if id == Atomic::section_id() => {
+                let atomic = try!(Atomic::read(rws, &FrameList::new(), &GeometryList(Vec::new())));
+                Object::Atomic(atomic)
+            },
+            id if id == FrameList::section_id() => Object::FrameList(try!(FrameList::read(rws))),
+            id if id == TexDictionary::section_id() => {
+                Object::TexDictionary(try!(TexDictionary::read(rws, "")))
+            },
+            id if id == UvAnimDictionary::section_id() => {
+                Object::UvAnimDictionary(try!(UvAnimDictionary::read(rws)))
+            },
+            id if id == Animation::section_id() => Object::Animation(try!(Animation::read(rws))),
+            _ => Object::Unknown(try!(SectionBuf::read(rws))),
+        })
+    }
+
+    /// Binds this object into the instance if it's a dictionary, so the objects read next get
+    /// their textures or animations from it.
+    ///
+    /// Returns whether anything got bound.
+    pub fn bind(&self, rw: &mut Instance) -> bool {
+        match *self {
+            Object::TexDictionary(ref dict) => rw.bind_dictionary(dict),
+            Object::UvAnimDictionary(ref dict) => rw.bind_uv_dictionary(dict),
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Reads all the top level objects off the RenderWare Stream, up to its end.
+///
+/// Files may hold several clumps or worlds, or standalone geometries, atomics, frame lists, dictionaries
+/// and animations, in any order. With `bind_dictionaries` the dictionaries are bound into the
+/// stream instance as they're read, see `Object::bind`, so the objects following them in the
+/// stream get their textures and animations from them.
+///
+/// Reading stops at a zero section id, as found on the padding of streams in CD images.
+pub fn read_stream<R: ReadExt>(rws: &mut Stream<R>, bind_dictionaries: bool) -> Result<Vec<Object>> {
+    let mut objects = Vec::new();
+    loop {
+        match SectionBuf::peek_header(rws) {
+            Ok(header) if header.id == 0 => break,
+            Ok(_) => {
+                let object = try!(Object::read(rws));
+                if bind_dictionaries {
+                    object.bind(rws.rw);
+                }
+                objects.push(object);
+            },
+            Err(ref err) if is_eof(err) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(objects)
+}
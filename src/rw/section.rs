@@ -2,6 +2,7 @@ use super::{Result, Error, ReadExt, Stream, Header, Section, SectionBuf};
 
 use std::io::{Seek, SeekFrom};
 use std::any::Any;
+use std::{cmp, fmt};
 
 #[derive(Debug)]
 pub struct Struct;
@@ -9,6 +10,35 @@ pub struct Struct;
 #[derive(Debug)]
 pub struct Extension;
 
+/// Types of objects owning an `Extension` section.
+///
+/// Plugins registered on `Instance` are keyed by this together with the plugin section id, since
+/// the same plugin id may carry different data depending on the object it's attached to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum PluginParent {
+    Clump,
+    Frame,
+    Atomic,
+    Geometry,
+    Material,
+    Texture,
+    TexDictionary,
+    TexNative,
+    World,
+    AtomicSector,
+    Light,
+}
+
+/// Holds the plugins read off an `Extension` section.
+///
+/// Plugins recognized by the reader are kept as `Any` objects, while the others are kept as raw
+/// chunks, so they can be inspected or written back without losing third-party data.
+pub struct PluginList {
+    known: Vec<Box<Any + Send + Sync>>,
+    /// Plugins no reader recognized.
+    pub unknown: Vec<SectionBuf>,
+}
+
 impl Section for Struct {
     fn section_id() -> u32 { 0x0001 }
 }
@@ -24,60 +54,148 @@ impl Section for Extension {
 impl Struct {
     pub fn read_up<R: ReadExt, T, F>(rws: &mut Stream<R>, f: F) -> Result<T>
                                     where F: FnOnce(&mut Stream<R>) -> Result<T> {
-        let _header = try!(Self::read_header(rws));
-        f(rws)
-        // TODO check if f() readed too much
+        let header = try!(Self::read_header(rws));
+        let end = rws.position() + header.size as u64;
+        match rws.read_section(header.id, end, f) {
+            Err(err) => Err(rws.annotate(err)),
+            ok => ok,
+        }
     }
 
     pub fn peek_up<R: ReadExt, T, F>(rws: &mut Stream<R>, f: F) -> Result<T>
                                     where F: FnOnce(&mut Stream<R>) -> Result<T> {
-        try!(rws.seek(SeekFrom::Current(0)).and_then(|pos| {
-            let result = Self::read_up(rws, f);
-            rws.seek(SeekFrom::Start(pos)).map(|_| result)
-        }))
+        let pos = rws.position();
+        let header = try!(Self::read_header(rws));
+        let end = rws.position() + header.size as u64;
+        let result = rws.read_bounded(end, f).map_err(|err| rws.annotate(err));
+        try!(rws.seek(SeekFrom::Start(pos)));
+        result
     }
 }
 
 impl Extension {
-    pub fn read_up<R: ReadExt, F>(rws: &mut Stream<R>, f: F) -> Result<Vec<Box<Any>>>
-                                    where F: Fn(&mut Stream<R>, Header) -> Result<Option<Box<Any>>> {
+    /// Reads the plugins in an Extension off the RenderWare Stream.
+    ///
+    /// The `f` callback is called for each plugin and should return `None` if the plugin isn't
+    /// recognized, in which case the plugins registered on the `Instance` for `parent` are tried.
+    /// Plugins no one recognizes are kept as raw chunks in the resulting `PluginList`.
+    pub fn read_up<R: ReadExt, F>(rws: &mut Stream<R>, parent: PluginParent, f: F) -> Result<PluginList>
+                                    where F: Fn(&mut Stream<R>, Header) -> Result<Option<Box<Any + Send + Sync>>> {
         let header = try!(Self::read_header(rws));
-        if header.size > 0 {
-            let endoff = (header.size as u64) + try!(rws.seek(SeekFrom::Current(0)));
-            let mut vec = Vec::new(); // XXX maybe with capacity if we add too many plugins
-            while endoff > try!(rws.seek(SeekFrom::Current(0))) {
-                let plg_header = try!(SectionBuf::read_header(rws));
-                try!(rws.seek(SeekFrom::Current(-12)));
-                // TODO check if f() readed too much
-                match try!(f(rws, plg_header)) {
-                    Some(plg) => vec.push(plg),
-                    None => { try!(SectionBuf::skip_section(rws)); },
+        let endoff = rws.position() + header.size as u64;
+        rws.read_section(header.id, endoff, |rws| {
+            let mut plugins = PluginList::new();
+            while endoff > rws.position() {
+                let plg_start = rws.position();
+                let plg_header = try!(SectionBuf::peek_header(rws));
+                let plg_endoff = plg_start + 12 + plg_header.size as u64;
+                let result = rws.read_section(plg_header.id, plg_endoff, |rws| {
+                    match try!(f(rws, plg_header)) {
+                        Some(plg) => plugins.known.push(plg),
+                        None => {
+                            try!(rws.seek(SeekFrom::Start(plg_start)));
+                            let buf = try!(SectionBuf::read(rws));
+                            match rws.rw.read_plugin(parent, &buf) {
+                                Some(result) => plugins.known.push(try!(result)),
+                                None => plugins.unknown.push(buf),
+                            }
+                        },
+                    }
+                    Ok(())
+                });
+
+                // A broken plugin is skipped altogether when lenient.
+                if let Err(err) = result {
+                    try!(rws.tolerate(err));
+                    try!(rws.seek(SeekFrom::Start(cmp::min(plg_endoff, endoff))));
                 }
             }
-            Ok(vec)
-        } else {
-            Ok(Vec::new())
-        }
+            Ok(plugins)
+        })
     }
 
-    pub fn read_for<R: ReadExt, T: Any + Section, F>(rws: &mut Stream<R>, f: F) -> Result<Option<T>>
+    /// Reads an Extension recognizing only the plugin `T`.
+    ///
+    /// Use `PluginList::take` to get the `T` plugin off the returned list.
+    pub fn read_for<R: ReadExt, T: Any + Send + Sync + Section, F>(rws: &mut Stream<R>, parent: PluginParent,
+                                                     f: F) -> Result<PluginList>
                                         where F: Fn(&mut Stream<R>) -> Result<T> {
-        let boxes = try!(Extension::read_up(rws, |rws, header| {
+        Extension::read_up(rws, parent, |rws, header| {
             if header.id == T::section_id() {
-                f(rws).map(|val| Some(Box::new(val) as Box<Any>))
+                f(rws).map(|val| Some(Box::new(val) as Box<Any + Send + Sync>))
             } else {
                 Ok(None)
             }
-        }));
-        Ok(boxes.into_iter()
-                .find(|bx_any| bx_any.is::<T>())
-                .map(|bx_any| bx_any.downcast().unwrap())
-                .map(|bx_tyy| *bx_tyy))
+        })
+    }
+
+    /// Reads an Extension recognizing only the plugins registered on the `Instance`.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>, parent: PluginParent) -> Result<PluginList> {
+        Extension::read_up(rws, parent, |_, _| Ok(None))
+    }
+}
+
+impl fmt::Debug for PluginList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PluginList {{ known: {} plugins, unknown: {:?} }}", self.known.len(), self.unknown)
+    }
+}
+
+impl PluginList {
+    /// Constructs an empty plugin list.
+    pub fn new() -> PluginList {
+        PluginList {
+            known: Vec::new(),
+            unknown: Vec::new(),
+        }
+    }
+
+    /// Checks whether there's no plugin at all in this list.
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty() && self.unknown.is_empty()
+    }
+
+    /// Gets the recognized plugin of type `T`, if any.
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.known.iter().filter_map(|bx_any| {
+            let any: &Any = &**bx_any;
+            any.downcast_ref::<T>()
+        }).next()
+    }
+
+    /// Removes the recognized plugin of type `T` off this list and returns it.
+    pub fn take<T: Any>(&mut self) -> Option<T> {
+        match self.known.iter().position(|bx_any| { let any: &Any = &**bx_any; any.is::<T>() }) {
+            Some(pos) => {
+                let bx_any: Box<Any> = self.known.remove(pos);
+                bx_any.downcast().ok().map(|bx_tyy| *bx_tyy)
+            },
+            None => None,
+        }
+    }
+
+    /// Copies the unrecognized plugins of this list into a new list.
+    ///
+    /// Recognized plugins are of arbitrary types which can't be copied, so they're left out.
+    pub fn clone_unknown(&self) -> PluginList {
+        PluginList {
+            known: Vec::new(),
+            unknown: self.unknown.clone(),
+        }
+    }
+
+    /// Gets the unrecognized plugin with the specified section id, if any.
+    pub fn find_unknown(&self, id: u32) -> Option<&SectionBuf> {
+        self.unknown.iter().find(|buf| buf.header.id == id)
     }
 }
 
 pub trait StringExt : Section {
     fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Self>;
+    /// Reads a null terminated string stored in a buffer of `size` bytes.
+    ///
+    /// Invalid UTF-8 gets replaced when lenient.
+    fn read_fixed<R: ReadExt>(rws: &mut Stream<R>, size: usize) -> Result<Self>;
     fn from_null_terminated_buffer<V: Into<Vec<u8>>>(mut vec: V) -> Result<Self> ;
 }
 
@@ -85,7 +203,22 @@ impl StringExt for String {
     fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Self> {
         // XXX perhaps optimize for '\0\0\0\0' strings to avoid unecessary alloc.
         let header = try!(SectionBuf::read_header_id(rws, Self::section_id()));
-        rws.read_bytes(header.size as usize).and_then(|vec| Self::from_null_terminated_buffer(vec))
+        Self::read_fixed(rws, header.size as usize)
+    }
+
+    fn read_fixed<R: ReadExt>(rws: &mut Stream<R>, size: usize) -> Result<Self> {
+        let mut vec = try!(rws.read_bytes(size));
+        if let Some(endpos) = vec.iter().position(|&c| c == 0) {
+            vec.truncate(endpos);
+        }
+        // The bytes are given back on failure, so there's no need to copy them beforehand.
+        match String::from_utf8(vec) {
+            Ok(string) => Ok(string),
+            Err(err) => {
+                try!(rws.tolerate(Error::Other("RwString is not valid UTF-8".into())));
+                Ok(String::from_utf8_lossy(&err.into_bytes()).into_owned())
+            },
+        }
     }
 
     fn from_null_terminated_buffer<V: Into<Vec<u8>>>(vec: V) -> Result<Self> {
@@ -1,12 +1,14 @@
 // TODO support III/VC and PS2 texture dictionaries
 
-use byteorder::{ReadBytesExt, LittleEndian};
+use byteorder::ReadBytesExt;
 use super::{Section, Struct, Result, Error, ReadExt, Stream};
 
-use super::{Extension, StringExt};
-use std::rc::Rc;
+use super::{Extension, StringExt, PluginList, PluginParent, Rgba};
+use super::gx::{self, GxFormat};
+use std::sync::Arc;
 use std::collections::HashMap;
 use std::cmp;
+use std::io::{Seek, SeekFrom};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum FilterMode {
@@ -74,6 +76,9 @@ impl WrapMode {
     }
 }
 
+/// The D3DFMT_DXT5 four character code, identifying DXT5 rasters on Direct3D 9.
+const FOURCC_DXT5: u32 = 0x35545844;
+
 impl RasterFormat {
     fn from_raw(raw: u32) -> Option<RasterFormat> {
         match raw & 0x0F00 {
@@ -96,6 +101,14 @@ pub enum TextureData {
     Dxt3(Vec<u8>),
     Dxt5(Vec<u8>),
     Rgb8(Vec<(u8, u8, u8)>),
+    /// RGBA 8 bits each, decoded off a platform specific format.
+    Rgba8(Vec<u8>),
+    /// Indices into a palette of colors, one byte for each pixel.
+    Paletted {
+        /// Colors of the palette, shared by all the levels of the raster.
+        palette: Arc<Vec<Rgba>>,
+        indices: Vec<u8>,
+    },
 }
 
 #[derive(Debug)]
@@ -115,13 +128,14 @@ pub struct Raster {
 // TODO PRIVATIZE
 #[derive(Debug)]
 pub struct Texture {
-    pub dict: Rc<String>,
-    pub name: Rc<String>,   // TODO...
-    pub mask: Rc<String>,   // ... why is this a Rc?
+    pub dict: Arc<String>,
+    pub name: Arc<String>,   // TODO...
+    pub mask: Arc<String>,   // ... why is this a Rc?
     pub raster: Raster,
     pub filter: FilterMode,
     pub wrap_x: WrapMode,
     pub wrap_y: WrapMode,
+    pub plugins: PluginList,
 }
 
 #[derive(Debug)]
@@ -132,12 +146,14 @@ pub struct SimpleTexture {
     pub filter: FilterMode,
     pub wrap_x: WrapMode,
     pub wrap_y: WrapMode,
+    pub plugins: PluginList,
 }
 
 #[derive(Debug)]
 pub struct TexDictionary {
-    pub name: Rc<String>,
-    pub textures: HashMap<String, Rc<Texture>>,
+    pub name: Arc<String>,
+    pub textures: HashMap<String, Arc<Texture>>,
+    pub plugins: PluginList,
 }
 
 #[derive(Debug)]
@@ -156,16 +172,20 @@ impl Section for TexNative {
 }
 
 impl Raster {
-    /// `mips` **shall not** be empty
-    pub fn new(mips: Vec<TexLevel>) -> Raster {
-        assert!(!mips.is_empty());
-        Raster {
-            mips: mips,
+    /// Constructs a raster from its levels, fails if `mips` is empty.
+    pub fn new(mips: Vec<TexLevel>) -> Result<Raster> {
+        if mips.is_empty() {
+            return Err(Error::EmptyRaster);
         }
+        Ok(Raster {
+            mips: mips,
+        })
     }
 
     pub fn with_base(base: TexLevel) -> Raster {
-        Raster::new(vec![base])
+        Raster {
+            mips: vec![base],
+        }
     }
 
     pub fn width(&self) -> u16 {
@@ -186,85 +206,115 @@ impl Raster {
 }
 
 impl Texture {
-    pub fn from_simple(dict_name: Rc<String>, tex: SimpleTexture) -> Rc<Texture> {
-        Rc::new(Texture {
+    pub fn from_simple(dict_name: Arc<String>, tex: SimpleTexture) -> Arc<Texture> {
+        Arc::new(Texture {
             dict: dict_name,
-            name: Rc::new(tex.name),
-            mask: Rc::new(tex.mask),
+            name: Arc::new(tex.name),
+            mask: Arc::new(tex.mask),
             raster: tex.raster,
             filter: tex.filter,
             wrap_x: tex.wrap_x,
             wrap_y: tex.wrap_y,
+            plugins: tex.plugins,
         })
     }
 
-    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<Rc<Texture>> {
+    /// Reads a Texture reference off the RenderWare Stream.
+    ///
+    /// The texture is looked up in the binded dictionary, `None` is returned in place of the
+    /// texture if it isn't found there. The extension plugins of the texture section are returned
+    /// as well, since the texture object is shared with the dictionary.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<(Option<Arc<Texture>>, PluginList)> {
         let _header = try!(Self::read_header(rws));
 
         let (filter_flags, _) = try!(Struct::read_up(rws, |rws| {
-            Ok((try!(rws.read_u16::<LittleEndian>()), try!(rws.read_u16::<LittleEndian>())))
+            Ok((try!(rws.read_u16_ordered()), try!(rws.read_u16_ordered())))
         }));
 
         let name = try!(String::read(rws));
         let mask = try!(String::read(rws));
 
-        try!(Extension::skip_section(rws));
+        let plugins = try!(Extension::read(rws, PluginParent::Texture));
 
-        rws.rw.read_texture(&name, Some(&mask))
-              .ok_or_else(|| Error::TextureNotFound(name))
+        Ok((rws.rw.read_texture(&name, Some(&mask)), plugins))
     }
 }
 
 impl TexDictionary {
 
-    pub fn new_empty<S: Into<String>>(name: S) -> Rc<TexDictionary> {
-        Rc::new(TexDictionary {
-            name: Rc::new(name.into()),
+    pub fn new_empty<S: Into<String>>(name: S) -> Arc<TexDictionary> {
+        Arc::new(TexDictionary {
+            name: Arc::new(name.into()),
             textures: HashMap::new(),
+            plugins: PluginList::new(),
         })
     }
 
-    pub fn new<S: Into<String>, I: Iterator<Item=SimpleTexture>>(name: S, texgen_iter: I) -> Rc<TexDictionary> {
+    pub fn new<S: Into<String>, I: Iterator<Item=SimpleTexture>>(name: S, texgen_iter: I) -> Arc<TexDictionary> {
+        Arc::new(TexDictionary::from_simple(name, texgen_iter, PluginList::new()))
+    }
+
+    fn from_simple<S: Into<String>, I: Iterator<Item=SimpleTexture>>(name: S, texgen_iter: I,
+                                                                      plugins: PluginList) -> TexDictionary {
         let mut textures = HashMap::with_capacity(texgen_iter.size_hint().0);
-        let mut dict_name = Rc::new(name.into());
+        let mut dict_name = Arc::new(name.into());
         for simple_tex in texgen_iter {
             let tex = Texture::from_simple(dict_name.clone(), simple_tex);
             textures.insert((*tex.name).clone(), tex);
         }
         //textures.shrink_to_fit();
-        Rc::new(TexDictionary {
+        TexDictionary {
             name: dict_name,
             textures: textures,
-        })
+            plugins: plugins,
+        }
     }
 
-    pub fn read_texture(&self, name: &str, mask: Option<&str>) -> Option<Rc<Texture>> {
+    pub fn read_texture(&self, name: &str, mask: Option<&str>) -> Option<Arc<Texture>> {
         self.textures.get(name).map(|rctex| rctex.clone())
     }
 
-    pub fn read<R: ReadExt, S: Into<String>>(rws: &mut Stream<R>, dict_name: S) -> Result<Rc<TexDictionary>> {
+    pub fn read<R: ReadExt, S: Into<String>>(rws: &mut Stream<R>, dict_name: S) -> Result<Arc<TexDictionary>> {
         let header = try!(Self::read_header(rws));
 
         let num_textures = {
             if header.version < 0x1803FFFF { // lesser than 3.6.0.0
                 try!(Struct::read_up(rws, |rws| {
-                    Ok((try!(rws.read_u32::<LittleEndian>())))
+                    Ok((try!(rws.read_u32_ordered())))
                 }))
             } else {
                 let (count, _dev_id) = try!(Struct::read_up(rws, |rws| {
-                    Ok((try!(rws.read_u16::<LittleEndian>()), try!(rws.read_u16::<LittleEndian>())))
+                    Ok((try!(rws.read_u16_ordered()), try!(rws.read_u16_ordered())))
                 }));
                 count as u32
             }
         };
 
 
-        let textures: Vec<_> = try!((0..num_textures).map(|_| TexNative::read(rws))
-                                             .collect());
+        let mut textures = Vec::with_capacity(try!(rws.check_count(num_textures as u64, 12)));
+        for _ in (0..num_textures) {
+            let start = rws.position();
+            match TexNative::read(rws) {
+                Ok(tex) => textures.push(tex),
+                // Textures we can't decode are skipped when lenient.
+                Err(err) => {
+                    let is_unsupported = match *err.root_cause() {
+                        Error::UnsupportedPlatform(_) | Error::UnsupportedRasterFormat(_) => true,
+                        _ => false,
+                    };
+                    if !is_unsupported {
+                        return Err(err);
+                    }
+                    try!(rws.tolerate(err));
+                    try!(rws.seek(SeekFrom::Start(start)));
+                    try!(TexNative::skip_section(rws));
+                },
+            }
+        }
 
-        try!(Extension::skip_section(rws));
+        let plugins = try!(Extension::read(rws, PluginParent::TexDictionary));
 
-        Ok(TexDictionary::new(dict_name, textures.into_iter()))
+        Ok(Arc::new(TexDictionary::from_simple(dict_name, textures.into_iter(), plugins)))
     }
 }
 
@@ -272,43 +322,125 @@ impl TexNative {
     pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<SimpleTexture> {
         let header = try!(Self::read_header(rws));
 
-        let platform_id = try!(Struct::peek_up(rws, |rws| Ok(try!(rws.read_u32::<LittleEndian>()))));
+        let platform_id = try!(Struct::peek_up(rws, |rws| Ok(try!(rws.read_u32_ordered()))));
 
-        let result = try!(match platform_id {
-            2 => unimplemented!(),                              // OpenGL
-            4 | 0x00325350 => unimplemented!(),                 // PS2 ("PS2/0")
-            5 => unimplemented!(),                              // Xbox
+        let mut result = try!(match platform_id {
+            6 => Struct::read_up(rws, |rws| Self::read_struct_gc(rws)),    // GameCube
             8 => Struct::read_up(rws, |rws| Self::read_struct_d3dx(rws)),  // D3D8
             9 => Struct::read_up(rws, |rws| Self::read_struct_d3dx(rws)),  // D3D9
-            _ => Err(Error::Other(format!("Unknown texture dictionary platform id {}", platform_id))),
+            // TODO OpenGL (2), PS2 (4 or "PS2\0") and Xbox (5)
+            _ => Err(Error::UnsupportedPlatform(platform_id)),
         });
 
-        try!(Extension::skip_section(rws));
+        result.plugins = try!(Extension::read(rws, PluginParent::TexNative));
 
         Ok(result)
     }
 
+    fn read_struct_gc<R: ReadExt>(rws: &mut Stream<R>) -> Result<SimpleTexture> {
+        let platform_id = try!(rws.read_u32_ordered());
+        if platform_id != 6 {
+            return Err(Error::UnsupportedPlatform(platform_id));
+        }
+
+        let filter_flags = try!(rws.read_u32_ordered());
+
+        let name = try!(String::read_fixed(rws, 32));
+        let mask = try!(String::read_fixed(rws, 32));
+
+        let raster_format = try!(rws.read_u32_ordered());
+        let _has_alpha = try!(rws.read_u32_ordered());
+
+        let flag_ext_pal8 = (raster_format & 0x2000) != 0;
+        let flag_ext_pal4 = (raster_format & 0x4000) != 0;
+
+        let width = try!(rws.read_u16_ordered());
+        let height = try!(rws.read_u16_ordered());
+        let _depth = try!(rws.read_u8());
+        let num_levels = try!(rws.read_u8());
+        let _raster_type = try!(rws.read_u8());
+        let gx_format = try!(rws.read_u8());
+
+        let filter = FilterMode::from_raw(filter_flags & 0xFF).unwrap_or(FilterMode::None);
+        let wrap_x = WrapMode::from_raw((filter_flags >> 8) & 0xF).unwrap_or(WrapMode::None);
+        let wrap_y = WrapMode::from_raw((filter_flags >> 12) & 0xF).unwrap_or(WrapMode::None);
+        let format = try!(GxFormat::from_raw(gx_format)
+                            .ok_or(Error::UnsupportedRasterFormat(raster_format)));
+
+        // Paletted rasters have their RGB5A3 palette before the pixels.
+        let palette = if flag_ext_pal8 || flag_ext_pal4 {
+            let num_colors = if flag_ext_pal8 { 256 } else { 16 };
+            let mut palette = Vec::with_capacity(num_colors);
+            for _ in (0..num_colors) {
+                palette.push(try!(rws.read_u16_ordered()));
+            }
+            Some(palette)
+        } else {
+            None
+        };
+
+        // All the levels are stored together, each one padded to whole blocks.
+        let raster_size = try!(rws.read_u32_ordered()) as usize;
+        let data = try!(rws.read_bytes(raster_size));
+
+        let (mut width, mut height) = (width, height);
+        let mut offset = 0;
+        let mut mips = Vec::with_capacity(num_levels as usize);
+        for _ in (0..num_levels) {
+            let size = format.image_size(width as usize, height as usize);
+            let rgba = if offset + size <= data.len() {
+                gx::decode(format, width as usize, height as usize,
+                           &data[offset..offset + size], palette.as_ref().map(|p| &p[..]))
+            } else {
+                None
+            };
+            match rgba {
+                Some(rgba) => mips.push(TexLevel {
+                    data: TextureData::Rgba8(rgba),
+                    width: width,
+                    height: height,
+                }),
+                None => break,
+            }
+            offset += size;
+            width = cmp::max(1, width / 2);
+            height = cmp::max(1, height / 2);
+        }
+
+        Ok(SimpleTexture {
+            name: name,
+            mask: mask,
+            raster: try!(Raster::new(mips)),
+            filter: filter,
+            wrap_x: wrap_x,
+            wrap_y: wrap_y,
+            plugins: PluginList::new(),
+        })
+    }
+
     fn read_struct_d3dx<R: ReadExt>(rws: &mut Stream<R>) -> Result<SimpleTexture> {
         // TODO TXDs are confusing, review this later
 
-        let platform_id = try!(rws.read_u32::<LittleEndian>());
-        assert!(platform_id == 8 || platform_id == 9);
+        let platform_id = try!(rws.read_u32_ordered());
+        if platform_id != 8 && platform_id != 9 {
+            return Err(Error::UnsupportedPlatform(platform_id));
+        }
 
-        let filter_flags = try!(rws.read_u32::<LittleEndian>());
+        let filter_flags = try!(rws.read_u32_ordered());
 
-        let name = try!(String::from_null_terminated_buffer(try!(rws.read_bytes(32))));
-        let mask = try!(String::from_null_terminated_buffer(try!(rws.read_bytes(32))));
+        let name = try!(String::read_fixed(rws, 32));
+        let mask = try!(String::read_fixed(rws, 32));
 
-        let raster_format = try!(rws.read_u32::<LittleEndian>());
-        let d3d_format = try!(rws.read_u32::<LittleEndian>());
+        let raster_format = try!(rws.read_u32_ordered());
+        let d3d_format = try!(rws.read_u32_ordered());
 
         let flag_auto_mip = (raster_format & 0x1000) != 0;
         let flag_ext_pal8 = (raster_format & 0x2000) != 0;
         let flag_ext_pal4 = (raster_format & 0x4000) != 0;
         let flag_mipmaps  = (raster_format & 0x8000) != 0;
 
-        let width = try!(rws.read_u16::<LittleEndian>());
-        let height = try!(rws.read_u16::<LittleEndian>());
+        let width = try!(rws.read_u16_ordered());
+        let height = try!(rws.read_u16_ordered());
         let depth = try!(rws.read_u8());
         let num_levels = try!(rws.read_u8());
         let raster_type = try!(rws.read_u8());
@@ -323,63 +455,124 @@ impl TexNative {
         let wrap_x = WrapMode::from_raw((filter_flags >> 8) & 0xF).unwrap_or(WrapMode::None);
         let wrap_y = WrapMode::from_raw((filter_flags >> 12) & 0xF).unwrap_or(WrapMode::None);
         let format = try!(RasterFormat::from_raw(raster_format)
-                            .ok_or(Error::Other(format!("Invalid raster format {}", raster_format))));
+                            .ok_or(Error::UnsupportedRasterFormat(raster_format)));
+
+        // Paletted rasters have their palette before the levels.
+        let palette = if flag_ext_pal8 || flag_ext_pal4 {
+            let num_colors = if flag_ext_pal8 { 256 } else { 32 };
+            let mut palette = Vec::with_capacity(num_colors);
+            for _ in (0..num_colors) {
+                palette.push(try!(Rgba::read(rws)));
+            }
+            Some(Arc::new(palette))
+        } else {
+            None
+        };
 
         let mips = {
-            if (flag_ext_pal8 || flag_ext_pal4) && (true)  {
-                unimplemented!();
-            } else {
-                let (mut width, mut height) = (width, height);
-                let mut mips = Vec::with_capacity(num_levels as usize);
-                let mut fuck_me = false;
-
-                for _ in (0..num_levels) {
-                    let raster_size = try!(rws.read_u32::<LittleEndian>()) as usize;
-
-                    // TODO check if raster_size matches the width height format things
-
-                    let data = match (format, is_compressed, has_alpha) {
-                        (RasterFormat::R5G6B5, true, false) => { // DXT1c
-                            TextureData::Dxt1c(try!(rws.read_bytes(raster_size)))
-                        },
-                        (RasterFormat::R5G5B5A1, true, true) => { // DXT1a
-                            TextureData::Dxt1a(try!(rws.read_bytes(raster_size)))
-                        },
-                        (RasterFormat::R4G4B4A4, true, true) => { // DXT3
-                            TextureData::Dxt3(try!(rws.read_bytes(raster_size)))
-                        },
-                        _ => unimplemented!(),
-                    };
-
-                    // Why the fuck this happens?
-                    if raster_size == 0 {
-                        fuck_me = true;
-                    }
-
-                    if !fuck_me {
-                        mips.push(TexLevel {
-                            data: data,
-                            width: width,
-                            height: height,
-                        });
-                    }
+            let (mut width, mut height) = (width, height);
+            let mut mips = Vec::with_capacity(num_levels as usize);
+            let mut fuck_me = false;
+
+            for _ in (0..num_levels) {
+                let raster_size = try!(rws.read_u32_ordered()) as usize;
+
+                // TODO check if raster_size matches the width height format things
+
+                let data = match (format, is_compressed, has_alpha) {
+                    _ if palette.is_some() => TextureData::Paletted {
+                        palette: palette.clone().unwrap(),
+                        indices: try!(rws.read_bytes(raster_size)),
+                    },
+                    _ if is_compressed && d3d_format == FOURCC_DXT5 => {
+                        TextureData::Dxt5(try!(rws.read_bytes(raster_size)))
+                    },
+                    (RasterFormat::R5G6B5, true, false) => { // DXT1c
+                        TextureData::Dxt1c(try!(rws.read_bytes(raster_size)))
+                    },
+                    (RasterFormat::R5G5B5A1, true, true) => { // DXT1a
+                        TextureData::Dxt1a(try!(rws.read_bytes(raster_size)))
+                    },
+                    (RasterFormat::R4G4B4A4, true, true) => { // DXT3
+                        TextureData::Dxt3(try!(rws.read_bytes(raster_size)))
+                    },
+                    (RasterFormat::R8G8B8A8, false, _) => { // stored as BGRA
+                        let data = try!(rws.read_bytes(raster_size));
+                        TextureData::Rgba8(data.chunks(4).filter(|p| p.len() == 4).flat_map(|p| {
+                            vec![p[2], p[1], p[0], p[3]].into_iter()
+                        }).collect())
+                    },
+                    (RasterFormat::R8G8B8, false, _) => { // stored as BGRX
+                        let data = try!(rws.read_bytes(raster_size));
+                        TextureData::Rgb8(data.chunks(4).filter(|p| p.len() == 4)
+                                                .map(|p| (p[2], p[1], p[0])).collect())
+                    },
+                    (RasterFormat::R5G6B5, false, _) |
+                    (RasterFormat::R5G5B5A1, false, _) |
+                    (RasterFormat::R4G4B4A4, false, _) |
+                    (RasterFormat::R5G5B5, false, _) => {
+                        let data = try!(rws.read_bytes(raster_size));
+                        TextureData::Rgba8(expand_16bit(format, &data))
+                    },
+                    _ => return Err(Error::UnsupportedRasterFormat(raster_format)),
+                };
+
+                // Why the fuck this happens?
+                if raster_size == 0 {
+                    fuck_me = true;
+                }
 
-                    width = cmp::max(1, width / 2);
-                    height = cmp::max(1, height / 2);
+                if !fuck_me {
+                    mips.push(TexLevel {
+                        data: data,
+                        width: width,
+                        height: height,
+                    });
                 }
 
-                mips.shrink_to_fit();
-                mips
+                width = cmp::max(1, width / 2);
+                height = cmp::max(1, height / 2);
             }
+
+            mips.shrink_to_fit();
+            mips
         };
 
         Ok(SimpleTexture {
             name: name,
             mask: mask,
-            raster: Raster::new(mips),
+            raster: try!(Raster::new(mips)),
             filter: filter,
             wrap_x: wrap_x,
             wrap_y: wrap_y,
+            plugins: PluginList::new(),
         })
     }
 }
+
+/// Expands little endian pixels of a 16 bits raster format into RGBA 8 bits each.
+fn expand_16bit(format: RasterFormat, data: &[u8]) -> Vec<u8> {
+    fn expand(value: u16, bits: u32) -> u8 {
+        let value = value as u32;
+        ((value << (8 - bits)) | (value >> (2 * bits - 8))) as u8
+    }
+
+    let mut rgba = Vec::with_capacity(data.len() * 2);
+    for p in data.chunks(2).filter(|p| p.len() == 2) {
+        let c = (p[0] as u16) | ((p[1] as u16) << 8);
+        let (r, g, b, a) = match format {
+            RasterFormat::R5G6B5 => (expand(c >> 11, 5), expand((c >> 5) & 0x3F, 6), expand(c & 0x1F, 5), 255),
+            RasterFormat::R4G4B4A4 => (expand((c >> 8) & 0xF, 4), expand((c >> 4) & 0xF, 4),
+                                       expand(c & 0xF, 4), expand(c >> 12, 4)),
+            RasterFormat::R5G5B5A1 => (expand((c >> 10) & 0x1F, 5), expand((c >> 5) & 0x1F, 5),
+                                       expand(c & 0x1F, 5), if (c & 0x8000) != 0 { 255 } else { 0 }),
+            _ => (expand((c >> 10) & 0x1F, 5), expand((c >> 5) & 0x1F, 5), expand(c & 0x1F, 5), 255),
+        };
+        rgba.push(r);
+        rgba.push(g);
+        rgba.push(b);
+        rgba.push(a);
+    }
+    rgba
+}
+
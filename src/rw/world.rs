@@ -0,0 +1,382 @@
+use byteorder::ReadBytesExt;
+use super::{Section, Struct, Result, Error, ReadExt, Stream, SectionBuf};
+
+use super::{Vec3, Uv, Rgba, BBox, Sphere};
+use super::{MaterialList, MeshHeader, Mesh, Triangle, Geometry, GeometryFlags, MorphTarget};
+use super::{Extension, PluginList, PluginParent};
+use std::any::Any;
+use std::usize;
+
+/// Static level geometry (RpWorld).
+///
+/// Worlds partition the space of a level by a binary tree of `PlaneSector`s, whose leaves are
+/// `AtomicSector`s holding the actual model data. The triangles of all the sectors refer to
+/// the single material list of the world.
+///
+/// Grand Theft Auto does not use worlds, but generic RenderWare titles store their levels as such.
+#[derive(Debug)]
+pub struct World {
+    /// Origin of the world, the coordinates of the sectors are already relative to it.
+    pub origin: Vec3,
+    /// Format flags of the world, as in `Geometry`, telling which vertex data the sectors have.
+    pub flags: u32,
+    /// Total number of triangles in the world.
+    pub num_triangles: u32,
+    /// Total number of vertices in the world.
+    pub num_vertices: u32,
+    /// Bounding box of the whole world.
+    pub bbox: BBox,
+    /// Materials of the triangles of all the sectors.
+    pub matlist: MaterialList,
+    /// Root of the sector tree.
+    pub root: WorldSector,
+    /// Extension plugins attached to this world.
+    pub plugins: PluginList,
+}
+
+/// Node of the sector tree of a `World`.
+#[derive(Debug)]
+pub enum WorldSector {
+    Plane(PlaneSector),
+    Atomic(AtomicSector),
+}
+
+/// Axis a `PlaneSector` splits the space along.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PlaneAxis {
+    X,
+    Y,
+    Z,
+}
+
+/// Inner node of the sector tree, splitting the space by an axis-aligned plane.
+#[derive(Debug)]
+pub struct PlaneSector {
+    /// Axis the splitting plane is perpendicular to.
+    pub axis: PlaneAxis,
+    /// Coordinate of the splitting plane along its axis.
+    pub value: f32,
+    /// Coordinate of the far end of the left sector, which may overlap the right one.
+    pub left_value: f32,
+    /// Coordinate of the near end of the right sector, which may overlap the left one.
+    pub right_value: f32,
+    /// Sector under the plane.
+    pub left: Box<WorldSector>,
+    /// Sector over the plane.
+    pub right: Box<WorldSector>,
+}
+
+/// Leaf of the sector tree, holding the model data of a region of the world.
+#[derive(Debug)]
+pub struct AtomicSector {
+    /// Bounding box of the sector.
+    pub bbox: BBox,
+    /// Vertex positions.
+    pub verts: Vec<Vec3>,
+    /// Vertex normals.
+    ///
+    /// One element for each vertex.
+    pub normals: Option<Vec<Vec3>>,
+    /// Pre-light colors.
+    ///
+    /// One element for each vertex.
+    pub colors: Option<Vec<Rgba>>,
+    /// Texture coordinate sets.
+    ///
+    /// One element for each coordinate set (uv0, uv1, ...), and then one element for each vertex.
+    pub uv_sets: Vec<Vec<Uv>>,
+    /// Triangles of the sector, whose material indices refer to the material list of the world.
+    pub faces: Vec<Triangle>,
+    /// List of meshes to be rendered.
+    pub meshlist: MeshHeader,
+    /// Extension plugins attached to this sector.
+    pub plugins: PluginList,
+}
+
+impl Section for World {
+    fn section_id() -> u32 { 0x000B }
+}
+
+impl Section for PlaneSector {
+    fn section_id() -> u32 { 0x000A }
+}
+
+impl Section for AtomicSector {
+    fn section_id() -> u32 { 0x0009 }
+}
+
+impl World {
+    /// Gets the plugin of type `T` attached to this world, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
+    }
+
+    /// Gets all the atomic sectors of this world, in depth-first order.
+    pub fn atomic_sectors(&self) -> Vec<&AtomicSector> {
+        let mut sectors = Vec::new();
+        self.root.collect_atomics(&mut sectors);
+        sectors
+    }
+
+    /// Reads a World off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>) -> Result<World> {
+        let _header = try!(Self::read_header(rws));
+
+        let (root_is_atomic, origin, num_tris, num_verts, flags, bbox) = try!(Struct::read_up(rws, |rws| {
+            let root_is_atomic = try!(rws.read_u32_ordered()) != 0;
+            let inv_origin = try!(Vec3::read(rws));
+            // Older streams store surface properties, the structure is 12 bytes longer.
+            if rws.remaining().unwrap_or(0) >= 60 {
+                let _amb_spec_diff = (try!(rws.read_f32_ordered()),
+                                      try!(rws.read_f32_ordered()),
+                                      try!(rws.read_f32_ordered()));
+            }
+            let num_tris = try!(rws.read_u32_ordered());
+            let num_verts = try!(rws.read_u32_ordered());
+            let _num_plane_sectors = try!(rws.read_u32_ordered());
+            let _num_atomic_sectors = try!(rws.read_u32_ordered());
+            let _col_sector_size = try!(rws.read_u32_ordered());
+            let flags = try!(rws.read_u32_ordered());
+            let bbox = try!(BBox::read(rws));
+            Ok((root_is_atomic, Vec3(-inv_origin.0, -inv_origin.1, -inv_origin.2),
+                num_tris, num_verts, flags, bbox))
+        }));
+
+        let matlist = try!(MaterialList::read(rws));
+        let root = try!(WorldSector::read(rws, root_is_atomic, flags, &matlist));
+        let plugins = try!(Extension::read(rws, PluginParent::World));
+
+        Ok(World {
+            origin: origin,
+            flags: flags,
+            num_triangles: num_tris,
+            num_vertices: num_verts,
+            bbox: bbox,
+            matlist: matlist,
+            root: root,
+            plugins: plugins,
+        })
+    }
+}
+
+impl WorldSector {
+    /// Collects the atomic sectors under this sector in depth-first order.
+    fn collect_atomics<'a>(&'a self, sectors: &mut Vec<&'a AtomicSector>) {
+        match *self {
+            WorldSector::Plane(ref plane) => {
+                plane.left.collect_atomics(sectors);
+                plane.right.collect_atomics(sectors);
+            },
+            WorldSector::Atomic(ref atomic) => sectors.push(atomic),
+        }
+    }
+
+    /// Reads a sector of the world with the specified format flags off the RenderWare Stream.
+    ///
+    /// The kind of sector expected is given by its parent, but the section id is checked anyway.
+    fn read<R: ReadExt>(rws: &mut Stream<R>, is_atomic: bool,
+                        flags: u32, matlist: &MaterialList) -> Result<WorldSector> {
+        let header = try!(SectionBuf::peek_header(rws));
+        match header.id {
+            id if id == AtomicSector::section_id() => {
+                Ok(WorldSector::Atomic(try!(AtomicSector::read(rws, flags, matlist))))
+            },
+            id if id == PlaneSector::section_id() => {
+                Ok(WorldSector::Plane(try!(PlaneSector::read(rws, flags, matlist))))
+            },
+            id => Err(rws.annotate(Error::ExpectedSection {
+                expect: if is_atomic { AtomicSector::section_id() } else { PlaneSector::section_id() },
+                found: id,
+            })),
+        }
+    }
+}
+
+impl PlaneSector {
+    /// Reads a Plane Sector and its childs off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>, flags: u32, matlist: &MaterialList) -> Result<PlaneSector> {
+        let _header = try!(Self::read_header(rws));
+
+        let (axis, value, left_is_atomic, right_is_atomic, left_value, right_value) = try!(Struct::read_up(rws, |rws| {
+            let axis = match try!(rws.read_u32_ordered()) {
+                0 => PlaneAxis::X,
+                4 => PlaneAxis::Y,
+                8 => PlaneAxis::Z,
+                axis => return Err(rws.annotate(Error::Other(format!("invalid plane sector axis {}", axis)))),
+            };
+            Ok((axis,
+                try!(rws.read_f32_ordered()),
+                try!(rws.read_u32_ordered()) != 0,
+                try!(rws.read_u32_ordered()) != 0,
+                try!(rws.read_f32_ordered()),
+                try!(rws.read_f32_ordered())))
+        }));
+
+        let left = try!(WorldSector::read(rws, left_is_atomic, flags, matlist));
+        let right = try!(WorldSector::read(rws, right_is_atomic, flags, matlist));
+
+        Ok(PlaneSector {
+            axis: axis,
+            value: value,
+            left_value: left_value,
+            right_value: right_value,
+            left: Box::new(left),
+            right: Box::new(right),
+        })
+    }
+}
+
+impl AtomicSector {
+    /// Gets the plugin of type `T` attached to this sector, if any.
+    pub fn plugin<T: Any>(&self) -> Option<&T> {
+        self.plugins.get::<T>()
+    }
+
+    /// Builds a geometry out of this sector, so it can be handled as any other model.
+    ///
+    /// The `world` must be the one owning this sector.
+    pub fn to_geometry(&self, world: &World) -> Geometry {
+        let Vec3(sx, sy, sz) = self.bbox.sup;
+        let Vec3(ix, iy, iz) = self.bbox.inf;
+        let (dx, dy, dz) = (sx - ix, sy - iy, sz - iz);
+        let sphere = Sphere {
+            center: Vec3((sx + ix) * 0.5, (sy + iy) * 0.5, (sz + iz) * 0.5),
+            radius: (dx * dx + dy * dy + dz * dz).sqrt() * 0.5,
+        };
+
+        Geometry {
+            is_tri_strip: self.meshlist.is_tri_strip,
+            flags: GeometryFlags::from_bits_truncate(world.flags as u16),
+            native_flags: 0,
+            num_uv_sets: self.uv_sets.len() as u8,
+            surf: None,
+            colors: self.colors.clone(),
+            uv_sets: self.uv_sets.clone(),
+            faces: self.faces.clone(),
+            targets: vec![MorphTarget::new(sphere, Some(self.verts.clone()), self.normals.clone())],
+            matlist: MaterialList(world.matlist.0.clone()),
+            meshlist: MeshHeader {
+                is_tri_strip: self.meshlist.is_tri_strip,
+                total_indices: self.meshlist.total_indices,
+                meshes: self.meshlist.meshes.iter().map(|mesh| Mesh {
+                    material: mesh.material.clone(),
+                    indices: mesh.indices.clone(),
+                }).collect(),
+            },
+            plugins: PluginList::new(),
+        }
+    }
+
+    /// Reads an Atomic Sector of a world with the specified format flags off the RenderWare Stream.
+    pub fn read<R: ReadExt>(rws: &mut Stream<R>, flags: u32, matlist: &MaterialList) -> Result<AtomicSector> {
+        let _header = try!(Self::read_header(rws));
+
+        let (bbox, verts, normals, colors, uv_sets, faces) = try!(Struct::read_up(rws, |rws| {
+            let mat_base = try!(rws.read_u32_ordered());
+            let num_tris = try!(rws.read_u32_ordered());
+            let num_verts = try!(rws.read_u32_ordered());
+            let bbox = try!(BBox::read(rws));
+            let _col_sector_present = try!(rws.read_u32_ordered());
+            let _unused = try!(rws.read_u32_ordered());
+
+            let num_verts = try!(rws.check_count(num_verts as u64, 12));
+            let mut verts = Vec::with_capacity(num_verts);
+            for _ in (0..num_verts) {
+                verts.push(try!(Vec3::read(rws)));
+            }
+
+            // Normals are compressed into signed bytes, plus padding.
+            let normals = if (flags & 16) != 0 {
+                let mut v = Vec::with_capacity(num_verts);
+                for _ in (0..num_verts) {
+                    let (x, y, z, _) = (try!(rws.read_i8()), try!(rws.read_i8()),
+                                        try!(rws.read_i8()), try!(rws.read_u8()));
+                    v.push(Vec3(x as f32 / 127.0, y as f32 / 127.0, z as f32 / 127.0));
+                }
+                Some(v)
+            } else {
+                None
+            };
+
+            let colors = if (flags & 8) != 0 {
+                let mut v = Vec::with_capacity(num_verts);
+                for _ in (0..num_verts) {
+                    v.push(try!(Rgba::read(rws)));
+                }
+                Some(v)
+            } else {
+                None
+            };
+
+            let mut uv_sets = Vec::new();
+            for _ in (0..num_uv_sets(flags)) {
+                let mut v = Vec::with_capacity(num_verts);
+                for _ in (0..num_verts) {
+                    v.push(try!(Uv::read(rws)));
+                }
+                uv_sets.push(v);
+            }
+
+            // Unlike geometries, sectors store the vertex indices in order, then the material.
+            let mut faces = Vec::with_capacity(try!(rws.check_count(num_tris as u64, 8)));
+            for _ in (0..num_tris) {
+                let x_id = try!(rws.read_u16_ordered());
+                let y_id = try!(rws.read_u16_ordered());
+                let z_id = try!(rws.read_u16_ordered());
+                let mat_id = try!(rws.read_u16_ordered());
+                // Material ids are relative to the base of the sector, into the world materials.
+                let mat_id = match mat_base.checked_add(mat_id as u32) {
+                    Some(index) if (index as usize) < matlist.0.len() => index as u16,
+                    index => {
+                        let index = index.map(|index| index as usize).unwrap_or(usize::MAX);
+                        try!(rws.tolerate(Error::InvalidIndex { kind: "Material", index: index }));
+                        0
+                    },
+                };
+                faces.push(Triangle {
+                    y_id: y_id,
+                    x_id: x_id,
+                    mat_id: mat_id,
+                    z_id: z_id,
+                });
+            }
+
+            Ok((bbox, verts, normals, colors, uv_sets, faces))
+        }));
+
+        let mut plugins = try!(Extension::read_up(rws, PluginParent::AtomicSector, |rws, header| {
+            match header.id {
+                id if id == MeshHeader::section_id() => {
+                    MeshHeader::read(rws, matlist, verts.len() as u32).map(|plg| Some(Box::new(plg) as Box<Any + Send + Sync>))
+                },
+                _ => Ok(None),
+            }
+        }));
+        // Empty sectors may have no meshes at all.
+        let meshlist = match plugins.take::<MeshHeader>() {
+            Some(meshlist) => meshlist,
+            None if faces.is_empty() => MeshHeader { is_tri_strip: false, total_indices: 0, meshes: Vec::new() },
+            None => return Err(rws.annotate(Error::MissingSection(MeshHeader::section_id()))),
+        };
+
+        Ok(AtomicSector {
+            bbox: bbox,
+            verts: verts,
+            normals: normals,
+            colors: colors,
+            uv_sets: uv_sets,
+            faces: faces,
+            meshlist: meshlist,
+            plugins: plugins,
+        })
+    }
+}
+
+/// Gets the number of texture coordinate sets of a world with the specified format flags.
+fn num_uv_sets(flags: u32) -> u32 {
+    match (flags >> 16) & 0xFF {
+        0 if (flags & 0x80) != 0 => 2,
+        0 if (flags & 0x04) != 0 => 1,
+        n => n,
+    }
+}
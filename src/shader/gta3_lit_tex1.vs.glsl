@@ -0,0 +1,32 @@
+#version 140
+
+in vec3 pos;
+in vec3 normal;
+// Pre-light color, added to the light from the scene.
+in vec4 color;
+in vec2 uv0;
+out vec2 v_uv0;
+out vec4 v_color;
+
+uniform mat4 model_view_proj;
+uniform mat4 model_view;
+uniform mat3 uv_matrix;
+
+// Directional light (in view space) plus ambient light.
+uniform vec3 light_direction;
+uniform vec3 light_color;
+uniform vec3 ambient_color;
+
+// Reflection coefficients of the material.
+uniform float ambient;
+uniform float diffuse;
+
+void main() {
+    vec3 n = normalize(mat3(model_view) * normal);
+    float intensity = max(dot(n, -light_direction), 0.0);
+
+    vec3 light = ambient_color * ambient + light_color * diffuse * intensity;
+    v_color = vec4(min(color.rgb + light, vec3(1.0)), color.a);
+    v_uv0 = (uv_matrix * vec3(uv0.x, /*1.0 -*/ uv0.y, 1.0)).xy;
+    gl_Position = model_view_proj * vec4(pos.x, pos.y, pos.z, 1.0);
+}
@@ -5,7 +5,13 @@ in vec2 v_uv0;
 out vec4 color;
 
 uniform sampler2D tex;
+uniform vec4 material_color;
+// Fragments less opaque than this are discarded.
+uniform float alpha_ref;
 
 void main() {
-    color = texture(tex, v_uv0) * v_color;
+    color = texture(tex, v_uv0) * v_color * material_color;
+    if (color.a < alpha_ref) {
+        discard;
+    }
 }
\ No newline at end of file
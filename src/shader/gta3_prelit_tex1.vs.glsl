@@ -7,9 +7,10 @@ out vec2 v_uv0;
 out vec4 v_color;
 
 uniform mat4 model_view_proj;
+uniform mat3 uv_matrix;
 
 void main() {
     v_color = color;
-    v_uv0 = vec2(uv0.x, /*1.0 -*/ uv0.y);
+    v_uv0 = (uv_matrix * vec3(uv0.x, /*1.0 -*/ uv0.y, 1.0)).xy;
     gl_Position = model_view_proj * vec4(pos.x, pos.y, pos.z, 1.0);
 }
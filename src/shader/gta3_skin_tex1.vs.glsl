@@ -0,0 +1,47 @@
+#version 140
+
+in vec3 pos;
+in vec3 normal;
+in vec4 color;
+in vec2 uv0;
+in vec4 bone_indices;
+in vec4 bone_weights;
+out vec2 v_uv0;
+out vec4 v_color;
+
+uniform mat4 model_view_proj;
+uniform mat4 model_view;
+uniform mat3 uv_matrix;
+
+// Whether the geometry is lit instead of prelit, see gta3_lit_tex1.vs.glsl.
+uniform bool lit;
+uniform vec3 light_direction;
+uniform vec3 light_color;
+uniform vec3 ambient_color;
+
+// Reflection coefficients of the material.
+uniform float ambient;
+uniform float diffuse;
+
+// Must match MAX_BONES in native::geometry.
+uniform Bones {
+    mat4 bone_matrices[64];
+};
+
+void main() {
+    mat4 skin_matrix = bone_matrices[int(bone_indices.x)] * bone_weights.x
+                     + bone_matrices[int(bone_indices.y)] * bone_weights.y
+                     + bone_matrices[int(bone_indices.z)] * bone_weights.z
+                     + bone_matrices[int(bone_indices.w)] * bone_weights.w;
+
+    if (lit) {
+        vec3 n = normalize(mat3(model_view) * mat3(skin_matrix) * normal);
+        float intensity = max(dot(n, -light_direction), 0.0);
+        vec3 light = ambient_color * ambient + light_color * diffuse * intensity;
+        v_color = vec4(min(color.rgb + light, vec3(1.0)), color.a);
+    } else {
+        v_color = color;
+    }
+    v_uv0 = (uv_matrix * vec3(uv0.x, /*1.0 -*/ uv0.y, 1.0)).xy;
+    gl_Position = model_view_proj * skin_matrix * vec4(pos.x, pos.y, pos.z, 1.0);
+}